@@ -2,16 +2,31 @@ extern crate hibitset;
 extern crate rtforth;
 
 use self::hibitset::BitSet;
-use rtforth::core::{Control, Core, ForwardReferences, Stack, State, Wordlist};
+use rtforth::block::Block;
+use rtforth::core::{
+    Compiler, Control, Core, ForwardReferences, Instrumentation, Stack, State, Wordlist,
+};
+use rtforth::coverage::Coverage;
+use rtforth::debugger::Debugger;
+use rtforth::double::Double;
 use rtforth::env::Environment;
-use rtforth::exception::Exception;
+use rtforth::exception::{Exception, Exceptions};
 use rtforth::facility::Facility;
 use rtforth::file_access::FileAccess;
 use rtforth::float::Float;
+use rtforth::image::Image;
 use rtforth::loader::{HasLoader, Source};
+use rtforth::locals::Locals;
 use rtforth::memory::DataSpace;
+use rtforth::memory_allocation::MemoryAllocation;
+use rtforth::optimizer::Optimizer;
 use rtforth::output::Output;
+use rtforth::profiler::Profiler;
+use rtforth::queue::Queue;
+use rtforth::search_order::SearchOrder;
+use rtforth::string::Strings;
 use rtforth::tools::Tools;
+use rtforth::trace::Tracer;
 use rtforth::units::Units;
 use rtforth::NUM_TASKS;
 use std::fs::File;
@@ -26,6 +41,7 @@ const LABEL_COUNT: u32 = 1000;
 /// dictionary and output buffer owned by virtual machine.
 pub struct Task {
     awake: bool,
+    in_use: bool,
     state: State,
     s_stk: Stack<isize>,
     r_stk: Stack<isize>,
@@ -42,6 +58,7 @@ impl Task {
     pub fn new_background() -> Task {
         Task {
             awake: false,
+            in_use: false,
             state: State::new(),
             s_stk: Stack::new(0x12345678),
             r_stk: Stack::new(0x12345678),
@@ -65,7 +82,7 @@ impl Task {
 /// Virtual machine
 pub struct VM {
     current_task: usize,
-    tasks: [Task; NUM_TASKS],
+    tasks: Vec<Task>,
     last_error: Option<Exception>,
     handler: usize,
     wordlist: Wordlist<VM>,
@@ -73,6 +90,9 @@ pub struct VM {
     tkn: Option<String>,
     outbuf: Option<String>,
     hldbuf: String,
+    exceptions: Exceptions,
+    instrumentation: Instrumentation,
+    compiler: Compiler,
     references: ForwardReferences,
     now: Instant,
     forward_bitset: BitSet,
@@ -81,20 +101,26 @@ pub struct VM {
 }
 
 impl VM {
-    /// Create a VM with data space size specified by `data_pages`.
+    /// Create a VM with data space size specified by `data_pages` and
+    /// `NUM_TASKS` tasks.
     pub fn new(data_pages: usize) -> VM {
+        VM::with_tasks(data_pages, NUM_TASKS)
+    }
+
+    /// Create a VM with data space size specified by `data_pages` and
+    /// `num_tasks` tasks.
+    pub fn with_tasks(data_pages: usize, num_tasks: usize) -> VM {
+        let mut tasks = Vec::with_capacity(num_tasks.max(1));
+        // Only operator task has its own input buffer.
+        tasks.push(Task::new_terminal());
+        for _ in 1..num_tasks {
+            tasks.push(Task::new_background());
+        }
         let mut labels = Vec::with_capacity(LABEL_COUNT as _);
         labels.resize(LABEL_COUNT as _, 0);
         let mut vm = VM {
             current_task: 0,
-            tasks: [
-                // Only operator task has its own input buffer.
-                Task::new_terminal(),
-                Task::new_background(),
-                Task::new_background(),
-                Task::new_background(),
-                Task::new_background(),
-            ],
+            tasks,
             last_error: None,
             handler: 0,
             wordlist: Wordlist::with_capacity(1000),
@@ -102,6 +128,9 @@ impl VM {
             tkn: Some(String::with_capacity(64)),
             outbuf: Some(String::with_capacity(128)),
             hldbuf: String::with_capacity(128),
+            exceptions: Exceptions::new(),
+            instrumentation: Instrumentation::new(),
+            compiler: Compiler::new(),
             references: ForwardReferences::new(),
             now: Instant::now(),
             forward_bitset: BitSet::with_capacity(LABEL_COUNT),
@@ -109,14 +138,27 @@ impl VM {
             labels,
         };
         vm.add_core();
+        vm.add_search_order();
         vm.add_output();
+        vm.add_double();
         vm.add_tools();
+        vm.add_debugger();
+        vm.add_profiler();
+        vm.add_tracer();
+        vm.add_coverage();
+        vm.add_optimizer();
+        vm.add_locals();
         vm.add_environment();
         vm.add_facility();
+        vm.add_queue();
+        vm.add_memory_allocation();
+        vm.add_string();
+        vm.add_block();
         vm.add_float();
         vm.add_units();
         vm.add_file_access();
         vm.add_loader();
+        vm.add_image();
 
         vm.load_core_fth();
 
@@ -146,6 +188,24 @@ impl Core for VM {
     fn hold_buffer(&mut self) -> &mut String {
         &mut self.hldbuf
     }
+    fn exceptions(&self) -> &Exceptions {
+        &self.exceptions
+    }
+    fn exceptions_mut(&mut self) -> &mut Exceptions {
+        &mut self.exceptions
+    }
+    fn instrumentation(&self) -> &Instrumentation {
+        &self.instrumentation
+    }
+    fn instrumentation_mut(&mut self) -> &mut Instrumentation {
+        &mut self.instrumentation
+    }
+    fn compiler(&self) -> &Compiler {
+        &self.compiler
+    }
+    fn compiler_mut(&mut self) -> &mut Compiler {
+        &mut self.compiler
+    }
     fn output_buffer(&mut self) -> &mut Option<String> {
         &mut self.outbuf
     }
@@ -218,30 +278,47 @@ impl Core for VM {
         let elapsed = self.now.elapsed();
         elapsed.as_nanos() as _
     }
+    fn num_tasks(&self) -> usize {
+        self.tasks.len()
+    }
     fn current_task(&self) -> usize {
         self.current_task
     }
     fn set_current_task(&mut self, i: usize) {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.current_task = i;
         } else {
             // Do nothing.
         }
     }
     fn awake(&self, i: usize) -> bool {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.tasks[i].awake
         } else {
             false
         }
     }
     fn set_awake(&mut self, i: usize, v: bool) {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.tasks[i].awake = v;
         } else {
             // Do nothing.
         }
     }
+    fn in_use(&self, i: usize) -> bool {
+        if i < self.tasks.len() {
+            self.tasks[i].in_use
+        } else {
+            false
+        }
+    }
+    fn set_in_use(&mut self, i: usize, v: bool) {
+        if i < self.tasks.len() {
+            self.tasks[i].in_use = v;
+        } else {
+            // Do nothing.
+        }
+    }
     fn forward_bitset(&self) -> &BitSet {
         &self.forward_bitset
     }
@@ -262,6 +339,7 @@ impl Core for VM {
     }
 }
 
+impl Double for VM {}
 impl Environment for VM {}
 impl Facility for VM {}
 impl Float for VM {}
@@ -270,3 +348,15 @@ impl FileAccess for VM {}
 impl HasLoader for VM {}
 impl Output for VM {}
 impl Tools for VM {}
+impl Debugger for VM {}
+impl Profiler for VM {}
+impl Tracer for VM {}
+impl Coverage for VM {}
+impl Optimizer for VM {}
+impl Locals for VM {}
+impl MemoryAllocation for VM {}
+impl Strings for VM {}
+impl Block for VM {}
+impl SearchOrder for VM {}
+impl Queue for VM {}
+impl Image for VM {}
@@ -7,17 +7,32 @@ extern crate unicode_width;
 mod term;
 
 use getopts::Options;
-use rtforth::core::{Control, Core, ForwardReferences, Stack, State, Wordlist};
+use rtforth::block::Block;
+use rtforth::core::{
+    Compiler, Control, Core, ForwardReferences, Instrumentation, Stack, State, Wordlist,
+};
+use rtforth::coverage::Coverage;
+use rtforth::debugger::Debugger;
+use rtforth::double::Double;
 use rtforth::env::Environment;
-use rtforth::exception::Exception;
+use rtforth::exception::{description, Exception, Exceptions};
 use rtforth::facility::Facility;
 use rtforth::file_access::FileAccess;
 use rtforth::float::Float;
 use rtforth::hibitset::BitSet;
+use rtforth::image::Image;
 use rtforth::loader::{HasLoader, Source};
+use rtforth::locals::Locals;
 use rtforth::memory::DataSpace;
+use rtforth::memory_allocation::MemoryAllocation;
+use rtforth::optimizer::Optimizer;
 use rtforth::output::Output;
+use rtforth::profiler::Profiler;
+use rtforth::queue::Queue;
+use rtforth::search_order::SearchOrder;
+use rtforth::string::Strings;
 use rtforth::tools::Tools;
+use rtforth::trace::Tracer;
 use rtforth::units::Units;
 use rtforth::NUM_TASKS;
 use std::env;
@@ -35,6 +50,7 @@ const LABEL_COUNT: u32 = 1000;
 /// dictionary and output buffer owned by virtual machine.
 pub struct Task {
     awake: bool,
+    in_use: bool,
     state: State,
     s_stk: Stack<isize>,
     r_stk: Stack<isize>,
@@ -51,6 +67,7 @@ impl Task {
     pub fn new_background() -> Task {
         Task {
             awake: false,
+            in_use: false,
             state: State::new(),
             s_stk: Stack::new(0x12345678),
             r_stk: Stack::new(0x12345678),
@@ -74,7 +91,7 @@ impl Task {
 /// Virtual machine
 pub struct VM {
     current_task: usize,
-    tasks: [Task; NUM_TASKS],
+    tasks: Vec<Task>,
     term: term::Term,
     last_error: Option<Exception>,
     handler: usize,
@@ -83,6 +100,9 @@ pub struct VM {
     tkn: Option<String>,
     outbuf: Option<String>,
     hldbuf: String,
+    exceptions: Exceptions,
+    instrumentation: Instrumentation,
+    compiler: Compiler,
     references: ForwardReferences,
     now: Instant,
     forward_bitset: BitSet,
@@ -91,21 +111,44 @@ pub struct VM {
 }
 
 impl VM {
-    /// Create a VM with data space size specified by `data_pages`.
-    pub fn new(data_pages: usize) -> VM {
+    /// Create a VM with data space size specified by `data_pages` and
+    /// `num_tasks` tasks.
+    pub fn new(data_pages: usize, num_tasks: usize) -> VM {
+        let mut vm = VM::with_primitives(data_pages, num_tasks);
+        vm.load_core_fth();
+
+        let rtf_fth = include_str!("../rtf.fth");
+        vm.load_str(rtf_fth);
+        if vm.last_error().is_some() {
+            panic!("Error {:?} {:?}", vm.last_error().unwrap(), vm.last_token());
+        }
+
+        vm.flush_output();
+
+        vm
+    }
+
+    /// Create a VM from the image in file `path` saved by `save-image`.
+    pub fn from_image(data_pages: usize, num_tasks: usize, path: &str) -> Result<VM, Exception> {
+        let mut vm = VM::with_primitives(data_pages, num_tasks);
+        vm.load_image(path)?;
+        Ok(vm)
+    }
+
+    /// Create a VM with primitives only.
+    fn with_primitives(data_pages: usize, num_tasks: usize) -> VM {
+        let mut tasks = Vec::with_capacity(num_tasks.max(1));
+        // Only the operator task is a terminal task
+        // with its own input buffer.
+        tasks.push(Task::new_terminal());
+        for _ in 1..num_tasks {
+            tasks.push(Task::new_background());
+        }
         let mut labels = Vec::with_capacity(LABEL_COUNT as _);
         labels.resize(LABEL_COUNT as _, 0);
         let mut vm = VM {
             current_task: 0,
-            tasks: [
-                // Only the operator task is a terminal task
-                // with its own input buffer.
-                Task::new_terminal(),
-                Task::new_background(),
-                Task::new_background(),
-                Task::new_background(),
-                Task::new_background(),
-            ],
+            tasks,
             term: Term::new(),
             last_error: None,
             handler: 0,
@@ -114,6 +157,9 @@ impl VM {
             tkn: Some(String::with_capacity(64)),
             outbuf: Some(String::with_capacity(128)),
             hldbuf: String::with_capacity(128),
+            exceptions: Exceptions::new(),
+            instrumentation: Instrumentation::new(),
+            compiler: Compiler::new(),
             references: ForwardReferences::new(),
             now: Instant::now(),
             forward_bitset: BitSet::with_capacity(LABEL_COUNT),
@@ -121,26 +167,28 @@ impl VM {
             labels,
         };
         vm.add_core();
+        vm.add_search_order();
         vm.add_output();
+        vm.add_double();
         vm.add_tools();
+        vm.add_debugger();
+        vm.add_profiler();
+        vm.add_tracer();
+        vm.add_coverage();
+        vm.add_optimizer();
+        vm.add_locals();
         vm.add_environment();
         vm.add_facility();
+        vm.add_queue();
+        vm.add_memory_allocation();
+        vm.add_string();
+        vm.add_block();
         vm.add_float();
         vm.add_units();
         vm.add_file_access();
         vm.add_loader();
+        vm.add_image();
         vm.add_primitive("receive", receive);
-
-        vm.load_core_fth();
-
-        let rtf_fth = include_str!("../rtf.fth");
-        vm.load_str(rtf_fth);
-        if vm.last_error().is_some() {
-            panic!("Error {:?} {:?}", vm.last_error().unwrap(), vm.last_token());
-        }
-
-        vm.flush_output();
-
         vm
     }
 }
@@ -167,6 +215,24 @@ impl Core for VM {
     fn hold_buffer(&mut self) -> &mut String {
         &mut self.hldbuf
     }
+    fn exceptions(&self) -> &Exceptions {
+        &self.exceptions
+    }
+    fn exceptions_mut(&mut self) -> &mut Exceptions {
+        &mut self.exceptions
+    }
+    fn instrumentation(&self) -> &Instrumentation {
+        &self.instrumentation
+    }
+    fn instrumentation_mut(&mut self) -> &mut Instrumentation {
+        &mut self.instrumentation
+    }
+    fn compiler(&self) -> &Compiler {
+        &self.compiler
+    }
+    fn compiler_mut(&mut self) -> &mut Compiler {
+        &mut self.compiler
+    }
     fn output_buffer(&mut self) -> &mut Option<String> {
         &mut self.outbuf
     }
@@ -240,30 +306,47 @@ impl Core for VM {
         elapsed.as_nanos() as _
     }
 
+    fn num_tasks(&self) -> usize {
+        self.tasks.len()
+    }
     fn current_task(&self) -> usize {
         self.current_task
     }
     fn set_current_task(&mut self, i: usize) {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.current_task = i;
         } else {
             // Do nothing.
         }
     }
     fn awake(&self, i: usize) -> bool {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.tasks[i].awake
         } else {
             false
         }
     }
     fn set_awake(&mut self, i: usize, v: bool) {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.tasks[i].awake = v;
         } else {
             // Do nothing.
         }
     }
+    fn in_use(&self, i: usize) -> bool {
+        if i < self.tasks.len() {
+            self.tasks[i].in_use
+        } else {
+            false
+        }
+    }
+    fn set_in_use(&mut self, i: usize, v: bool) {
+        if i < self.tasks.len() {
+            self.tasks[i].in_use = v;
+        } else {
+            // Do nothing.
+        }
+    }
     fn forward_bitset(&self) -> &BitSet {
         &self.forward_bitset
     }
@@ -284,6 +367,7 @@ impl Core for VM {
     }
 }
 
+impl Double for VM {}
 impl Environment for VM {}
 impl Facility for VM {}
 impl Float for VM {}
@@ -291,16 +375,33 @@ impl Units for VM {}
 impl HasLoader for VM {}
 impl Output for VM {}
 impl Tools for VM {}
+impl Debugger for VM {}
+impl Profiler for VM {}
+impl Tracer for VM {}
+impl Coverage for VM {}
+impl Optimizer for VM {}
+impl Locals for VM {}
+impl MemoryAllocation for VM {}
+impl Strings for VM {}
+impl Block for VM {}
+impl SearchOrder for VM {}
+impl Queue for VM {}
 impl FileAccess for VM {}
+impl Image for VM {}
 
 fn main() {
-    let vm = &mut VM::new(1024);
-
     let args: Vec<_> = env::args().collect();
     let program = args[0].clone();
     let mut opts = Options::new();
     opts.optflag("h", "help", "print help menu");
     opts.optflag("v", "version", "print version number");
+    opts.optopt("t", "tasks", "number of tasks", "N");
+    opts.optopt(
+        "i",
+        "image",
+        "start from an image saved by save-image",
+        "FILE",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -308,6 +409,20 @@ fn main() {
             panic!("{}", f.to_string());
         }
     };
+    let num_tasks = match matches.opt_str("t") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => panic!("Invalid number of tasks: {}", n),
+        },
+        None => NUM_TASKS,
+    };
+    let vm = &mut match matches.opt_str("i") {
+        Some(path) => match VM::from_image(1024, num_tasks, &path) {
+            Ok(vm) => vm,
+            Err(e) => panic!("Cannot load image {}: {}", path, description(e)),
+        },
+        None => VM::new(1024, num_tasks),
+    };
     if matches.opt_present("h") {
         print_usage(&program, opts);
     } else if matches.opt_present("v") {
@@ -0,0 +1,515 @@
+//! Block word set
+//!
+//! Blocks of 1024 characters are kept in a block file, opened with
+//! `open-file` or `create-file` and handed to `block-file`. Block `u` is at
+//! position `(u - 1) * 1024` of the file. Reading a block beyond the end of
+//! the file gives a block of spaces.
+//!
+//! Block buffers are carved out of the data space by `block-buffers`, after
+//! a header telling which block each buffer holds and whether it has been
+//! updated. The header is marked as code, so that programs cannot overwrite
+//! it.
+//!
+//! ```text
+//! Block buffers
+//! +--------+-------+---------+-------+---------+-----+----------+-----+
+//! | fileid | count | current | block | updated | ... | buffer 0 | ... |
+//! +--------+-------+---------+-------+---------+-----+----------+-----+
+//! ```
+//!
+//! Block 0 is not a valid block number and marks a buffer holding no block.
+//!
+//! `load` interprets a block as an input source of 16 lines of 64
+//! characters, so that `\` ends at the end of a line and errors tell the
+//! block and the line.
+//!
+//! Example:
+//!
+//! ```text
+//! 4 block-buffers
+//! s" params.blk" r/w open-file throw block-file
+//! 1 load
+//! ```
+
+use core::Core;
+use exception::{
+    Exception, BLOCK_READ_EXCEPTION, BLOCK_WRITE_EXCEPTION, DICTIONARY_OVERFLOW,
+    INVALID_BLOCK_NUMBER, INVALID_NUMERIC_ARGUMENT, UNSUPPORTED_OPERATION,
+};
+use loader::{HasLoader, Source};
+use memory::{DataSpace, Memory};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::result;
+
+/// Characters in a block.
+pub const BLOCK_SIZE: usize = 1024;
+
+/// Characters in a line of a block.
+pub const LINE_SIZE: usize = 64;
+
+/// Cells in the header before the entries of the buffers.
+const HEADER: usize = 3;
+
+pub trait Block: Core + HasLoader {
+    /// Add block primitives.
+    fn add_block(&mut self) {
+        self.add_primitive("block-buffers", Block::p_block_buffers);
+        self.add_primitive("block-file", Block::p_block_file);
+        self.add_primitive("block", Block::p_block);
+        self.add_primitive("buffer", Block::p_buffer);
+        self.add_primitive("update", Block::update);
+        self.add_primitive("save-buffers", Block::p_save_buffers);
+        self.add_primitive("flush", Block::flush);
+        self.add_primitive("empty-buffers", Block::empty_buffers);
+        self.add_primitive("block-source", Block::block_source);
+        self.add_primitive("blk", Block::blk);
+    }
+
+    /// Cell `i` of the header of the block buffers at `start`.
+    fn block_header(&mut self, start: usize, i: usize) -> usize {
+        unsafe {
+            self.data_space()
+                .get_usize(start + i * mem::size_of::<usize>())
+        }
+    }
+
+    /// Store `v` in cell `i` of the header of the block buffers at `start`.
+    fn set_block_header(&mut self, start: usize, i: usize, v: usize) {
+        unsafe {
+            self.data_space()
+                .put_usize(v, start + i * mem::size_of::<usize>())
+        }
+    }
+
+    /// Start address of the block buffers, `None` if there are none.
+    fn block_buffers(&mut self) -> Option<usize> {
+        let (start, end) = self.data_space().blocks();
+        if start == end {
+            None
+        } else {
+            Some(self.data_space().address(start))
+        }
+    }
+
+    /// Address of buffer `i` of the block buffers at `start`.
+    fn buffer_address(&mut self, start: usize, i: usize) -> usize {
+        let count = self.block_header(start, 1);
+        start + (HEADER + 2 * count) * mem::size_of::<usize>() + i * BLOCK_SIZE
+    }
+
+    /// Create `count` block buffers at `here`, replacing the previous ones
+    /// if any. Updated buffers are saved first.
+    fn create_block_buffers(&mut self, count: usize) -> result::Result<(), Exception> {
+        if count == 0 {
+            return Err(INVALID_NUMERIC_ARGUMENT);
+        }
+        if count > self.data_space().capacity() / BLOCK_SIZE {
+            return Err(DICTIONARY_OVERFLOW);
+        }
+        let fileid = match self.block_buffers() {
+            Some(old) => {
+                self.save_buffers()?;
+                self.block_header(old, 0)
+            }
+            None => 0,
+        };
+        self.data_space().align();
+        let start = self.data_space().here();
+        let size = (HEADER + 2 * count) * mem::size_of::<usize>() + count * BLOCK_SIZE;
+        let end = match start.checked_add(size) {
+            Some(end) if end <= self.data_space().limit() => end,
+            _ => return Err(DICTIONARY_OVERFLOW),
+        };
+        self.data_space().set_here(DataSpace::aligned(end))?;
+        self.set_block_header(start, 0, fileid);
+        self.set_block_header(start, 1, count);
+        self.set_block_header(start, 2, 0);
+        for i in 0..count {
+            self.set_block_header(start, HEADER + 2 * i, 0);
+            self.set_block_header(start, HEADER + 2 * i + 1, 0);
+        }
+        let buffers = self.buffer_address(start, 0);
+        self.data_space().mark_code(start, buffers);
+        let start = self.data_space().offset(start);
+        let end = self.data_space().offset(end);
+        self.data_space().set_blocks((start, end));
+        Ok(())
+    }
+
+    /// Transfer block `u` between the block file and the buffer at `addr`,
+    /// reading if `read` is true, otherwise writing.
+    fn transfer_block(
+        &mut self,
+        u: usize,
+        addr: usize,
+        read: bool,
+    ) -> result::Result<(), Exception> {
+        let e = if read {
+            BLOCK_READ_EXCEPTION
+        } else {
+            BLOCK_WRITE_EXCEPTION
+        };
+        let start = self.block_buffers().ok_or(e)?;
+        let fileid = self.block_header(start, 0);
+        if fileid == 0 || fileid > self.files().len() {
+            return Err(e);
+        }
+        let mut file = self.files_mut()[fileid - 1].take().ok_or(e)?;
+        let position = ((u - 1) * BLOCK_SIZE) as u64;
+        let result = if read {
+            let buf = unsafe {
+                self.data_space()
+                    .buffer_from_raw_parts_mut(addr, BLOCK_SIZE)
+            };
+            read_at(&mut file, position, buf)
+        } else {
+            let buf = unsafe { self.data_space().buffer_from_raw_parts(addr, BLOCK_SIZE) };
+            write_at(&mut file, position, buf)
+        };
+        self.files_mut()[fileid - 1] = Some(file);
+        result.map_err(|_| e)
+    }
+
+    /// Assign a block buffer to block `u` and make it the current buffer,
+    /// reading the block from the block file if `read` is true. Return the
+    /// address of the buffer.
+    ///
+    /// A buffer already holding block `u` is used as is. Otherwise the first
+    /// buffer holding no block is used, or the one after the current buffer,
+    /// which is saved first if it has been updated.
+    fn assign_buffer(&mut self, u: isize, read: bool) -> result::Result<usize, Exception> {
+        if u <= 0 || u as usize > isize::MAX as usize / BLOCK_SIZE {
+            return Err(INVALID_BLOCK_NUMBER);
+        }
+        let u = u as usize;
+        let start = self.block_buffers().ok_or(UNSUPPORTED_OPERATION)?;
+        let count = self.block_header(start, 1);
+        let mut empty = None;
+        for i in 0..count {
+            let block = self.block_header(start, HEADER + 2 * i);
+            if block == u {
+                self.set_block_header(start, 2, i);
+                return Ok(self.buffer_address(start, i));
+            }
+            if block == 0 && empty.is_none() {
+                empty = Some(i);
+            }
+        }
+        let i = match empty {
+            Some(i) => i,
+            None => {
+                let i = (self.block_header(start, 2) + 1) % count;
+                if self.block_header(start, HEADER + 2 * i + 1) != 0 {
+                    let block = self.block_header(start, HEADER + 2 * i);
+                    let addr = self.buffer_address(start, i);
+                    self.transfer_block(block, addr, false)?;
+                }
+                i
+            }
+        };
+        let addr = self.buffer_address(start, i);
+        self.set_block_header(start, HEADER + 2 * i, 0);
+        self.set_block_header(start, HEADER + 2 * i + 1, 0);
+        if read {
+            self.transfer_block(u, addr, true)?;
+        }
+        self.set_block_header(start, HEADER + 2 * i, u);
+        self.set_block_header(start, 2, i);
+        Ok(addr)
+    }
+
+    /// Write updated block buffers to the block file and mark them as
+    /// unmodified.
+    fn save_buffers(&mut self) -> result::Result<(), Exception> {
+        if let Some(start) = self.block_buffers() {
+            let count = self.block_header(start, 1);
+            for i in 0..count {
+                if self.block_header(start, HEADER + 2 * i + 1) != 0 {
+                    let block = self.block_header(start, HEADER + 2 * i);
+                    let addr = self.buffer_address(start, i);
+                    self.transfer_block(block, addr, false)?;
+                    self.set_block_header(start, HEADER + 2 * i + 1, 0);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run-time: ( u -- )
+    ///
+    /// Carve `u` block buffers out of the data space, replacing the previous
+    /// ones if any. Updated buffers are saved first.
+    ///
+    /// Example: `4 block-buffers`
+    fn p_block_buffers(&mut self) {
+        let u = self.s_stack().pop();
+        if u < 0 {
+            self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        } else if let Err(e) = self.create_block_buffers(u as usize) {
+            self.abort_with(e);
+        }
+    }
+
+    /// Run-time: ( fileid -- )
+    ///
+    /// Use the file identified by `fileid` as the block file. Updated buffers
+    /// are saved to the previous block file first, and all buffers are
+    /// unassigned. The file is still identified by `fileid`, and should not
+    /// be closed while it is the block file. Blocks are accessed with the
+    /// files of the executing task.
+    fn p_block_file(&mut self) {
+        let fileid = self.s_stack().pop();
+        if fileid <= 0
+            || fileid as usize > self.files().len()
+            || self.files()[fileid as usize - 1].is_none()
+        {
+            return self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+        let start = match self.block_buffers() {
+            Some(start) => start,
+            None => return self.abort_with(UNSUPPORTED_OPERATION),
+        };
+        match self.save_buffers() {
+            Ok(()) => {
+                self.empty_buffers();
+                self.set_block_header(start, 0, fileid as usize);
+            }
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( u -- a-addr )
+    ///
+    /// `a-addr` is the address of the first character of the block buffer
+    /// assigned to block `u`, which becomes the current block buffer. If
+    /// block `u` is not already in a buffer, it is read from the block file,
+    /// after saving the contents of the reassigned buffer if it has been
+    /// updated.
+    fn p_block(&mut self) {
+        let u = self.s_stack().pop();
+        match self.assign_buffer(u, true) {
+            Ok(addr) => self.s_stack().push(addr as isize),
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( u -- a-addr )
+    ///
+    /// Same as `block`, except that block `u` is not read from the block
+    /// file if it is not already in a buffer.
+    fn p_buffer(&mut self) {
+        let u = self.s_stack().pop();
+        match self.assign_buffer(u, false) {
+            Ok(addr) => self.s_stack().push(addr as isize),
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Mark the current block buffer as modified, so that it is written to
+    /// the block file when the buffer is reassigned or by `save-buffers`.
+    fn update(&mut self) {
+        if let Some(start) = self.block_buffers() {
+            let i = self.block_header(start, 2);
+            if self.block_header(start, HEADER + 2 * i) != 0 {
+                self.set_block_header(start, HEADER + 2 * i + 1, 1);
+            }
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Write the updated block buffers to the block file and mark them as
+    /// unmodified.
+    fn p_save_buffers(&mut self) {
+        if let Err(e) = self.save_buffers() {
+            self.abort_with(e);
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Perform the function of `save-buffers`, then unassign all block
+    /// buffers.
+    fn flush(&mut self) {
+        match self.save_buffers() {
+            Ok(()) => self.empty_buffers(),
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Unassign all block buffers without saving them.
+    fn empty_buffers(&mut self) {
+        if let Some(start) = self.block_buffers() {
+            let count = self.block_header(start, 1);
+            for i in 0..count {
+                self.set_block_header(start, HEADER + 2 * i, 0);
+                self.set_block_header(start, HEADER + 2 * i + 1, 0);
+            }
+        }
+    }
+
+    /// Run-time: ( u -- source-id )
+    ///
+    /// Open an input source of the contents of block `u`, read as by `block`.
+    /// Used by `load`.
+    fn block_source(&mut self) {
+        let u = self.s_stack().pop();
+        match self.assign_buffer(u, true) {
+            Ok(addr) => {
+                let text = {
+                    let buf = unsafe { self.data_space().buffer_from_raw_parts(addr, BLOCK_SIZE) };
+                    buf.chunks(LINE_SIZE)
+                        .map(String::from_utf8_lossy)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                let sid = self.add_source(Source::from_block(u as usize, text));
+                self.s_stack().push(sid);
+            }
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( -- a-addr )
+    ///
+    /// `a-addr` is the address of a cell containing the number of the block
+    /// being interpreted, or zero if the input source is not a block.
+    fn blk(&mut self) {
+        let sid = self.source_id();
+        let u = if sid > 0 {
+            self.sources()[sid as usize - 1]
+                .as_ref()
+                .map_or(0, |s| s.block())
+        } else {
+            0
+        };
+        let addr = self.data_space().system_variables().blk_addr();
+        unsafe { self.data_space().put_isize(u as isize, addr) };
+        self.s_stack().push(addr as isize);
+    }
+}
+
+/// Read `buf` from `position` of `file`. The part of `buf` beyond the end
+/// of the file is filled with spaces.
+fn read_at(file: &mut File, position: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(position))?;
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    for c in &mut buf[n..] {
+        *c = b' ';
+    }
+    Ok(())
+}
+
+/// Write `buf` at `position` of `file`.
+fn write_at(file: &mut File, position: u64, buf: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(position))?;
+    file.write_all(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BLOCK_SIZE;
+    use core::Core;
+    use exception::{INVALID_BLOCK_NUMBER, UNSUPPORTED_OPERATION, WRITE_TO_A_READ_ONLY_LOCATION};
+    use mock_vm::VM;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_block_update_flush() {
+        let path = env::temp_dir().join(format!("rtf-test-{}.blk", std::process::id()));
+        let path = path.to_str().expect("path");
+        let vm = &mut VM::new();
+        vm.set_source("1 block");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(UNSUPPORTED_OPERATION));
+        vm.reset();
+        vm.set_source(&format!(
+            "
+            2 block-buffers
+            : path   s\" {}\" ;
+            path r/w create-file throw block-file
+            0 ' block catch nip
+            1 block c@
+            char a 1 buffer c!  update
+            char b 2 buffer c!  update
+            char c 3 buffer c!  update
+            flush  1 block c@  3 block c@
+            char d 3 block c!  empty-buffers  3 block c@
+            ",
+            path
+        ));
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(
+            vm.s_stack().as_slice(),
+            [
+                INVALID_BLOCK_NUMBER.into(),
+                ' ' as isize,
+                'a' as isize,
+                'c' as isize,
+                'c' as isize
+            ]
+        );
+        let bytes = fs::read(path).expect("block file");
+        fs::remove_file(path).expect("remove");
+        assert_eq!(bytes.len(), 3 * BLOCK_SIZE);
+        assert_eq!(bytes[BLOCK_SIZE], b'b');
+    }
+
+    #[test]
+    fn test_load() {
+        let path = env::temp_dir().join(format!("rtf-test-load-{}.blk", std::process::id()));
+        let path = path.to_str().expect("path");
+        let mut text = format!("{:64}{:960}", ": sq  dup * ;  blk @  \\ 1 drop", "5 sq");
+        text.push_str(&format!("{:1024}", "3 4 +  3 load"));
+        text.push_str(&format!("{:1024}", "blk @"));
+        fs::write(path, text).expect("block file");
+        let vm = &mut VM::new();
+        vm.set_source(&format!(
+            "
+            1 block-buffers
+            : path   s\" {}\" ;
+            path r/w open-file throw block-file
+            1 load  2 3 thru  blk @
+            ",
+            path
+        ));
+        vm.evaluate_input();
+        fs::remove_file(path).expect("remove");
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [1, 25, 7, 3, 3, 0]);
+        assert_eq!(vm.source_id(), 0);
+    }
+    #[test]
+    fn test_block_header_read_only() {
+        let vm = &mut VM::new();
+        vm.set_source("align here 1 block-buffers  dup 2 cells + 100000000000 swap !");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(WRITE_TO_A_READ_ONLY_LOCATION));
+        vm.reset();
+        vm.set_source("align here 1 block-buffers  64 blank");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(WRITE_TO_A_READ_ONLY_LOCATION));
+        vm.reset();
+        // The buffers themselves can be written.
+        vm.set_source("update  2 buffer  dup 0 swap c!  c@  update");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0]);
+    }
+}
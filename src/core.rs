@@ -3,41 +3,46 @@
 //! This module contains rtForth core words.
 
 extern crate libc;
+use double::{from_double, from_udouble, to_double, to_udouble};
 use exception::{
-    self, Exception, ABORT, CONTROL_STRUCTURE_MISMATCH, DIVISION_BY_ZERO,
+    Exception, Exceptions, ABORT, ABORT_QUOTE, CONTROL_STRUCTURE_MISMATCH, DIVISION_BY_ZERO,
     FLOATING_POINT_STACK_OVERFLOW, FLOATING_POINT_STACK_UNDERFLOW,
     INTERPRETING_A_COMPILE_ONLY_WORD, INVALID_MEMORY_ADDRESS, INVALID_NUMERIC_ARGUMENT,
-    RETURN_STACK_OVERFLOW, RETURN_STACK_UNDERFLOW, STACK_OVERFLOW, STACK_UNDERFLOW, UNDEFINED_WORD,
-    UNEXPECTED_END_OF_FILE, UNSUPPORTED_OPERATION,
+    RESULT_OUT_OF_RANGE, RETURN_STACK_OVERFLOW, RETURN_STACK_UNDERFLOW, STACK_OVERFLOW,
+    STACK_UNDERFLOW, UNDEFINED_WORD, UNEXPECTED_END_OF_FILE, UNSUPPORTED_OPERATION, WATCHPOINT_HIT,
+    WRITE_TO_A_READ_ONLY_LOCATION,
 };
 use hibitset::{BitSet, BitSetLike};
 use loader::Source;
 use memory::{DataSpace, Memory};
 use parser;
+use std::collections::BTreeMap;
 use std::fmt::Write;
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::mem;
 use std::ops::{Index, IndexMut};
 use std::str;
-use {FALSE, NUM_TASKS, TRUE};
+use {FALSE, TRUE};
 
 // Word
 pub struct Word<Target> {
-    is_immediate: bool,
-    is_compile_only: bool,
-    hidden: bool,
-    link: usize,
-    hash: u32,
-    nfa: usize,
-    dfa: usize,
-    doer: usize,
-    action: fn(&mut Target),
+    pub(crate) is_immediate: bool,
+    pub(crate) is_compile_only: bool,
+    pub(crate) hidden: bool,
+    pub(crate) wid: usize,
+    pub(crate) link: usize,
+    pub(crate) hash: u32,
+    pub(crate) nfa: usize,
+    pub(crate) dfa: usize,
+    pub(crate) doer: usize,
+    pub(crate) action: fn(&mut Target),
     pub(crate) compilation_semantics: fn(&mut Target, usize),
     // Minimum execution time in [ns]
     pub(crate) min_execution_time: usize,
     // Maximum execution time in [ns]
     pub(crate) max_execution_time: usize,
+    pub(crate) profile: Profile,
 }
 
 impl<Target> Word<Target> {
@@ -51,6 +56,7 @@ impl<Target> Word<Target> {
             is_immediate: false,
             is_compile_only: false,
             hidden: false,
+            wid: 0,
             link: 0,
             hash: 0,
             nfa: nfa,
@@ -60,6 +66,7 @@ impl<Target> Word<Target> {
             compilation_semantics: compilation_semantics,
             min_execution_time: 0,
             max_execution_time: 0,
+            profile: Profile::default(),
         }
     }
 
@@ -87,6 +94,11 @@ impl<Target> Word<Target> {
         self.hidden = flag;
     }
 
+    /// Identifier of the word list the word belongs to.
+    pub fn wid(&self) -> usize {
+        self.wid
+    }
+
     pub fn nfa(&self) -> usize {
         self.nfa
     }
@@ -98,26 +110,71 @@ impl<Target> Word<Target> {
     pub fn action(&self) -> fn(&mut Target) {
         self.action
     }
+
+    /// Calls and execution times recorded by the profiler.
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
 }
 
-const BUCKET_SIZE: usize = 64;
+/// Calls and execution times of a word recorded by the profiler.
+///
+/// Times are in nano-seconds of `system_time_ns`. The inclusive time
+/// includes the words called, the exclusive time does not. Time other
+/// tasks run while the word pauses is part of the inclusive time only.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Profile {
+    pub calls: u64,
+    pub inclusive_ns: u64,
+    pub exclusive_ns: u64,
+}
+
+pub(crate) const BUCKET_SIZE: usize = 64;
+
+/// Maximum number of word lists in the search order.
+pub const SEARCH_ORDER_SIZE: usize = 16;
+
+/// Identifier of the word list FORTH-WORDLIST.
+pub const FORTH_WORDLIST: usize = 0;
 
 /// Wordlist
+///
+/// All words of the system, organized into word lists identified by `wid`.
+/// Each word list has its own hash buckets. Words are found by searching the
+/// word lists in the search order, and new words are added to the current
+/// compilation word list.
+///
+/// Every action and compilation semantics used by a word is registered, so
+/// that a word can refer to them by index in a dictionary image. The
+/// fingerprint identifies the primitives and the registries.
 pub struct Wordlist<Target> {
-    words: Vec<Word<Target>>,
-    buckets: [usize; BUCKET_SIZE],
-    temp_buckets: [usize; BUCKET_SIZE],
-    last: usize,
+    pub(crate) words: Vec<Word<Target>>,
+    pub(crate) buckets: Vec<[usize; BUCKET_SIZE]>,
+    pub(crate) names: Vec<usize>,
+    pub(crate) search_order: Vec<usize>,
+    pub(crate) current: usize,
+    pub(crate) last: usize,
+    pub(crate) actions: Vec<fn(&mut Target)>,
+    pub(crate) compilations: Vec<fn(&mut Target, usize)>,
+    fingerprint: u64,
 }
 
 impl<Target> Wordlist<Target> {
     /// Create a wordlist with capacity of `cap`.
     pub fn with_capacity(cap: usize) -> Wordlist<Target> {
+        let mut search_order = Vec::with_capacity(SEARCH_ORDER_SIZE);
+        search_order.push(FORTH_WORDLIST);
         Wordlist {
             words: Vec::with_capacity(cap),
-            buckets: [0; BUCKET_SIZE],
-            temp_buckets: [0; BUCKET_SIZE],
+            buckets: vec![[0; BUCKET_SIZE]],
+            names: vec![0],
+            search_order,
+            current: FORTH_WORDLIST,
             last: 0,
+            actions: Vec::new(),
+            compilations: Vec::new(),
+            // FNV-1a offset basis
+            fingerprint: 0xcbf2_9ce4_8422_2325,
         }
     }
 
@@ -140,22 +197,131 @@ impl<Target> Wordlist<Target> {
         hash
     }
 
-    /// Push word `w` into list.
-    fn push(&mut self, name: &str, mut w: Word<Target>) {
+    /// Index of `action` in the registry of actions, registered if new.
+    pub(crate) fn action_index(&mut self, action: fn(&mut Target)) -> usize {
+        let addr = action as usize;
+        match self.actions.iter().position(|&a| a as usize == addr) {
+            Some(i) => i,
+            None => {
+                self.actions.push(action);
+                self.actions.len() - 1
+            }
+        }
+    }
+
+    /// Index of `compilation` in the registry of compilation semantics,
+    /// registered if new.
+    pub(crate) fn compilation_index(&mut self, compilation: fn(&mut Target, usize)) -> usize {
+        let addr = compilation as usize;
+        match self.compilations.iter().position(|&c| c as usize == addr) {
+            Some(i) => i,
+            None => {
+                self.compilations.push(compilation);
+                self.compilations.len() - 1
+            }
+        }
+    }
+
+    /// Fold `name` and registry indices `action` and `compilation` into the
+    /// fingerprint.
+    pub(crate) fn fold_fingerprint(&mut self, name: &str, action: usize, compilation: usize) {
+        let action = (action as u64).to_le_bytes();
+        let compilation = (compilation as u64).to_le_bytes();
+        let bytes = name
+            .as_bytes()
+            .iter()
+            .chain(&[0])
+            .chain(&action)
+            .chain(&compilation);
+        for &b in bytes {
+            // FNV-1a
+            self.fingerprint ^= b as u64;
+            self.fingerprint = self.fingerprint.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Fingerprint of the primitives and the registries of actions and
+    /// compilation semantics.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Push word `w` into the current compilation word list.
+    pub(crate) fn push(&mut self, name: &str, mut w: Word<Target>) {
+        self.action_index(w.action);
+        self.compilation_index(w.compilation_semantics);
         w.hash = Self::hash(name);
+        w.wid = self.current;
         let b = w.hash as usize % BUCKET_SIZE;
-        w.link = self.buckets[b];
+        w.link = self.buckets[self.current][b];
         self.last = self.words.len();
-        self.buckets[b] = self.last;
+        self.buckets[self.current][b] = self.last;
         self.words.push(w);
     }
 
     /// Remove the `i`th word and all words behind it.
     fn truncate(&mut self, i: usize) {
+        // Links always point to older words, so following the chains until
+        // a word older than `i` restores the buckets.
+        for buckets in self.buckets.iter_mut() {
+            for head in buckets.iter_mut() {
+                while *head >= i {
+                    *head = self.words[*head].link;
+                }
+            }
+        }
         self.words.truncate(i);
         self.last = self.words.len() - 1;
     }
 
+    /// Number of word lists
+    pub fn wordlist_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Create a new empty word list and return its identifier.
+    pub fn add_wordlist(&mut self) -> usize {
+        self.buckets.push([0; BUCKET_SIZE]);
+        self.names.push(0);
+        self.buckets.len() - 1
+    }
+
+    /// Remove word list `wid` and all word lists created after it.
+    fn truncate_wordlists(&mut self, wid: usize) {
+        self.buckets.truncate(wid);
+        self.names.truncate(wid);
+    }
+
+    /// Name field address of the word naming word list `wid`, 0 if anonymous.
+    pub fn wordlist_name(&self, wid: usize) -> usize {
+        self.names[wid]
+    }
+
+    /// Name word list `wid` after the word with name field address `nfa`.
+    pub fn set_wordlist_name(&mut self, wid: usize, nfa: usize) {
+        self.names[wid] = nfa;
+    }
+
+    /// Compilation word list
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Set compilation word list to `wid`.
+    pub fn set_current(&mut self, wid: usize) {
+        self.current = wid;
+    }
+
+    /// Search order, the last one is searched first.
+    pub fn search_order(&self) -> &[usize] {
+        &self.search_order
+    }
+
+    /// Mutable search order, the last one is searched first.
+    pub fn search_order_mut(&mut self) -> &mut Vec<usize> {
+        &mut self.search_order
+    }
+
     /// Find execution token of the word to whom the address may belong to.
     pub fn find_xt(&self, addr: usize) -> Option<usize> {
         let result = self.words.binary_search_by(|w| w.nfa().cmp(&addr));
@@ -281,6 +447,13 @@ impl<T: Default + Copy + PartialEq + Display> Stack<T> {
         self.len == 0
     }
 
+    /// Restore depth to `len` and repair the canaries.
+    pub fn restore(&mut self, len: u8) {
+        self.len = len;
+        self.inner[64] = self.canary;
+        self.inner[255] = self.canary;
+    }
+
     /// # Safety
     /// Because the implementer (me) is still learning Rust, it is uncertain if as_slice is safe.
     pub fn as_slice(&self) -> &[T] {
@@ -381,6 +554,14 @@ pub struct ForwardReferences {
     pub idx__postpone: usize,
     pub idx_to_r: usize,
     pub idx__does: usize,
+    pub idx__task: usize,
+    pub idx__call: usize,
+    pub idx__catch: usize,
+    pub idx__abort_quote: usize,
+    pub idx__local_fetch: usize,
+    pub idx__flocal_fetch: usize,
+    pub idx__local_exit: usize,
+    pub wid_substitutions: usize,
 }
 
 impl ForwardReferences {
@@ -403,6 +584,14 @@ impl ForwardReferences {
             idx__postpone: 0,
             idx_to_r: 0,
             idx__does: 0,
+            idx__task: 0,
+            idx__call: 0,
+            idx__catch: 0,
+            idx__abort_quote: 0,
+            idx__local_fetch: 0,
+            idx__flocal_fetch: 0,
+            idx__local_exit: 0,
+            wid_substitutions: 0,
         }
     }
 }
@@ -414,6 +603,22 @@ pub struct State {
     pub aborted_word_pointer: usize,
     pub source_index: usize,
     pub source_id: isize,
+    pub schedule: Schedule,
+    /// Depth of return stack at the innermost `catch` frame, 0 if there is
+    /// no such frame.
+    pub catch_frame: u8,
+    /// Depth of return stack at the local frame of the executing
+    /// definition, 0 if there is no such frame.
+    pub local_frame: u8,
+    /// The debugger, trace, coverage, profiler or sampler hooks into the
+    /// tokens executed by the task, or the task went to sleep while no
+    /// other task was awake. See `Core::update_hooks`.
+    hooks: bool,
+    pub debug: DebugState,
+    pub profile: ProfileState,
+    /// Number of samples of each chain of words being executed, in folded
+    /// stack format.
+    pub samples: BTreeMap<String, u64>,
 }
 
 impl State {
@@ -425,6 +630,13 @@ impl State {
             aborted_word_pointer: 0,
             source_index: 0,
             source_id: 0,
+            schedule: Schedule::default(),
+            catch_frame: 0,
+            local_frame: 0,
+            hooks: false,
+            debug: DebugState::default(),
+            profile: ProfileState::default(),
+            samples: BTreeMap::new(),
         }
     }
 
@@ -433,6 +645,435 @@ impl State {
     }
 }
 
+/// Real-time scheduling parameters and statistics of a task.
+///
+/// Times are in nano-seconds of `system_time_ns`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Schedule {
+    /// Among tasks which are awake, the one with the highest priority is
+    /// resumed first.
+    pub priority: isize,
+    /// Period, 0 if the task is not periodic.
+    pub period_ns: u64,
+    /// Release time of the current period.
+    pub release_ns: u64,
+    /// Time the current job started.
+    pub start_ns: u64,
+    /// Waiting for the next release.
+    pub waiting: bool,
+    /// Released but not resumed yet.
+    pub released: bool,
+    /// Number of releases.
+    pub releases: u64,
+    /// Number of missed deadlines.
+    pub misses: u64,
+    /// Release jitter of the last job.
+    pub jitter_ns: u64,
+    /// Maximum release jitter.
+    pub max_jitter_ns: u64,
+    /// Execution time of the last job.
+    pub exec_ns: u64,
+    /// Maximum execution time.
+    pub max_exec_ns: u64,
+}
+
+impl Schedule {
+    /// Clear statistics.
+    pub fn clear_stats(&mut self) {
+        self.releases = 0;
+        self.misses = 0;
+        self.jitter_ns = 0;
+        self.max_jitter_ns = 0;
+        self.exec_ns = 0;
+        self.max_exec_ns = 0;
+    }
+
+    /// Record the start of a job at `now`.
+    pub fn start(&mut self, now: u64) {
+        self.released = false;
+        self.start_ns = now;
+        self.releases += 1;
+        self.jitter_ns = now.saturating_sub(self.release_ns);
+        self.max_jitter_ns = self.max_jitter_ns.max(self.jitter_ns);
+    }
+}
+
+/// Where the debugger stops a task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Before the task executes word `xt`.
+    Word(usize),
+    /// Before the task executes the token at address `addr`.
+    Address(usize),
+}
+
+/// How a task resumed by the debugger runs until it stops again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Step {
+    /// Run until a breakpoint.
+    #[default]
+    Run,
+    /// Stop before the next token.
+    Into,
+    /// Stop before the next token with a return stack not deeper than `n`.
+    Over(usize),
+    /// Stop before the next token with a return stack shallower than `n`.
+    Out(usize),
+}
+
+/// Debugger state of a task.
+#[derive(Clone, Default, Debug)]
+pub struct DebugState {
+    /// Breakpoints and stepping apply to the task.
+    pub attached: bool,
+    /// Stopped by the debugger and suspended.
+    pub stopped: bool,
+    /// Resumed by the debugger, the token at the instruction pointer is
+    /// executed without stopping.
+    pub resumed: bool,
+    pub step: Step,
+    pub breakpoints: Vec<Breakpoint>,
+    /// Task debugged by this task, 1-based as `me`, 0 if none.
+    pub target: usize,
+}
+
+/// Token executed, recorded in the trace.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub task: usize,
+    pub xt: usize,
+    /// Address of the token.
+    pub ip: usize,
+    /// Top of data stack before the token is executed, 0 if the stack is
+    /// empty.
+    pub tos: isize,
+    pub time_ns: u64,
+}
+
+/// Maximum number of entries in the trace.
+pub const MAX_TRACE: usize = 65536;
+
+/// Ring buffer of the last tokens executed.
+#[derive(Clone, Default, Debug)]
+pub struct TraceBuffer {
+    entries: Vec<TraceEntry>,
+    /// Index of the next entry to overwrite.
+    next: usize,
+    /// Number of entries recorded, up to the capacity.
+    len: usize,
+}
+
+impl TraceBuffer {
+    /// Create a trace buffer holding the last `capacity` entries.
+    pub fn with_capacity(capacity: usize) -> TraceBuffer {
+        TraceBuffer {
+            entries: vec![TraceEntry::default(); capacity],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Record `entry`, overwriting the oldest one if full.
+    #[inline(always)]
+    pub fn push(&mut self, entry: TraceEntry) {
+        let capacity = self.entries.len();
+        if capacity > 0 {
+            self.entries[self.next] = entry;
+            self.next = (self.next + 1) % capacity;
+            self.len = (self.len + 1).min(capacity);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.next = 0;
+        self.len = 0;
+    }
+
+    /// Entries recorded, the oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &TraceEntry> {
+        let capacity = self.entries.len();
+        let first = (self.next + capacity - self.len) % capacity.max(1);
+        (0..self.len).map(move |k| &self.entries[(first + k) % capacity])
+    }
+}
+
+/// Marks of the cells of compiled threads executed while measuring code
+/// coverage, with the source lines the threads were compiled from.
+#[derive(Clone, Default, Debug)]
+pub struct CoverageMap {
+    /// Marks indexed by the offset of a cell in data space divided by the
+    /// size of a cell. Empty if not measuring coverage.
+    marks: Vec<u8>,
+    /// Offset of the first cell compiled while measuring coverage.
+    start: usize,
+    /// Paths of the sources compiled from.
+    paths: Vec<String>,
+    /// Offset of the first cell compiled from a source line, the index of
+    /// the path of the source plus one or 0 if not compiled from a source,
+    /// and the line number, sorted by offset.
+    lines: Vec<(usize, usize, usize)>,
+}
+
+impl CoverageMap {
+    /// The token in the cell was executed.
+    pub const EXECUTED: u8 = 1;
+    /// The `0branch` in the cell jumped to its destination.
+    pub const TAKEN: u8 = 2;
+    /// The `0branch` in the cell fell through.
+    pub const FALLEN: u8 = 4;
+
+    /// Create a coverage map of `cells` cells, for code compiled from
+    /// offset `start`.
+    pub fn new(cells: usize, start: usize) -> CoverageMap {
+        CoverageMap {
+            marks: vec![0; cells],
+            start,
+            paths: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        !self.marks.is_empty()
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Add `bits` to the marks of the cell at `offset`.
+    #[inline(always)]
+    pub fn mark(&mut self, offset: usize, bits: u8) {
+        if let Some(m) = self.marks.get_mut(offset / mem::size_of::<usize>()) {
+            *m |= bits;
+        }
+    }
+
+    /// Marks of the cell at `offset`.
+    pub fn marks(&self, offset: usize) -> u8 {
+        self.marks
+            .get(offset / mem::size_of::<usize>())
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Clear all marks, keeping the source lines.
+    pub fn clear(&mut self) {
+        for m in self.marks.iter_mut() {
+            *m = 0;
+        }
+    }
+
+    /// Record that code compiled from `offset` comes from `line` of source
+    /// `path`, or not from a source if `None`.
+    pub fn record_line(&mut self, offset: usize, location: Option<(String, usize)>) {
+        // Code at and after `offset` has been forgotten.
+        let n = self.lines.partition_point(|l| l.0 < offset);
+        self.lines.truncate(n);
+        let (path, line) = match location {
+            Some((path, line)) => {
+                let i = match self.paths.iter().position(|p| *p == path) {
+                    Some(i) => i,
+                    None => {
+                        self.paths.push(path);
+                        self.paths.len() - 1
+                    }
+                };
+                (i + 1, line)
+            }
+            None => (0, 0),
+        };
+        match self.lines.last() {
+            Some(&(_, p, l)) if p == path && l == line => {}
+            _ => self.lines.push((offset, path, line)),
+        }
+    }
+
+    /// Source path and line the cell at `offset` was compiled from.
+    pub fn line(&self, offset: usize) -> Option<(&str, usize)> {
+        let n = self.lines.partition_point(|l| l.0 <= offset);
+        match n.checked_sub(1).map(|i| self.lines[i]) {
+            Some((_, path, line)) if path > 0 => Some((&self.paths[path - 1], line)),
+            _ => None,
+        }
+    }
+}
+
+/// Maximum number of watchpoints.
+pub const MAX_WATCHPOINTS: usize = 8;
+
+/// Contents of a watched range before or after a write.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchValue {
+    Cell(isize),
+    Char(u8),
+    Float(f64),
+    /// Bytes written into the watched range by `move`.
+    Bytes(Vec<u8>),
+}
+
+impl Display for WatchValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WatchValue::Cell(v) => write!(f, "{}", v),
+            WatchValue::Char(v) => write!(f, "{}", v),
+            WatchValue::Float(v) => write!(f, "{:e}", v),
+            WatchValue::Bytes(ref bytes) => {
+                for (k, b) in bytes.iter().enumerate() {
+                    if k > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{:02X}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Write into a watched range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    pub task: usize,
+    /// Word writing, such as `!`.
+    pub xt: usize,
+    /// Definition executing the word, if any.
+    pub caller: Option<usize>,
+    /// Address written.
+    pub addr: usize,
+    pub old: WatchValue,
+    pub new: WatchValue,
+}
+
+/// Profiler, sampler, trace, coverage and watchpoints shared by all tasks.
+#[derive(Default)]
+pub struct Instrumentation {
+    /// Record calls and execution times of words.
+    pub(crate) profiling: bool,
+    /// Period of sampling the words being executed, 0 if not sampling.
+    pub(crate) sample_period_ns: u64,
+    /// Time of the next sample.
+    pub(crate) next_sample_ns: u64,
+    /// Trace of the tokens executed by `run`, empty if not tracing.
+    pub(crate) trace: TraceBuffer,
+    /// Code coverage, empty if not measuring coverage.
+    pub(crate) coverage: CoverageMap,
+    /// Watched ranges of addresses, from the start up to but not including
+    /// the end.
+    pub(crate) watchpoints: Vec<(usize, usize)>,
+    /// Last write into a watched range.
+    pub(crate) watch_hit: Option<WatchHit>,
+}
+
+impl Instrumentation {
+    pub fn new() -> Instrumentation {
+        Instrumentation::default()
+    }
+
+    /// Does any tool hook into the tokens executed?
+    ///
+    /// Watchpoints do not, they hook into the stores.
+    pub fn is_on(&self) -> bool {
+        self.profiling
+            || self.sample_period_ns != 0
+            || self.trace.capacity() != 0
+            || self.coverage.is_on()
+    }
+}
+
+/// Locals and optimizer of the definition being compiled.
+#[derive(Default)]
+pub struct Compiler {
+    /// Names of the locals of the current definition and whether they are
+    /// float locals, in the order of their slots in the local frame.
+    pub(crate) locals: Vec<(String, bool)>,
+    /// Fuse tokens into superinstructions and fold literal arithmetic while
+    /// compiling.
+    pub(crate) optimizing: bool,
+    /// Superinstructions, with the two words fused into each.
+    pub(crate) superinstructions: Vec<(usize, usize, usize)>,
+    /// Tokens just compiled that the optimizer may fuse or fold with the
+    /// next one, as their address, execution token and literal.
+    pub(crate) peephole: Vec<(usize, usize, isize)>,
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::default()
+    }
+
+    /// Slot of local `name` of the current definition and whether it is a
+    /// float local.
+    pub fn find_local(&self, name: &str) -> Option<(usize, bool)> {
+        self.locals
+            .iter()
+            .rposition(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|slot| (slot, self.locals[slot].1))
+    }
+}
+
+/// Word being executed by a task while profiling.
+#[derive(Clone, Copy, Debug)]
+pub struct Activation {
+    pub xt: usize,
+    pub start_ns: u64,
+    /// Depth of return stack after entering a colon definition, 0 for
+    /// other words.
+    pub depth: usize,
+}
+
+/// Profiler state of a task.
+#[derive(Clone, Default, Debug)]
+pub struct ProfileState {
+    /// Words being executed, the innermost last.
+    pub activations: Vec<Activation>,
+    /// Time the last profiling event of the task happened.
+    pub last_ns: u64,
+}
+
+/// Status returned by `Core::run_for`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// The instruction pointer is outside the data space, nothing to run.
+    Idle,
+    /// The token budget or the deadline ran out, or every task sleeps
+    /// until a periodic task is released. Call `run_for` again to resume
+    /// from the current instruction pointer.
+    Paused,
+    /// An exception is pending.
+    Error(Exception),
+    /// The debugger stopped the current task and no other task is awake.
+    Stopped,
+}
+
+/// What `Core::step` did with the token at the instruction pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stepped {
+    /// The token was executed.
+    Executed,
+    /// The current task, stopped by the debugger or asleep, did not
+    /// execute the token, and a task which is awake was resumed.
+    Switched,
+    /// The debugger stopped the current task and no other task is awake.
+    Stopped,
+    /// No task is awake until a periodic task is released.
+    Waiting,
+    /// No task is awake, and none will be.
+    Idle,
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum Control {
     Default,
@@ -481,6 +1122,15 @@ pub trait Core: Sized {
     fn data_space_const(&self) -> &DataSpace;
     /// Numeric output buffer
     fn hold_buffer(&mut self) -> &mut String;
+    /// Exceptions defined by the application
+    fn exceptions(&self) -> &Exceptions;
+    fn exceptions_mut(&mut self) -> &mut Exceptions;
+    /// Profiler, sampler, trace, coverage and watchpoints
+    fn instrumentation(&self) -> &Instrumentation;
+    fn instrumentation_mut(&mut self) -> &mut Instrumentation;
+    /// Locals and optimizer of the definition being compiled
+    fn compiler(&self) -> &Compiler;
+    fn compiler_mut(&mut self) -> &mut Compiler;
     /// Get `output_buffer`.
     fn output_buffer(&mut self) -> &mut Option<String>;
     /// Set `output_buffer` to `Some(buffer)`.
@@ -513,6 +1163,8 @@ pub trait Core: Sized {
     fn state(&mut self) -> &mut State;
     fn references(&mut self) -> &mut ForwardReferences;
     fn system_time_ns(&self) -> u64;
+    /// Number of tasks, fixed when the VM is constructed.
+    fn num_tasks(&self) -> usize;
     /// Current task
     fn current_task(&self) -> usize;
     /// Set curretn task.
@@ -527,6 +1179,14 @@ pub trait Core: Sized {
     ///
     /// No operation if there is no task `i`.
     fn set_awake(&mut self, i: usize, v: bool);
+    /// Is the slot of task `i` in use?
+    ///
+    /// False if there is no task `i`.
+    fn in_use(&self, i: usize) -> bool;
+    /// Mark the slot of task `i` in use or free.
+    ///
+    /// No operation if there is no task `i`.
+    fn set_in_use(&mut self, i: usize, v: bool);
     /// Bitset to check forward declaration of labels.
     fn forward_bitset(&self) -> &BitSet;
     /// Mutable bitset to check forward declaration of labels.
@@ -548,8 +1208,10 @@ pub trait Core: Sized {
         self.add_compile_only("lit", Core::lit);
         self.add_compile_only("flit", Core::flit);
         self.add_compile_only("_s\"", Core::p_s_quote);
+        self.add_compile_only("_abort\"", Core::p_abort_quote);
         self.add_compile_only("branch", Core::branch);
         self.add_compile_only("0branch", Core::zero_branch);
+        self.add_compile_only("_call", Core::_call);
         self.add_compile_only("_do", Core::_do);
         self.add_compile_only("_qdo", Core::_qdo);
         self.add_compile_only("_loop", Core::_loop);
@@ -659,6 +1321,15 @@ pub trait Core: Sized {
         self.add_primitive("mod", Core::p_mod);
         self.add_primitive("abs", Core::abs);
         self.add_primitive("negate", Core::negate);
+        self.add_primitive("u<", Core::u_less_than);
+        self.add_primitive("s>d", Core::s_to_d);
+        self.add_primitive("m*", Core::m_star);
+        self.add_primitive("um*", Core::um_star);
+        self.add_primitive("um/mod", Core::um_slash_mod);
+        self.add_primitive("sm/rem", Core::sm_slash_rem);
+        self.add_primitive("fm/mod", Core::fm_slash_mod);
+        self.add_primitive("*/", Core::star_slash);
+        self.add_primitive("*/mod", Core::star_slash_mod);
         self.add_primitive("parse-word", Core::parse_word);
         self.add_primitive("char", Core::char);
         self.add_primitive("_skip", Core::_skip);
@@ -679,6 +1350,10 @@ pub trait Core: Sized {
         self.add_primitive("0stacks", Core::clear_stacks);
         self.add_primitive("reset", Core::reset);
         self.add_primitive("abort", Core::abort);
+        self.add_primitive("catch", Core::catch);
+        self.add_primitive("throw", Core::throw);
+        self.add_primitive("_end-catch", Core::end_catch);
+        self.add_primitive("exception", Core::p_exception);
         self.add_primitive("compiling?", Core::p_compiling);
         self.add_primitive("token-empty?", Core::token_empty);
         self.add_primitive(".token", Core::dot_token);
@@ -696,6 +1371,7 @@ pub trait Core: Sized {
         self.references().idx_exit = self.find("exit").expect("exit undefined");
         self.references().idx_zero_branch = self.find("0branch").expect("0branch undefined");
         self.references().idx_branch = self.find("branch").expect("branch undefined");
+        self.references().idx__call = self.find("_call").expect("_call undefined");
         self.references().idx_do = self.find("_do").expect("_do undefined");
         self.references().idx_qdo = self.find("_qdo").expect("_qdo undefined");
         self.references().idx_loop = self.find("_loop").expect("_loop undefined");
@@ -709,6 +1385,12 @@ pub trait Core: Sized {
 
         self.patch_compilation_semanticses();
 
+        self.add_doer(":", Core::nest, Core::compile_nest);
+        self.add_doer("create", Core::p_var, Core::compile_var);
+        self.add_doer("constant", Core::p_const, Core::compile_const);
+        self.add_doer("marker", Core::unmark, Core::compile_unmark);
+        self.add_doer("does>", Core::xdoes, Core::compile_var);
+
         {
             // Multitasker
             self.add_compile_only("pause", Core::pause);
@@ -716,16 +1398,63 @@ pub trait Core: Sized {
             self.add_primitive("me", Core::me);
             self.add_primitive("suspend", Core::suspend);
             self.add_primitive("resume", Core::resume);
+            self.add_primitive("spawn", Core::p_spawn);
+            self.add_primitive("kill", Core::p_kill);
+            self.add_primitive("_terminate", Core::p_terminate);
+            // Code executed by a spawned task: ( xt -- ) execute _terminate
+            self.add_primitive("_task", Core::nest);
+            let idx_execute = self.find("execute").expect("execute undefined");
+            let idx_terminate = self.find("_terminate").expect("_terminate undefined");
+            self.compile_word(idx_execute);
+            self.compile_word(idx_terminate);
+            let dfa = self.wordlist()[self.wordlist().last].dfa();
+            let here = self.data_space().here();
+            self.data_space().mark_code(dfa, here);
+            self.references().idx__task = self.find("_task").expect("_task undefined");
+        }
+        {
+            // Code executed by catch: ( xt -- ) execute _end-catch exit
+            self.add_primitive("_catch", Core::nest);
+            let idx_execute = self.find("execute").expect("execute undefined");
+            let idx_end_catch = self.find("_end-catch").expect("_end-catch undefined");
+            let idx_exit = self.references().idx_exit;
+            self.compile_word(idx_execute);
+            self.compile_word(idx_end_catch);
+            self.compile_word(idx_exit);
+            let dfa = self.wordlist()[self.wordlist().last].dfa();
+            let here = self.data_space().here();
+            self.data_space().mark_code(dfa, here);
+            self.references().idx__catch = self.find("_catch").expect("_catch undefined");
         }
         self.set_awake(0, true);
+        self.set_in_use(0, true);
     }
 
     /// Add a primitive word to word list.
     fn add_primitive(&mut self, name: &str, action: fn(&mut Self)) {
         let nfa = self.data_space().compile_str(name);
         self.data_space().align();
-        let word = Word::new(action, Core::compile_word, nfa, self.data_space().here());
+        let dfa = self.data_space().here();
+        self.data_space().mark_code(nfa, dfa);
+        let word = Word::new(action, Core::compile_word, nfa, dfa);
         self.wordlist_mut().push(name, word);
+        let a = self.wordlist_mut().action_index(action);
+        let c = self.wordlist_mut().compilation_index(Core::compile_word);
+        self.wordlist_mut().fold_fingerprint(name, a, c);
+    }
+
+    /// Register `action` and `compilation_semantics` of words created by
+    /// defining word `name`, so that a dictionary image containing such
+    /// words can be loaded before any of them is defined.
+    fn add_doer(
+        &mut self,
+        name: &str,
+        action: fn(&mut Self),
+        compilation_semantics: fn(&mut Self, usize),
+    ) {
+        let a = self.wordlist_mut().action_index(action);
+        let c = self.wordlist_mut().compilation_index(compilation_semantics);
+        self.wordlist_mut().fold_fingerprint(name, a, c);
     }
 
     /// Set the last definition immediate.
@@ -761,7 +1490,22 @@ pub trait Core: Sized {
 
     /// Execute word at position `i`.
     fn execute_word(&mut self, i: usize) {
-        self.state().word_pointer = i;
+        let hooks = {
+            let state = self.state();
+            state.word_pointer = i;
+            state.hooks
+        };
+        if hooks {
+            self.execute_hooked(i);
+        } else {
+            self.dispatch(i);
+        }
+    }
+
+    /// Execute word at position `i`, already in the word pointer, without
+    /// the hooks of the profiler and the sampler.
+    #[inline(always)]
+    fn dispatch(&mut self, i: usize) {
         if i < self.wordlist().len() {
             (self.wordlist()[i].action())(self);
         } else {
@@ -769,11 +1513,181 @@ pub trait Core: Sized {
         }
     }
 
-    /// Find the word with name `name`.
+    /// Execute word at position `i`, already in the word pointer, with the
+    /// hooks of the profiler and the sampler.
+    #[inline(never)]
+    fn execute_hooked(&mut self, i: usize) {
+        let task = self.current_task();
+        if self.instrumentation().profiling && i < self.wordlist().len() {
+            self.execute_profiled(i);
+        } else {
+            self.dispatch(i);
+        }
+        if self.instrumentation().sample_period_ns != 0 && i < self.wordlist().len() {
+            self.sample(task, i);
+        }
+    }
+
+    /// Set the hooks flag of every task after a tool is switched on or off,
+    /// or the debugger is attached to or detached from a task.
+    ///
+    /// Tokens are executed without checking any tool while the flag of the
+    /// task is off.
+    fn update_hooks(&mut self) {
+        let on = self.instrumentation().is_on();
+        let current_task = self.current_task();
+        for i in 0..self.num_tasks() {
+            self.set_current_task(i);
+            let attached = self.state().debug.attached;
+            self.state().hooks = on || attached;
+        }
+        self.set_current_task(current_task);
+    }
+
+    /// Take a sample if the sampling period elapsed after task `task`
+    /// executed word `i`.
+    ///
+    /// The sample is the chain of words the task is executing, from its
+    /// return stack to word `i`, rooted at the task.
+    #[inline(never)]
+    fn sample(&mut self, task: usize, i: usize) {
+        let now = self.system_time_ns();
+        if now < self.instrumentation().next_sample_ns {
+            return;
+        }
+        self.instrumentation_mut().next_sample_ns = now + self.instrumentation().sample_period_ns;
+        // `pause` resumes another task.
+        let next_task = self.current_task();
+        self.set_current_task(task);
+        let mut addrs: Vec<usize> = Vec::new();
+        for k in 0..self.r_stack().len() {
+            let addr = self.r_stack()[k] as usize;
+            if self.is_return_address(addr) {
+                addrs.push(addr);
+            }
+        }
+        let ip = self.state().instruction_pointer;
+        if self.data_space().start() <= ip && ip < self.data_space().here() {
+            addrs.push(ip);
+        }
+        let mut chain: Vec<usize> = addrs
+            .into_iter()
+            .filter_map(|addr| self.wordlist().find_xt(addr))
+            .collect();
+        // A colon definition entered is already in the chain.
+        if chain.last() != Some(&i) {
+            chain.push(i);
+        }
+        let mut folded = format!("task{}", task + 1);
+        for xt in chain {
+            let nfa = self.wordlist()[xt].nfa();
+            let name = unsafe { self.data_space().get_str(nfa) };
+            write!(folded, ";{}", name).unwrap();
+        }
+        *self.state().samples.entry(folded).or_insert(0) += 1;
+        self.set_current_task(next_task);
+    }
+
+    /// Does `addr` on the return stack look like a return address, that is
+    /// an address in the compiled code following a token which calls?
+    ///
+    /// Loop parameters and other data on the return stack usually do not.
+    fn is_return_address(&mut self, addr: usize) -> bool {
+        let cell = mem::size_of::<usize>();
+        if addr < self.data_space().start() + cell
+            || addr >= self.data_space().here()
+            || !addr.is_multiple_of(cell)
+        {
+            return false;
+        }
+        let xt = unsafe { self.data_space().get_usize(addr - cell) };
+        if xt == 0 || xt >= self.wordlist().len() {
+            return false;
+        }
+        let action = self.wordlist()[xt].action() as usize;
+        let calls: [fn(&mut Self); 4] = [Core::nest, Core::xdoes, Core::execute, Core::catch];
+        calls.iter().any(|&f| f as usize == action)
+    }
+
+    /// Execute word `i` and record its call and execution times.
+    ///
+    /// A colon definition stays active until the return stack gets
+    /// shallower than after entering it.
+    #[inline(never)]
+    fn execute_profiled(&mut self, i: usize) {
+        let task = self.current_task();
+        let t0 = self.system_time_ns();
+        self.profile_event(t0);
+        let action = self.wordlist()[i].action();
+        let nest: fn(&mut Self) = Core::nest;
+        let xdoes: fn(&mut Self) = Core::xdoes;
+        let nests = action as usize == nest as usize || action as usize == xdoes as usize;
+        self.wordlist_mut()[i].profile.calls += 1;
+        let index = self.state().profile.activations.len();
+        self.state().profile.activations.push(Activation {
+            xt: i,
+            start_ns: t0,
+            depth: 0,
+        });
+        action(self);
+        let t1 = self.system_time_ns();
+        // `pause` resumes another task, finish with this one first.
+        let next_task = self.current_task();
+        self.set_current_task(task);
+        self.profile_event(t1);
+        if index < self.state().profile.activations.len() {
+            if nests {
+                let depth = self.r_stack().len() as usize;
+                self.state().profile.activations[index].depth = depth;
+            } else {
+                let a = self.state().profile.activations.remove(index);
+                self.wordlist_mut()[a.xt].profile.inclusive_ns += t1 - a.start_ns;
+            }
+        }
+        if next_task != task {
+            self.set_current_task(next_task);
+            // Time before the task resumes is not its own.
+            self.state().profile.last_ns = t1;
+        }
+    }
+
+    /// Charge time since the last profiling event of the current task to
+    /// the innermost word it executes, and end the colon definitions which
+    /// returned.
+    fn profile_event(&mut self, now: u64) {
+        let depth = self.r_stack().len() as usize;
+        let last_ns = self.state().profile.last_ns;
+        self.state().profile.last_ns = now;
+        if let Some(a) = self.state().profile.activations.last().cloned() {
+            self.wordlist_mut()[a.xt].profile.exclusive_ns += now.saturating_sub(last_ns);
+        }
+        while let Some(a) = self.state().profile.activations.last().cloned() {
+            if a.depth == 0 || depth >= a.depth {
+                break;
+            }
+            self.state().profile.activations.pop();
+            self.wordlist_mut()[a.xt].profile.inclusive_ns += now - a.start_ns;
+        }
+    }
+
+    /// Find the word with name `name` in the search order.
     /// If not found returns zero.
     fn find(&mut self, name: &str) -> Option<usize> {
+        let mut i = self.wordlist().search_order().len();
+        while i > 0 {
+            i -= 1;
+            let wid = self.wordlist().search_order()[i];
+            if let Some(w) = self.find_in(wid, name) {
+                return Some(w);
+            }
+        }
+        None
+    }
+
+    /// Find the word with name `name` in word list `wid`.
+    fn find_in(&mut self, wid: usize, name: &str) -> Option<usize> {
         let hash = Wordlist::<Self>::hash(name);
-        let mut w = self.wordlist().buckets[hash as usize % BUCKET_SIZE];
+        let mut w = self.wordlist().buckets[wid][hash as usize % BUCKET_SIZE];
         while w != 0 {
             if !self.wordlist()[w].is_hidden() {
                 {
@@ -803,35 +1717,373 @@ pub trait Core: Sized {
         while self.data_space().start() <= ip
             && ip + mem::size_of::<isize>() <= self.data_space().limit()
         {
-            let w = unsafe { self.data_space().get_isize(ip) as usize };
-            self.state().instruction_pointer += mem::size_of::<isize>();
-            self.execute_word(w);
+            if matches!(self.step(), Stepped::Stopped | Stepped::Idle) {
+                return;
+            }
             ip = self.state().instruction_pointer;
         }
     }
 
+    /// Evaluate a compiled program following self.state().instruction_pointer
+    /// for at most `max_tokens` tokens or until `system_time_ns` reaches
+    /// `deadline_ns`, whichever comes first.
+    ///
+    /// Returns `RunStatus::Paused` if the program is not finished, and the
+    /// next call resumes exactly where this one left off. An exception stops
+    /// the loop with `RunStatus::Error`; the error stays pending until it is
+    /// cleared, so call `set_error(None)` or `reset` before resuming.
+    fn run_for(&mut self, max_tokens: usize, deadline_ns: u64) -> RunStatus {
+        if let Some(e) = self.last_error() {
+            return RunStatus::Error(e);
+        }
+        let mut tokens = 0;
+        loop {
+            let ip = self.state().instruction_pointer;
+            if !(self.data_space().start() <= ip
+                && ip + mem::size_of::<isize>() <= self.data_space().limit())
+            {
+                return RunStatus::Idle;
+            }
+            if tokens >= max_tokens || self.system_time_ns() >= deadline_ns {
+                return RunStatus::Paused;
+            }
+            match self.step() {
+                Stepped::Executed => {
+                    tokens += 1;
+                    if let Some(e) = self.last_error() {
+                        return RunStatus::Error(e);
+                    }
+                }
+                Stepped::Switched => {}
+                Stepped::Stopped => return RunStatus::Stopped,
+                Stepped::Waiting => return RunStatus::Paused,
+                Stepped::Idle => return RunStatus::Idle,
+            }
+        }
+    }
+
     // Execute one step of vm loop.
     //
     // Return true if there are more steps to execute, false if otherwise.
     fn forth(&mut self) -> bool {
-        let mut ip = self.state().instruction_pointer;
+        let ip = self.state().instruction_pointer;
         if self.data_space().start() <= ip
             && ip + mem::size_of::<isize>() <= self.data_space().limit()
         {
-            let w = unsafe { self.data_space().get_isize(ip) as usize };
-            self.state().instruction_pointer += mem::size_of::<isize>();
-            self.execute_word(w);
-            ip = self.state().instruction_pointer;
-            true
+            !matches!(self.step(), Stepped::Stopped | Stepped::Idle)
         } else {
             false
         }
     }
 
+    /// Execute the token at the instruction pointer, which must be in data
+    /// space, unless the debugger stops the current task before it.
+    #[inline(always)]
+    fn step(&mut self) -> Stepped {
+        let (ip, hooks) = {
+            let state = self.state();
+            (state.instruction_pointer, state.hooks)
+        };
+        if hooks {
+            return self.step_hooked();
+        }
+        let w = unsafe { self.data_space().get_usize(ip) };
+        {
+            let state = self.state();
+            state.instruction_pointer = ip + mem::size_of::<isize>();
+            state.word_pointer = w;
+        }
+        self.dispatch(w);
+        Stepped::Executed
+    }
+
+    /// `step` with the hooks of the debugger, the trace, the coverage, the
+    /// profiler and the sampler.
+    #[inline(never)]
+    fn step_hooked(&mut self) -> Stepped {
+        if !self.awake(self.current_task()) {
+            return self.idle();
+        }
+        let hooks = self.instrumentation().is_on() || self.state().debug.attached;
+        self.state().hooks = hooks;
+        if self.state().debug.attached && self.debug_break() {
+            return if self.awake(self.current_task()) {
+                Stepped::Switched
+            } else {
+                Stepped::Stopped
+            };
+        }
+        let ip = self.state().instruction_pointer;
+        let w = unsafe { self.data_space().get_isize(ip) as usize };
+        if self.instrumentation().trace.capacity() != 0 {
+            self.record_trace(ip, w);
+        }
+        if self.instrumentation().coverage.is_on() {
+            self.mark_coverage(ip, w);
+        }
+        {
+            let state = self.state();
+            state.instruction_pointer = ip + mem::size_of::<isize>();
+            state.word_pointer = w;
+        }
+        self.execute_hooked(w);
+        Stepped::Executed
+    }
+
+    /// Resume a task released since the current task, which is not awake,
+    /// paused with no other task awake.
+    ///
+    /// A sleeping task must not run on, so when there is still no task
+    /// awake, return `Stepped::Waiting` if a periodic task waits for its
+    /// next period, or `Stepped::Idle` otherwise.
+    #[inline(never)]
+    fn idle(&mut self) -> Stepped {
+        self.pause();
+        let current_task = self.current_task();
+        if self.awake(current_task) {
+            return Stepped::Switched;
+        }
+        if self.state().debug.stopped {
+            return Stepped::Stopped;
+        }
+        let mut waiting = false;
+        for i in 0..self.num_tasks() {
+            self.set_current_task(i);
+            waiting |= self.state().schedule.waiting;
+        }
+        self.set_current_task(current_task);
+        if waiting {
+            Stepped::Waiting
+        } else {
+            Stepped::Idle
+        }
+    }
+
+    /// Record token `xt` at address `ip` about to be executed by the
+    /// current task in the trace.
+    #[inline(never)]
+    fn record_trace(&mut self, ip: usize, xt: usize) {
+        let tos = if self.s_stack().is_empty() {
+            0
+        } else {
+            self.s_stack().last().unwrap_or(0)
+        };
+        let entry = TraceEntry {
+            task: self.current_task(),
+            xt,
+            ip,
+            tos,
+            time_ns: self.system_time_ns(),
+        };
+        self.instrumentation_mut().trace.push(entry);
+    }
+
+    /// Mark token `xt` at address `ip` about to be executed as covered,
+    /// and for `0branch`, whether it jumps to its destination.
+    #[inline(never)]
+    fn mark_coverage(&mut self, ip: usize, xt: usize) {
+        let idx_zero_branch = self.references().idx_zero_branch;
+        let fused = self.fused_words(xt).map(|(_, second)| second);
+        let bits = if xt == idx_zero_branch || fused == Some(idx_zero_branch) {
+            if self.s_stack().last() == Some(0) {
+                CoverageMap::EXECUTED | CoverageMap::TAKEN
+            } else {
+                CoverageMap::EXECUTED | CoverageMap::FALLEN
+            }
+        } else {
+            CoverageMap::EXECUTED
+        };
+        let offset = self.data_space().offset(ip);
+        self.instrumentation_mut().coverage.mark(offset, bits);
+    }
+
+    /// Trace recorded, the oldest token first, one per line with the time,
+    /// the task, the address and the name of the token, and the top of
+    /// data stack before the token is executed.
+    fn trace_dump(&mut self) -> String {
+        let entries: Vec<TraceEntry> = self.instrumentation().trace.iter().cloned().collect();
+        let mut dump = String::new();
+        for e in entries {
+            let name = if e.xt < self.wordlist().len() {
+                let nfa = self.wordlist()[e.xt].nfa();
+                unsafe { self.data_space().get_str(nfa) }.to_string()
+            } else {
+                format!("unknown({})", e.xt)
+            };
+            writeln!(
+                dump,
+                "{:>12} task{} {:X} {} {}",
+                e.time_ns,
+                e.task + 1,
+                e.ip,
+                name,
+                e.tos
+            )
+            .unwrap();
+        }
+        dump
+    }
+
+    /// Check breakpoints and stepping of the current task before it
+    /// executes the token at the instruction pointer.
+    ///
+    /// Return true if the task stops, in which case it is suspended and
+    /// the next task which is awake is resumed.
+    #[inline(never)]
+    fn debug_break(&mut self) -> bool {
+        let ip = self.state().instruction_pointer;
+        let xt = unsafe { self.data_space().get_usize(ip) };
+        let depth = self.r_stack().len() as usize;
+        let debug = &mut self.state().debug;
+        // A stopped task woken by `resume` runs on as if resumed.
+        if debug.resumed || debug.stopped {
+            debug.resumed = false;
+            debug.stopped = false;
+            return false;
+        }
+        let stop = match debug.step {
+            Step::Run => false,
+            Step::Into => true,
+            Step::Over(n) => depth <= n,
+            Step::Out(n) => depth < n,
+        } || debug.breakpoints.iter().any(|&b| match b {
+            Breakpoint::Word(w) => w == xt,
+            Breakpoint::Address(addr) => addr == ip,
+        });
+        if stop {
+            debug.stopped = true;
+            debug.step = Step::Run;
+            let i = self.current_task();
+            self.set_awake(i, false);
+            self.pause();
+        }
+        stop
+    }
+
     fn compile_word(&mut self, word_index: usize) {
+        if self.instrumentation().coverage.is_on() {
+            self.record_source_line();
+        }
+        if self.compiler().optimizing && self.optimize(word_index) {
+            return;
+        }
         self.data_space().compile_usize(word_index as usize);
     }
 
+    /// Fold `xt` with the two literals compiled before it, or fuse it with
+    /// the token compiled before it into a superinstruction.
+    ///
+    /// Return true if `xt` has been compiled that way.
+    fn optimize(&mut self, xt: usize) -> bool {
+        let cell = mem::size_of::<usize>();
+        let here = self.data_space().here();
+        let idx_lit = self.references().idx_lit;
+        // Forget tokens not directly followed by `xt`.
+        if let Some(&(addr, prev, _)) = self.compiler().peephole.last() {
+            let len = if prev == idx_lit { 2 * cell } else { cell };
+            if addr + len != here {
+                self.compiler_mut().peephole.clear();
+            }
+        }
+        let n = self.compiler().peephole.len();
+        if n >= 2 {
+            let (addr, first, n1) = self.compiler().peephole[n - 2];
+            let (_, second, n2) = self.compiler().peephole[n - 1];
+            if first == idx_lit && second == idx_lit {
+                if let Some(v) = self.fold(xt, n1, n2) {
+                    self.compiler_mut().peephole.truncate(n - 2);
+                    self.data_space().truncate(addr);
+                    self.compile_integer(v);
+                    return true;
+                }
+            }
+        }
+        if let Some(&(addr, prev, _)) = self.compiler().peephole.last() {
+            let fused = self
+                .compiler()
+                .superinstructions
+                .iter()
+                .find(|s| s.1 == prev && s.2 == xt)
+                .map(|s| s.0);
+            if let Some(fused) = fused {
+                unsafe { self.data_space().put_usize(fused, addr) };
+                self.compiler_mut().peephole.clear();
+                return true;
+            }
+        }
+        // Literals are added by `compile_integer` with their values.
+        if xt != idx_lit {
+            if self.compiler().superinstructions.iter().any(|s| s.1 == xt) {
+                self.compiler_mut().peephole.push((here, xt, 0));
+            } else {
+                self.compiler_mut().peephole.clear();
+            }
+        }
+        false
+    }
+
+    /// Result of `n1 n2 xt execute` if `xt` is an arithmetic or logic
+    /// primitive that can be computed at compile time.
+    fn fold(&mut self, xt: usize, n1: isize, n2: isize) -> Option<isize> {
+        let action = self.wordlist()[xt].action() as usize;
+        let plus: fn(&mut Self) = Core::plus;
+        let minus: fn(&mut Self) = Core::minus;
+        let star: fn(&mut Self) = Core::star;
+        let and: fn(&mut Self) = Core::and;
+        let or: fn(&mut Self) = Core::or;
+        let xor: fn(&mut Self) = Core::xor;
+        if action == plus as usize {
+            Some(n1.wrapping_add(n2))
+        } else if action == minus as usize {
+            Some(n1.wrapping_sub(n2))
+        } else if action == star as usize {
+            Some(n1.wrapping_mul(n2))
+        } else if action == and as usize {
+            Some(n1 & n2)
+        } else if action == or as usize {
+            Some(n1 | n2)
+        } else if action == xor as usize {
+            Some(n1 ^ n2)
+        } else {
+            None
+        }
+    }
+
+    /// Code compiled from now on is the destination of a jump, so the
+    /// optimizer must not fuse it with the code before.
+    fn peephole_barrier(&mut self) {
+        self.compiler_mut().peephole.clear();
+    }
+
+    /// Words fused into superinstruction `xt`, `None` if `xt` is not a
+    /// superinstruction.
+    fn fused_words(&self, xt: usize) -> Option<(usize, usize)> {
+        self.compiler()
+            .superinstructions
+            .iter()
+            .find(|s| s.0 == xt)
+            .map(|s| (s.1, s.2))
+    }
+
+    /// Record the source line code compiled from `here` comes from, for
+    /// code coverage.
+    fn record_source_line(&mut self) {
+        let here = self.data_space().here();
+        let offset = self.data_space().offset(here);
+        let id = self.source_id();
+        let location = if id > 0 {
+            match self.sources().get(id as usize - 1) {
+                Some(Some(source)) => Some((source.path().to_string(), source.line())),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        self.instrumentation_mut()
+            .coverage
+            .record_line(offset, location);
+    }
+
     fn compile_nest(&mut self, word_index: usize) {
         self.compile_word(word_index);
     }
@@ -868,8 +2120,12 @@ pub trait Core: Sized {
     /// Compile integer `i`.
     fn compile_integer(&mut self, i: isize) {
         let idx = self.references().idx_lit;
+        let here = self.data_space().here();
         self.compile_word(idx);
         self.data_space().compile_isize(i as isize);
+        if self.compiler().optimizing {
+            self.compiler_mut().peephole.push((here, idx, i));
+        }
     }
 
     fn flit(&mut self) {
@@ -908,20 +2164,53 @@ pub trait Core: Sized {
     fn patch_compilation_semanticses(&mut self) {
         let idx_leave = self.find("leave").expect("leave");
         self.wordlist_mut()[idx_leave].compilation_semantics = Self::compile_leave;
+        self.add_doer("leave", Core::leave, Self::compile_leave);
+        let idx_exit = self.find("exit").expect("exit");
+        self.wordlist_mut()[idx_exit].compilation_semantics = Self::compile_exit;
+        self.add_doer("exit", Core::exit, Self::compile_exit);
     }
 
+    /// Continue execution at the destination following `branch`.
+    ///
+    /// Destinations are compiled as offsets from the start of data space, so
+    /// that compiled code does not depend on where data space is allocated.
     fn branch(&mut self) {
         let ip = self.state().instruction_pointer;
-        self.state().instruction_pointer = unsafe { self.data_space().get_isize(ip) as usize };
+        let offset = unsafe { self.data_space().get_usize(ip) };
+        self.state().instruction_pointer = self.data_space().address(offset);
     }
 
-    fn compile_branch(&mut self, destination: usize) -> usize {
-        let idx = self.references().idx_branch;
+    /// Compile word `idx` followed by `destination`. A `destination` of 0 is
+    /// unresolved and patched later.
+    ///
+    /// Return the address following the destination.
+    fn compile_jump(&mut self, idx: usize, destination: usize) -> usize {
         self.compile_word(idx);
-        self.data_space().compile_isize(destination as isize);
+        let offset = if destination == 0 {
+            0
+        } else {
+            self.data_space().offset(destination)
+        };
+        self.data_space().compile_usize(offset);
         self.data_space().here()
     }
 
+    /// Resolve the destination which ends at `part` to `here`.
+    fn resolve_jump(&mut self, part: usize) {
+        self.peephole_barrier();
+        let here = self.data_space().here();
+        let offset = self.data_space().offset(here);
+        unsafe {
+            self.data_space()
+                .put_usize(offset, part - mem::size_of::<isize>());
+        }
+    }
+
+    fn compile_branch(&mut self, destination: usize) -> usize {
+        let idx = self.references().idx_branch;
+        self.compile_jump(idx, destination)
+    }
+
     fn zero_branch(&mut self) {
         let v = self.s_stack().pop();
         if v == 0 {
@@ -933,9 +2222,17 @@ pub trait Core: Sized {
 
     fn compile_zero_branch(&mut self, destination: usize) -> usize {
         let idx = self.references().idx_zero_branch;
-        self.compile_word(idx);
-        self.data_space().compile_isize(destination as isize);
-        self.data_space().here()
+        self.compile_jump(idx, destination)
+    }
+
+    /// Run-time: ( -- ) ( R: -- nest-sys )
+    ///
+    /// Call the subroutine at the destination following `_call`. `exit` at
+    /// the end of the subroutine returns to the code after the destination.
+    fn _call(&mut self) {
+        let ip = self.state().instruction_pointer;
+        self.r_stack().push((ip + mem::size_of::<isize>()) as isize);
+        self.branch();
     }
 
     /// ( n1|u1 n2|u2 -- ) ( R: -- loop-sys )
@@ -1062,8 +2359,8 @@ pub trait Core: Sized {
             self.abort_with(RETURN_STACK_UNDERFLOW);
             return;
         }
-        self.state().instruction_pointer =
-            unsafe { self.data_space().get_isize(third as usize) as usize };
+        let offset = unsafe { self.data_space().get_usize(third as usize) };
+        self.state().instruction_pointer = self.data_space().address(offset);
     }
 
     fn compile_leave(&mut self, word_idx: usize) {
@@ -1150,10 +2447,7 @@ pub trait Core: Sized {
         } else {
             let here = self.compile_branch(0);
             self.c_stack().push(Control::Else(here));
-            unsafe {
-                self.data_space()
-                    .put_isize(here as isize, if_part - mem::size_of::<isize>());
-            }
+            self.resolve_jump(if_part);
         }
     }
 
@@ -1169,11 +2463,7 @@ pub trait Core: Sized {
         if self.c_stack().underflow() {
             self.abort_with(CONTROL_STRUCTURE_MISMATCH);
         } else {
-            let here = self.data_space().here();
-            unsafe {
-                self.data_space()
-                    .put_isize(here as isize, branch_part - mem::size_of::<isize>());
-            }
+            self.resolve_jump(branch_part);
         }
     }
 
@@ -1244,10 +2534,7 @@ pub trait Core: Sized {
         } else {
             let here = self.compile_branch(0);
             self.c_stack().push(Control::Endof(here));
-            unsafe {
-                self.data_space()
-                    .put_isize(here as isize, of_part - mem::size_of::<isize>());
-            }
+            self.resolve_jump(of_part);
         }
     }
 
@@ -1268,11 +2555,7 @@ pub trait Core: Sized {
             if self.c_stack().underflow() {
                 self.abort_with(CONTROL_STRUCTURE_MISMATCH);
             } else {
-                let here = self.data_space().here();
-                unsafe {
-                    self.data_space()
-                        .put_isize(here as isize, endof_part - mem::size_of::<isize>());
-                }
+                self.resolve_jump(endof_part);
             }
         }
         if self.c_stack().underflow() {
@@ -1282,6 +2565,7 @@ pub trait Core: Sized {
 
     /// Begin a structure that is terminated by `repeat`, `until`, or `again`. `begin ( -- )`.
     fn imm_begin(&mut self) {
+        self.peephole_barrier();
         let here = self.data_space().here();
         self.c_stack().push(Control::Begin(here));
     }
@@ -1323,11 +2607,8 @@ pub trait Core: Sized {
         if self.c_stack().underflow() {
             self.abort_with(CONTROL_STRUCTURE_MISMATCH);
         } else {
-            let here = self.compile_branch(begin_part);
-            unsafe {
-                self.data_space()
-                    .put_isize(here as isize, while_part - mem::size_of::<isize>());
-            }
+            self.compile_branch(begin_part);
+            self.resolve_jump(while_part);
         }
     }
 
@@ -1403,19 +2684,18 @@ pub trait Core: Sized {
     fn imm_label(&mut self) {
         let n = self.s_stack().pop() as usize;
         if 0 < n && n < self.labels().capacity() {
+            self.peephole_barrier();
             let here = self.data_space().here();
             if self.forward_bitset().contains(n as u32) {
                 // Resolve forward references.
                 let mut p = self.labels()[n];
                 loop {
                     let last = unsafe { self.data_space().get_usize(p) };
-                    unsafe {
-                        self.data_space().put_isize(here as isize, p as usize);
-                    }
+                    self.resolve_jump(p + mem::size_of::<isize>());
                     if last == 0 {
                         break;
                     }
-                    p = last;
+                    p = self.data_space().address(last);
                 }
                 self.labels_mut()[n] = here;
                 self.forward_bitset_mut().remove(n as u32);
@@ -1435,28 +2715,38 @@ pub trait Core: Sized {
     ///
     /// Go to label `n`.
     ///
-    /// ```
-    /// +--------+---------------------+-----+-------
-    /// | BRANCH | data ptr of label n | ... |  addr at label n
-    /// +--------+---------------------+-----+-------
-    ///             |                             ^
-    ///             +-----------------------------+
+    /// ```text
+    /// +--------+-------------------+-----+-------
+    /// | BRANCH | offset of label n | ... |  addr at label n
+    /// +--------+-------------------+-----+-------
+    ///             |                           ^
+    ///             +---------------------------+
     ///
     /// [ n ] goto ... [ n ] label ...
     /// [ n ] label ... [ n ]  goto
     /// ```
     fn imm_goto(&mut self) {
+        let idx = self.references().idx_branch;
+        self.jump_to_label(idx);
+    }
+
+    /// Compile word `idx` followed by the offset of label `n`. `n` is on the
+    /// data stack.
+    ///
+    /// Unresolved destinations of label `n` are chained and resolved by
+    /// `label`.
+    fn jump_to_label(&mut self, idx: usize) {
         let n = self.s_stack().pop() as usize;
         if 0 < n && n < self.labels().capacity() {
             if self.forward_bitset().contains(n as u32) {
                 let p = self.labels()[n];
-                let to_patch = self.compile_branch(p) - mem::size_of::<isize>();
+                let to_patch = self.compile_jump(idx, p) - mem::size_of::<isize>();
                 self.labels_mut()[n] = to_patch;
             } else if self.resolved_bitset().contains(n as u32) {
                 let p = self.labels()[n];
-                let _ = self.compile_branch(p);
+                let _ = self.compile_jump(idx, p);
             } else {
-                let to_patch = self.compile_branch(0) - mem::size_of::<isize>();
+                let to_patch = self.compile_jump(idx, 0) - mem::size_of::<isize>();
                 self.labels_mut()[n] = to_patch;
                 self.forward_bitset_mut().add(n as u32);
             }
@@ -1469,12 +2759,13 @@ pub trait Core: Sized {
     ///
     /// Call subroutine at label `n`.
     ///
-    /// ```
-    /// +----+-----------------+----+------------+-------------------------+--+------+
-    /// | LIT | return_addr | >R |  BRANCH | data ptr of label n | ... | EXIT |
-    /// +----+-----------------+----+------------+-------------------------+--+------+
-    ///                       |                                                                                       ^
-    ///                       +-------------------------------------------------------+
+    /// ```text
+    /// +-------+-------------------+-----+-----------------+-----+------+
+    /// | _CALL | offset of label n | ... | addr at label n | ... | EXIT |
+    /// +-------+-------------------+-----+-----------------+-----+------+
+    ///             |                  ^     ^                       |
+    ///             +------------------|-----+                       |
+    ///                                +-----------------------------+
     ///
     /// Usage:
     ///
@@ -1482,11 +2773,8 @@ pub trait Core: Sized {
     /// [ n ] label .. exit ... [ n ] call ...
     /// ```
     fn imm_call(&mut self) {
-        let return_addr = self.data_space().here() + 5 * mem::size_of::<isize>();
-        self.compile_integer(return_addr as _);
-        let idx_to_r = self.references().idx_to_r;
-        self.compile_word(idx_to_r);
-        self.imm_goto();
+        let idx = self.references().idx__call;
+        self.jump_to_label(idx);
     }
 
     /// Execution: ( -- a-ddr )
@@ -1588,13 +2876,8 @@ pub trait Core: Sized {
             self.abort_with(CONTROL_STRUCTURE_MISMATCH);
         } else {
             let idx = self.references().idx_loop;
-            self.compile_word(idx);
-            self.data_space().compile_isize(do_part as isize);
-            let here = self.data_space().here();
-            unsafe {
-                self.data_space()
-                    .put_isize(here as isize, (do_part - mem::size_of::<isize>()) as usize);
-            }
+            self.compile_jump(idx, do_part);
+            self.resolve_jump(do_part);
         }
     }
 
@@ -1616,27 +2899,24 @@ pub trait Core: Sized {
             self.abort_with(CONTROL_STRUCTURE_MISMATCH);
         } else {
             let idx = self.references().idx_plus_loop;
-            self.compile_word(idx);
-            self.data_space().compile_isize(do_part as isize);
-            let here = self.data_space().here();
-            unsafe {
-                self.data_space()
-                    .put_isize(here as isize, (do_part - mem::size_of::<isize>()) as usize);
-            }
+            self.compile_jump(idx, do_part);
+            self.resolve_jump(do_part);
         }
     }
 
     fn activate(&mut self) {
         let i = (self.s_stack().pop() - 1) as usize;
-        if i < NUM_TASKS {
+        if i < self.num_tasks() {
             // Wake task `i`.
             self.set_awake(i, true);
+            self.set_in_use(i, true);
             // Reset task `i` and Assign the code following ACTIVATE to task `i`
             let current_task = self.current_task();
             let ip = self.state().instruction_pointer;
             self.set_current_task(i);
             self.reset();
             self.state().instruction_pointer = ip;
+            self.state().schedule = Schedule::default();
             self.set_current_task(current_task);
             // Return to caller.
             let ip = self.r_stack().pop() as usize;
@@ -1897,6 +3177,17 @@ pub trait Core: Sized {
 
     fn compile_token(&mut self) {
         let last_token = self.last_token().take().expect("token");
+        if let Some((slot, float)) = self.compiler().find_local(&last_token) {
+            let idx = if float {
+                self.references().idx__flocal_fetch
+            } else {
+                self.references().idx__local_fetch
+            };
+            self.compile_word(idx);
+            self.data_space().compile_usize(slot);
+            self.set_last_token(last_token);
+            return;
+        }
         match self.find(&last_token) {
             Some(found_index) => {
                 self.set_last_token(last_token);
@@ -2011,7 +3302,10 @@ pub trait Core: Sized {
     /// Store token in counted string at `c-addr`.`
     fn store_token(&mut self) {
         let c_addr = self.s_stack().pop() as usize;
-        if self.data_space().start() <= c_addr {
+        let len = self.last_token().as_ref().map_or(0, |t| t.len().min(255));
+        if self.data_space().is_code(c_addr, c_addr + len + 1) {
+            self.abort_with(WRITE_TO_A_READ_ONLY_LOCATION);
+        } else if self.data_space().start() <= c_addr {
             match self.last_token().take() {
                 Some(mut t) => {
                     self.data_space().put_cstr(&t, c_addr);
@@ -2273,7 +3567,8 @@ pub trait Core: Sized {
         self.parse_word();
         let mut last_token = self.last_token().take().expect("last token");
         last_token.make_ascii_lowercase();
-        if let Some(_) = self.find(&last_token) {
+        let current = self.wordlist().current();
+        if let Some(_) = self.find_in(current, &last_token) {
             match self.output_buffer().as_mut() {
                 Some(buf) => {
                     write!(buf, "Redefining {}", last_token).expect("write");
@@ -2287,13 +3582,16 @@ pub trait Core: Sized {
         } else {
             let nfa = self.data_space().compile_str(&last_token);
             self.data_space().align();
-            let word = Word::new(action, compilation_semantics, nfa, self.data_space().here());
+            let dfa = self.data_space().here();
+            self.data_space().mark_code(nfa, dfa);
+            let word = Word::new(action, compilation_semantics, nfa, dfa);
             self.wordlist_mut().push(&last_token, word);
             self.set_last_token(last_token);
         }
     }
 
     fn colon(&mut self) {
+        self.compiler_mut().locals.clear();
         self.define(Core::nest, Core::compile_nest);
         if self.last_error().is_none() {
             let def = self.wordlist().last;
@@ -2314,7 +3612,12 @@ pub trait Core: Sized {
             compile(self, idx);
             let def = self.wordlist().last;
             self.wordlist_mut()[def].set_hidden(false);
+            // The compiled thread cannot be written any more.
+            let dfa = self.wordlist()[def].dfa();
+            let here = self.data_space().here();
+            self.data_space().mark_code(dfa, here);
         }
+        self.compiler_mut().locals.clear();
         self.left_bracket();
     }
 
@@ -2336,15 +3639,24 @@ pub trait Core: Sized {
             let w = &self.wordlist()[wp];
             (w.nfa(), w.dfa())
         };
-        let x = unsafe { self.data_space().get_usize(dfa) };
-        self.wordlist_mut().last = x;
-        for i in 0..BUCKET_SIZE {
+        let last = unsafe { self.data_space().get_usize(dfa) };
+        dfa += mem::size_of::<usize>();
+        let wordlist_count = unsafe { self.data_space().get_usize(dfa) };
+        dfa += mem::size_of::<usize>();
+        let current = unsafe { self.data_space().get_usize(dfa) };
+        dfa += mem::size_of::<usize>();
+        let order_len = unsafe { self.data_space().get_usize(dfa) };
+        self.wordlist_mut().search_order_mut().clear();
+        for _ in 0..order_len {
             dfa += mem::size_of::<usize>();
-            let x = unsafe { self.data_space().get_usize(dfa) };
-            self.wordlist_mut().buckets[i] = x;
+            let wid = unsafe { self.data_space().get_usize(dfa) };
+            self.wordlist_mut().search_order_mut().push(wid);
         }
         self.data_space().truncate(nfa);
         self.wordlist_mut().truncate(wp);
+        self.wordlist_mut().truncate_wordlists(wordlist_count);
+        self.wordlist_mut().set_current(current);
+        self.wordlist_mut().last = last;
     }
 
     /// Example:
@@ -2352,18 +3664,25 @@ pub trait Core: Sized {
     /// marker -work
     ///
     /// DFA of -work
-    /// +------+--------+
-    /// | last | b0-b63 |
-    /// +------+--------+
+    /// +------+------------+---------+--------+-----------------+
+    /// | last | #wordlists | current | #order | wid1 ... widn   |
+    /// +------+------------+---------+--------+-----------------+
     /// ```
     fn marker(&mut self) {
-        let x = self.wordlist().last;
-        self.wordlist_mut().temp_buckets = self.wordlist().buckets;
+        let last = self.wordlist().last;
+        let wordlist_count = self.wordlist().wordlist_count();
+        let current = self.wordlist().current();
         self.define(Core::unmark, Core::compile_unmark);
-        self.data_space().compile_usize(x);
-        for i in 0..BUCKET_SIZE {
-            let x = self.wordlist().temp_buckets[i];
-            self.data_space().compile_usize(x);
+        if self.last_error().is_none() {
+            self.data_space().compile_usize(last);
+            self.data_space().compile_usize(wordlist_count);
+            self.data_space().compile_usize(current);
+            let order_len = self.wordlist().search_order().len();
+            self.data_space().compile_usize(order_len);
+            for i in 0..order_len {
+                let wid = self.wordlist().search_order()[i];
+                self.data_space().compile_usize(wid);
+            }
         }
     }
 
@@ -2399,6 +3718,12 @@ pub trait Core: Sized {
     ///   +---+----+
     /// ```
     fn does(&mut self) {
+        // Locals of the defining part end at `does>`.
+        if !self.compiler().locals.is_empty() {
+            let idx = self.references().idx__local_exit;
+            self.compile_word(idx);
+            self.compiler_mut().locals.clear();
+        }
         let idx = self.references().idx__does;
         self.s_stack().push(idx as isize);
         self.compile_comma();
@@ -2423,7 +3748,8 @@ pub trait Core: Sized {
     /// Run time behavior of does>.
     fn _does(&mut self) {
         let doer = self.state().instruction_pointer + mem::size_of::<isize>();
-        self.data_space().compile_usize(doer);
+        let offset = self.data_space().offset(doer);
+        self.data_space().compile_usize(offset);
         let def = self.wordlist().last;
         let word = &mut self.wordlist_mut()[def];
         word.action = Core::xdoes;
@@ -2662,6 +3988,141 @@ pub trait Core: Sized {
         self.s_stack().push(t.wrapping_neg());
     }
 
+    /// Run-time: ( u1 u2 -- flag )
+    ///
+    /// flag is true if and only if u1 is less than u2.
+    fn u_less_than(&mut self) {
+        let (u1, u2) = self.s_stack().pop2();
+        self.s_stack().push(if (u1 as usize) < (u2 as usize) {
+            TRUE
+        } else {
+            FALSE
+        });
+    }
+
+    /// Run-time: ( n -- d )
+    ///
+    /// Convert the number n to the double-cell number d with the same
+    /// numerical value.
+    fn s_to_d(&mut self) {
+        let n = self.s_stack().pop();
+        self.s_stack().push2(n, if n < 0 { -1 } else { 0 });
+    }
+
+    /// Run-time: ( n1 n2 -- d )
+    ///
+    /// d is the signed product of n1 times n2.
+    fn m_star(&mut self) {
+        let (n1, n2) = self.s_stack().pop2();
+        let (lo, hi) = from_double(n1 as i128 * n2 as i128);
+        self.s_stack().push2(lo, hi);
+    }
+
+    /// Run-time: ( u1 u2 -- ud )
+    ///
+    /// Multiply u1 by u2, giving the unsigned double-cell product ud.
+    fn um_star(&mut self) {
+        let (u1, u2) = self.s_stack().pop2();
+        let (lo, hi) = from_udouble(u1 as usize as u128 * u2 as usize as u128);
+        self.s_stack().push2(lo, hi);
+    }
+
+    /// Run-time: ( ud u1 -- u2 u3 )
+    ///
+    /// Divide ud by u1, giving the quotient u3 and the remainder u2.
+    ///
+    /// Abort with `RESULT_OUT_OF_RANGE` if the quotient does not fit in a
+    /// single cell.
+    fn um_slash_mod(&mut self) {
+        let (lo, hi, u1) = self.s_stack().pop3();
+        let ud = to_udouble(lo, hi);
+        let u1 = u1 as usize as u128;
+        match ud.checked_div(u1) {
+            None => self.abort_with(DIVISION_BY_ZERO),
+            Some(q) if q > usize::MAX as u128 => self.abort_with(RESULT_OUT_OF_RANGE),
+            Some(q) => {
+                self.s_stack()
+                    .push2((ud % u1) as usize as isize, q as usize as isize);
+            }
+        }
+    }
+
+    /// Divide `d` by `n`, giving the quotient and the remainder.
+    ///
+    /// The quotient is floored if `floored` is true, symmetric otherwise.
+    /// Abort with `DIVISION_BY_ZERO` or `RESULT_OUT_OF_RANGE` and return
+    /// `None` if the division fails or the quotient does not fit in a single
+    /// cell.
+    fn divide_double(&mut self, d: i128, n: isize, floored: bool) -> Option<(isize, isize)> {
+        if n == 0 {
+            self.abort_with(DIVISION_BY_ZERO);
+            return None;
+        }
+        let n = n as i128;
+        let (mut q, mut r) = match (d.checked_div(n), d.checked_rem(n)) {
+            (Some(q), Some(r)) => (q, r),
+            _ => {
+                self.abort_with(RESULT_OUT_OF_RANGE);
+                return None;
+            }
+        };
+        if floored && r != 0 && ((r < 0) != (n < 0)) {
+            q -= 1;
+            r += n;
+        }
+        if q < isize::MIN as i128 || q > isize::MAX as i128 {
+            self.abort_with(RESULT_OUT_OF_RANGE);
+            None
+        } else {
+            Some((r as isize, q as isize))
+        }
+    }
+
+    /// Run-time: ( d1 n1 -- n2 n3 )
+    ///
+    /// Divide d1 by n1, giving the symmetric quotient n3 and the remainder
+    /// n2.
+    fn sm_slash_rem(&mut self) {
+        let (lo, hi, n1) = self.s_stack().pop3();
+        if let Some((r, q)) = self.divide_double(to_double(lo, hi), n1, false) {
+            self.s_stack().push2(r, q);
+        }
+    }
+
+    /// Run-time: ( d1 n1 -- n2 n3 )
+    ///
+    /// Divide d1 by n1, giving the floored quotient n3 and the remainder n2.
+    fn fm_slash_mod(&mut self) {
+        let (lo, hi, n1) = self.s_stack().pop3();
+        if let Some((r, q)) = self.divide_double(to_double(lo, hi), n1, true) {
+            self.s_stack().push2(r, q);
+        }
+    }
+
+    /// Run-time: ( n1 n2 n3 -- n4 )
+    ///
+    /// Multiply n1 by n2 producing the intermediate double-cell result d.
+    /// Divide d by n3 giving the single-cell quotient n4. The division is
+    /// symmetric like `/`.
+    fn star_slash(&mut self) {
+        let (n1, n2, n3) = self.s_stack().pop3();
+        if let Some((_, q)) = self.divide_double(n1 as i128 * n2 as i128, n3, false) {
+            self.s_stack().push(q);
+        }
+    }
+
+    /// Run-time: ( n1 n2 n3 -- n4 n5 )
+    ///
+    /// Multiply n1 by n2 producing the intermediate double-cell result d.
+    /// Divide d by n3 producing the single-cell remainder n4 and the
+    /// single-cell quotient n5. The division is symmetric like `/mod`.
+    fn star_slash_mod(&mut self) {
+        let (n1, n2, n3) = self.s_stack().pop3();
+        if let Some((r, q)) = self.divide_double(n1 as i128 * n2 as i128, n3, false) {
+            self.s_stack().push2(r, q);
+        }
+    }
+
     fn zero_less(&mut self) {
         let t = self.s_stack().pop();
         self.s_stack().push(if t < 0 { TRUE } else { FALSE });
@@ -2768,6 +4229,16 @@ pub trait Core: Sized {
         self.r_stack().len = rlen;
     }
 
+    /// Compile `exit`, preceded by the teardown of the local frame if the
+    /// current definition has locals.
+    fn compile_exit(&mut self, word_index: usize) {
+        if !self.compiler().locals.is_empty() {
+            let idx = self.references().idx__local_exit;
+            self.compile_word(idx);
+        }
+        self.compile_word(word_index);
+    }
+
     /// Execution: ( -- )
     ///
     /// Set the instruction pointer to zero in order to terminate inner interpreter.
@@ -2791,15 +4262,23 @@ pub trait Core: Sized {
 
     /// Run-time: ( x a-addr -- )
     ///
-    /// Store `x` at `a-addr`.
+    /// Store `x` at `a-addr`. Word headers and compiled code cannot be
+    /// written.
     fn store(&mut self) {
         let (n, t) = self.s_stack().pop2();
         let t = t as usize;
-        if self.data_space().start() < t && t + mem::size_of::<isize>() <= self.data_space().limit()
+        if !(self.data_space().start() < t
+            && t + mem::size_of::<isize>() <= self.data_space().limit())
         {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+        } else if self.data_space().is_code(t, t + mem::size_of::<isize>()) {
+            self.abort_with(WRITE_TO_A_READ_ONLY_LOCATION);
+        } else if self.is_watched(t, mem::size_of::<isize>()) {
+            let old = unsafe { self.data_space().get_isize(t) };
             unsafe { self.data_space().put_isize(n as isize, t as usize) };
+            self.watch_triggered(t, WatchValue::Cell(old), WatchValue::Cell(n));
         } else {
-            self.abort_with(INVALID_MEMORY_ADDRESS);
+            unsafe { self.data_space().put_isize(n as isize, t as usize) };
         }
     }
 
@@ -2825,10 +4304,16 @@ pub trait Core: Sized {
     fn c_store(&mut self) {
         let (n, t) = self.s_stack().pop2();
         let t = t as usize;
-        if self.data_space().start() < t && t < self.data_space().limit() {
+        if !(self.data_space().start() < t && t < self.data_space().limit()) {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+        } else if self.data_space().is_code(t, t + 1) {
+            self.abort_with(WRITE_TO_A_READ_ONLY_LOCATION);
+        } else if self.is_watched(t, 1) {
+            let old = unsafe { self.data_space().get_u8(t) };
             unsafe { self.data_space().put_u8(n as u8, t as usize) };
+            self.watch_triggered(t, WatchValue::Char(old), WatchValue::Char(n as u8));
         } else {
-            self.abort_with(INVALID_MEMORY_ADDRESS);
+            unsafe { self.data_space().put_u8(n as u8, t as usize) };
         }
     }
 
@@ -2845,11 +4330,20 @@ pub trait Core: Sized {
             let u = u as usize;
             let addr1 = addr1 as usize;
             let addr2 = addr2 as usize;
-            if self.data_space().start() < addr1
+            if !(self.data_space().start() < addr1
                 && addr1 + u <= self.data_space().limit()
                 && self.data_space().start() < addr2
-                && addr2 + u <= self.data_space().limit()
+                && addr2 + u <= self.data_space().limit())
             {
+                self.abort_with(INVALID_MEMORY_ADDRESS);
+            } else if self.data_space().is_code(addr2, addr2 + u) {
+                self.abort_with(WRITE_TO_A_READ_ONLY_LOCATION);
+            } else {
+                let old = if self.is_watched(addr2, u) {
+                    Some(unsafe { self.data_space().buffer_from_raw_parts(addr2, u) }.to_vec())
+                } else {
+                    None
+                };
                 unsafe {
                     if addr1 < addr2 {
                         for p in (addr1..(addr1 + u))
@@ -2867,8 +4361,9 @@ pub trait Core: Sized {
                         }
                     }
                 }
-            } else {
-                self.abort_with(INVALID_MEMORY_ADDRESS);
+                if let Some(old) = old {
+                    self.move_triggered(addr2, old);
+                }
             }
         }
     }
@@ -2969,6 +4464,8 @@ pub trait Core: Sized {
     ///
     /// `addr` is the data-space pointer.
     fn here(&mut self) {
+        // `here` may be used as the destination of a jump.
+        self.peephole_barrier();
         let here = self.data_space().here() as isize;
         self.s_stack().push(here);
     }
@@ -2978,9 +4475,17 @@ pub trait Core: Sized {
     /// If `n` is greater than zero, reserve n address units of data space. If `n`
     /// is less than zero, release `|n|` address units of data space. If `n` is
     /// zero, leave the data-space pointer unchanged.
+    ///
+    /// Space holding word headers or compiled code cannot be released.
     fn allot(&mut self) {
         let v = self.s_stack().pop();
-        self.data_space().allot(v);
+        let here = self.data_space().here();
+        let there = (here as isize).wrapping_add(v) as usize;
+        if v < 0 && self.data_space().is_code(there, here) {
+            self.abort_with(WRITE_TO_A_READ_ONLY_LOCATION);
+        } else {
+            self.data_space().allot(v);
+        }
     }
 
     /// Run-time: ( addr -- a-addr )
@@ -3007,7 +4512,92 @@ pub trait Core: Sized {
     /// data-space pointer is not aligned prior to execution of `,`.
     fn comma(&mut self) {
         let v = self.s_stack().pop();
-        self.data_space().compile_isize(v as isize);
+        let here = self.data_space().here();
+        if self.is_watched(here, mem::size_of::<isize>()) {
+            let old = unsafe { self.data_space().get_isize(here) };
+            self.data_space().compile_isize(v as isize);
+            self.watch_triggered(here, WatchValue::Cell(old), WatchValue::Cell(v));
+        } else {
+            self.data_space().compile_isize(v as isize);
+        }
+    }
+
+    /// Does writing `len` bytes at `addr` touch a watched range?
+    #[inline(always)]
+    fn is_watched(&self, addr: usize, len: usize) -> bool {
+        let watchpoints = &self.instrumentation().watchpoints;
+        !watchpoints.is_empty()
+            && watchpoints
+                .iter()
+                .any(|&(start, end)| addr < end && start < addr + len)
+    }
+
+    /// Report the write of `new` over `old` at `addr` into a watched range,
+    /// then stop the current task if it is attached to the debugger, or
+    /// abort with `WATCHPOINT_HIT` otherwise.
+    #[inline(never)]
+    fn watch_triggered(&mut self, addr: usize, old: WatchValue, new: WatchValue) {
+        let ip = self.state().instruction_pointer;
+        let hit = WatchHit {
+            task: self.current_task(),
+            xt: self.state().word_pointer,
+            caller: self.wordlist().find_xt(ip),
+            addr,
+            old,
+            new,
+        };
+        let report = self.watch_report(&hit);
+        if let Some(buf) = self.output_buffer().as_mut() {
+            writeln!(buf, "{}", report).unwrap();
+        }
+        self.instrumentation_mut().watch_hit = Some(hit);
+        if self.state().debug.attached {
+            self.state().debug.step = Step::Into;
+        } else {
+            self.abort_with(WATCHPOINT_HIT);
+        }
+    }
+
+    /// Report the bytes at `addr` replacing `old` by `move` if they touch
+    /// a watched range. Only the bytes inside the watched ranges are
+    /// reported.
+    fn move_triggered(&mut self, addr: usize, old: Vec<u8>) {
+        let end = addr + old.len();
+        let (mut s, mut e) = (end, addr);
+        for &(start, stop) in &self.instrumentation().watchpoints {
+            if addr < stop && start < end {
+                s = s.min(start.max(addr));
+                e = e.max(stop.min(end));
+            }
+        }
+        let new = unsafe { self.data_space().buffer_from_raw_parts(s, e - s) }.to_vec();
+        let old = old[s - addr..e - addr].to_vec();
+        self.watch_triggered(s, WatchValue::Bytes(old), WatchValue::Bytes(new));
+    }
+
+    /// Description of the write `hit` into a watched range.
+    fn watch_report(&mut self, hit: &WatchHit) -> String {
+        let name = |vm: &mut Self, xt: usize| {
+            let nfa = vm.wordlist()[xt].nfa();
+            unsafe { vm.data_space().get_str(nfa) }.to_string()
+        };
+        let mut report = format!(
+            "Watchpoint at {:X} written by {}",
+            hit.addr,
+            name(self, hit.xt)
+        );
+        if let Some(caller) = hit.caller {
+            write!(report, " in {}", name(self, caller)).unwrap();
+        }
+        write!(
+            report,
+            " of task {}: {} -> {}",
+            hit.task + 1,
+            hit.old,
+            hit.new
+        )
+        .unwrap();
+        report
     }
 
     fn p_to_r(&mut self) {
@@ -3106,14 +4696,20 @@ pub trait Core: Sized {
     }
 
     /// Print error description. `.error ( -- )`
+    ///
+    /// Exceptions allocated by `exception` and `ABORT"` print their own
+    /// messages.
     fn dot_error(&mut self) {
         match self.last_error() {
-            Some(e) => match self.output_buffer().as_mut() {
-                Some(buf) => {
-                    write!(buf, "{}", exception::description(e)).expect("write");
+            Some(e) => {
+                let description = self.exceptions().description(e).to_string();
+                match self.output_buffer().as_mut() {
+                    Some(buf) => {
+                        write!(buf, "{}", description).expect("write");
+                    }
+                    None => {}
                 }
-                None => {}
-            },
+            }
             None => {}
         }
     }
@@ -3187,12 +4783,27 @@ pub trait Core: Sized {
         }
         self.state().aborted_word_pointer = 0;
         self.state().source_index = 0;
+        self.state().catch_frame = 0;
+        self.state().local_frame = 0;
+        self.compiler_mut().locals.clear();
         self.left_bracket();
         self.set_error(None);
     }
 
     /// Abort the inner loop with an exception, reset VM and clears stacks.
+    ///
+    /// If there is a `catch` frame, `e` is thrown to it instead. Otherwise
+    /// the trace, if any, is dumped to the output buffer first.
     fn abort_with(&mut self, e: Exception) {
+        if self.unwind(e) {
+            return;
+        }
+        if !self.instrumentation().trace.is_empty() {
+            let dump = self.trace_dump();
+            if let Some(buf) = self.output_buffer().as_mut() {
+                buf.push_str(&dump);
+            }
+        }
         self.clear_stacks();
         self.set_error(Some(e));
         let h = self.handler();
@@ -3205,16 +4816,191 @@ pub trait Core: Sized {
         self.abort_with(ABORT);
     }
 
+    /// Run-time: ( i*x xt -- j*x 0 | i*x n )
+    ///
+    /// Push an exception frame on the return stack and execute `xt`. If
+    /// `xt` returns normally, drop the frame and return 0. If an exception
+    /// `n` is thrown during the execution of `xt`, including errors such as
+    /// `STACK_UNDERFLOW` raised by the system, restore the depths of data,
+    /// floating point and return stacks and the input source to those at
+    /// `catch`, and return `n`. The local frame of the definition executing
+    /// `catch` is restored as well.
+    ///
+    /// ```text
+    /// Return stack
+    /// +--------+-------------+-------------+---------+---------+-----------+------------+
+    /// | caller | outer frame | local frame | s depth | f depth | source id | source idx |
+    /// +--------+-------------+-------------+---------+---------+-----------+------------+
+    ///                                                                                   ^
+    ///                                                                                   |
+    ///                                                                         catch frame
+    /// ```
+    fn catch(&mut self) {
+        let xt = self.s_stack().pop();
+        let ip = self.state().instruction_pointer as isize;
+        let outer = self.state().catch_frame as isize;
+        let local_frame = self.state().local_frame as isize;
+        let s_depth = self.s_stack().len() as isize;
+        let f_depth = self.f_stack().len() as isize;
+        let source_id = self.state().source_id;
+        let source_index = self.state().source_index as isize;
+        self.r_stack().push3(ip, outer, local_frame);
+        self.r_stack().push2(s_depth, f_depth);
+        self.r_stack().push2(source_id, source_index);
+        self.state().catch_frame = self.r_stack().len();
+        self.s_stack().push(xt);
+        let idx = self.references().idx__catch;
+        self.state().instruction_pointer = self.wordlist()[idx].dfa();
+    }
+
+    /// Run-time: ( -- 0 )
+    ///
+    /// Drop the innermost exception frame when the word executed by `catch`
+    /// returns normally. Stack errors of the word are thrown to the frame.
+    fn end_catch(&mut self) {
+        let frame = self.state().catch_frame;
+        self.check_stacks();
+        // The frame is gone if a stack error was thrown to it.
+        if self.state().catch_frame == frame {
+            self.r_stack().len = frame;
+            let _ = self.r_stack().pop2();
+            let _ = self.r_stack().pop2();
+            // The caller is left for `exit`.
+            let (outer, _) = self.r_stack().pop2();
+            self.state().catch_frame = outer as u8;
+            self.s_stack().push(0);
+        }
+    }
+
+    /// Run-time: ( k*x n -- k*x | i*x n )
+    ///
+    /// If `n` is not zero, throw exception `n` to the innermost exception
+    /// frame. Without such a frame, abort with `n`.
+    fn throw(&mut self) {
+        let n = self.s_stack().pop();
+        if n != 0 {
+            self.abort_with(Exception::from(n));
+        }
+    }
+
+    /// Run-time: ( c-addr u -- n )
+    ///
+    /// Allocate a new exception code `n` described by the string `c-addr u`.
+    /// `.error` prints the description when `n` is thrown and not caught.
+    ///
+    /// Example: `: spindle s" Spindle not at speed" ;  spindle exception constant spindle-error`
+    fn p_exception(&mut self) {
+        let (caddr, u) = self.s_stack().pop2();
+        let limit = self.data_space().limit();
+        if u >= 0
+            && self.data_space().start() <= caddr as usize
+            && u as usize <= limit.saturating_sub(caddr as usize)
+        {
+            let (caddr, u) = (caddr as usize, u as usize);
+            let message = unsafe { self.data_space().str_from_raw_parts(caddr, u) }.to_string();
+            let e = self.exceptions_mut().allocate(&message);
+            self.s_stack().push(e.into());
+        } else {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+        }
+    }
+
+    /// Run-time of `ABORT"`: ( i*x x1 c-addr u -- | i*x )
+    ///
+    /// If any bit of `x1` is not zero, throw `ABORT_QUOTE` with the message
+    /// `c-addr u`.
+    fn p_abort_quote(&mut self) {
+        let (x1, caddr, u) = self.s_stack().pop3();
+        if x1 != 0 {
+            let message = unsafe {
+                self.data_space()
+                    .str_from_raw_parts(caddr as usize, u as usize)
+            }
+            .to_string();
+            self.exceptions_mut().set_abort_message(&message);
+            self.abort_with(ABORT_QUOTE);
+        }
+    }
+
+    /// Throw exception `e` to the innermost exception frame.
+    ///
+    /// Return false if there is no exception frame.
+    fn unwind(&mut self, e: Exception) -> bool {
+        let frame = self.state().catch_frame;
+        if frame == 0 || frame > self.r_stack().len() {
+            return false;
+        }
+        self.r_stack().len = frame;
+        let (source_id, source_index) = self.r_stack().pop2();
+        let (s_depth, f_depth) = self.r_stack().pop2();
+        let (ip, outer, local_frame) = self.r_stack().pop3();
+        self.state().catch_frame = outer as u8;
+        self.state().local_frame = local_frame as u8;
+        self.s_stack().restore(s_depth as u8);
+        self.f_stack().restore(f_depth as u8);
+        self.c_stack().reset();
+        self.state().source_id = source_id;
+        self.state().source_index = source_index as usize;
+        self.state().instruction_pointer = ip as usize;
+        self.s_stack().push(e.into());
+        self.set_error(None);
+        true
+    }
+
     /// Pause the current task and resume the next task which is awake.
+    ///
+    /// Periodic tasks waiting for their next period are woken when the
+    /// period is released. Among the tasks which are awake, the one with
+    /// the highest priority is resumed, round-robin among equal priorities.
+    /// If none is awake, the current task stays, but the inner interpreter
+    /// does not run it until it is woken.
     fn pause(&mut self) {
-        let mut i = self.current_task();
-        loop {
-            i = (i + 1) % NUM_TASKS;
+        let n = self.num_tasks();
+        let current_task = self.current_task();
+        let mut now = None;
+        let mut next = current_task;
+        let mut highest = None;
+        let mut i = current_task;
+        for _ in 0..n {
+            i = (i + 1) % n;
+            self.set_current_task(i);
+            if self.state().schedule.waiting {
+                let t = match now {
+                    Some(t) => t,
+                    None => self.system_time_ns(),
+                };
+                now = Some(t);
+                if t >= self.state().schedule.release_ns {
+                    self.state().schedule.waiting = false;
+                    self.state().schedule.released = true;
+                    self.set_awake(i, true);
+                }
+            }
             if self.awake(i) {
-                self.set_current_task(i);
-                break;
+                let priority = self.state().schedule.priority;
+                let higher = match highest {
+                    Some(h) => priority > h,
+                    None => true,
+                };
+                if higher {
+                    highest = Some(priority);
+                    next = i;
+                }
             }
         }
+        self.set_current_task(next);
+        if !self.awake(next) {
+            // No task is awake, the current one must not run on until
+            // `step` finds one released.
+            self.state().hooks = true;
+        }
+        if self.state().schedule.released {
+            let t = match now {
+                Some(t) => t,
+                None => self.system_time_ns(),
+            };
+            self.state().schedule.start(t);
+        }
     }
 
     /// Current task ID
@@ -3226,8 +5012,13 @@ pub trait Core: Sized {
     /// Suspend task `i`. `suspend ( i -- )`
     fn suspend(&mut self) {
         let i = (self.s_stack().pop() - 1) as usize;
-        if i < NUM_TASKS {
+        if i < self.num_tasks() {
             self.set_awake(i as usize, false);
+            // Do not release a suspended periodic task.
+            let current_task = self.current_task();
+            self.set_current_task(i);
+            self.state().schedule.waiting = false;
+            self.set_current_task(current_task);
         } else {
             self.abort_with(INVALID_NUMERIC_ARGUMENT);
         }
@@ -3236,26 +5027,117 @@ pub trait Core: Sized {
     /// Resume task `i`. `resume ( i -- )`
     fn resume(&mut self) {
         let i = (self.s_stack().pop() - 1) as usize;
-        if i < NUM_TASKS {
+        if i < self.num_tasks() {
             self.set_awake(i as usize, true);
         } else {
             self.abort_with(INVALID_NUMERIC_ARGUMENT);
         }
     }
+
+    /// Start a task on execution token `xt` in a free task slot.
+    ///
+    /// The task gets fresh stacks with `xt` on its data stack, and runs
+    /// `xt` the next time it gets the VM. When `xt` returns, the task
+    /// terminates and its slot is reclaimed. Returns the index of the task,
+    /// or `None` if all slots are in use.
+    fn spawn(&mut self, xt: usize) -> Option<usize> {
+        let i = (1..self.num_tasks()).find(|&i| !self.in_use(i))?;
+        let current_task = self.current_task();
+        let idx = self.references().idx__task;
+        let ip = self.wordlist()[idx].dfa();
+        self.set_current_task(i);
+        self.clear_stacks();
+        self.reset();
+        self.s_stack().push(xt as isize);
+        self.state().instruction_pointer = ip;
+        self.state().schedule = Schedule::default();
+        self.set_current_task(current_task);
+        self.set_in_use(i, true);
+        self.set_awake(i, true);
+        Some(i)
+    }
+
+    /// Terminate task `i` and reclaim its slot.
+    ///
+    /// The operator task 0 cannot be killed. If task `i` is the current
+    /// task, the next task which is awake is resumed.
+    fn kill(&mut self, i: usize) {
+        if i == 0 || i >= self.num_tasks() {
+            return;
+        }
+        self.set_awake(i, false);
+        self.set_in_use(i, false);
+        let current_task = self.current_task();
+        self.set_current_task(i);
+        self.clear_stacks();
+        self.r_stack().reset();
+        self.state().instruction_pointer = 0;
+        self.state().catch_frame = 0;
+        self.state().local_frame = 0;
+        self.state().schedule = Schedule::default();
+        self.state().debug = DebugState::default();
+        self.state().profile = ProfileState::default();
+        self.update_hooks();
+        if i == current_task {
+            self.pause();
+        } else {
+            self.set_current_task(current_task);
+        }
+    }
+
+    /// Run-time: ( xt -- n )
+    ///
+    /// Start a new task `n` on `xt`. `n` is zero if there is no free task
+    /// slot.
+    fn p_spawn(&mut self) {
+        let xt = self.s_stack().pop() as usize;
+        if xt < self.wordlist().len() {
+            let n = match self.spawn(xt) {
+                Some(i) => i as isize + 1,
+                None => 0,
+            };
+            self.s_stack().push(n);
+        } else {
+            self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+    }
+
+    /// Run-time: ( n -- )
+    ///
+    /// Terminate task `n` and reclaim its slot. `me kill` terminates the
+    /// current task.
+    fn p_kill(&mut self) {
+        let i = (self.s_stack().pop() - 1) as usize;
+        if 0 < i && i < self.num_tasks() {
+            self.kill(i);
+        } else {
+            self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Terminate the current task.
+    fn p_terminate(&mut self) {
+        let i = self.current_task();
+        self.kill(i);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     extern crate test;
-    use super::{Core, Memory};
+    use super::{Core, Memory, RunStatus};
     use exception::{
-        ABORT, CONTROL_STRUCTURE_MISMATCH, INTERPRETING_A_COMPILE_ONLY_WORD,
-        INVALID_MEMORY_ADDRESS, RETURN_STACK_UNDERFLOW, STACK_UNDERFLOW, UNDEFINED_WORD,
-        UNEXPECTED_END_OF_FILE, UNSUPPORTED_OPERATION,
+        Exception, ABORT, ABORT_QUOTE, CONTROL_STRUCTURE_MISMATCH, DIVISION_BY_ZERO,
+        INTERPRETING_A_COMPILE_ONLY_WORD, INVALID_MEMORY_ADDRESS, INVALID_NUMERIC_ARGUMENT,
+        RESULT_OUT_OF_RANGE, RETURN_STACK_UNDERFLOW, STACK_UNDERFLOW, UNDEFINED_WORD,
+        UNEXPECTED_END_OF_FILE, UNSUPPORTED_OPERATION, WRITE_TO_A_READ_ONLY_LOCATION,
     };
     use loader::HasLoader;
     use mock_vm::VM;
     use std::mem;
+    use NUM_TASKS;
 
     #[test]
     fn test_find() {
@@ -3773,6 +5655,80 @@ mod tests {
         assert_eq!(vm.s_stack().pop(), -30);
     }
 
+    #[test]
+    fn test_u_less_than() {
+        let vm = &mut VM::new();
+        vm.set_source("1 2 u<  -1 2 u<  2 -1 u<");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, 0, -1]);
+    }
+
+    #[test]
+    fn test_s_to_d() {
+        let vm = &mut VM::new();
+        vm.set_source("5 s>d  -5 s>d");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [5, 0, -5, -1]);
+    }
+
+    #[test]
+    fn test_m_star() {
+        let vm = &mut VM::new();
+        vm.set_source("max-n 2 m*  -3 4 m*  -1 -1 um*");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-2, 0, -12, -1, 1, -2]);
+    }
+
+    #[test]
+    fn test_um_slash_mod() {
+        let vm = &mut VM::new();
+        vm.set_source("-2 0 2 um/mod  7 1 -1 um/mod");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, isize::MAX, 8, 1]);
+        vm.s_stack().reset();
+        vm.set_source("0 1 1 um/mod");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(RESULT_OUT_OF_RANGE));
+        vm.reset();
+        vm.set_source("1 0 0 um/mod");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(DIVISION_BY_ZERO));
+    }
+
+    #[test]
+    fn test_sm_rem_fm_mod() {
+        let vm = &mut VM::new();
+        vm.set_source("-7 s>d 2 sm/rem  -7 s>d 2 fm/mod  7 s>d -2 fm/mod  7 s>d 2 fm/mod");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, -3, 1, -4, -1, -4, 1, 3]);
+        vm.s_stack().reset();
+        vm.set_source("0 1 1 sm/rem");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(RESULT_OUT_OF_RANGE));
+        vm.reset();
+        vm.set_source("1 0 0 fm/mod");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(DIVISION_BY_ZERO));
+    }
+
+    #[test]
+    fn test_star_slash() {
+        let vm = &mut VM::new();
+        vm.set_source("max-n 1000 2000 */  max-n 3 3 */mod  -7 1 2 */");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [isize::MAX / 2, 0, isize::MAX, -3]);
+        vm.s_stack().reset();
+        vm.set_source("max-n 4 2 */");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(RESULT_OUT_OF_RANGE));
+    }
+
     #[test]
     fn test_zero_less() {
         let vm = &mut VM::new();
@@ -4660,6 +6616,42 @@ mod tests {
         assert!(vm.last_error() != None);
     }
 
+    #[test]
+    fn test_position_independent_code() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : 2const   create , , does> 2@ ;
+            : pic
+                0labels
+                0  5 0 ?do  i 3 = if leave then  1+  loop
+                0  10 0 do  1+  2 +loop
+                0  begin 1+ dup 4 = until
+                0  begin dup 2 < while 1+ repeat
+                3  case 3 of s\" abc\" nip endof 0 swap endcase
+                [ 10 ] goto  99  [ 10 ] goto  [ 10 ] label
+                [ 20 ] call  exit  [ 20 ] label  7 ;
+            1 2 2const one-two
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        // Compiled code holds no address in data space.
+        let start = vm.data_space().start();
+        let limit = vm.data_space().limit();
+        let w = vm.find("2const").expect("2const");
+        let mut addr = vm.wordlist()[w].dfa();
+        while addr < vm.data_space().here() {
+            let v = unsafe { vm.data_space().get_usize(addr) };
+            assert!(v < start || limit < v);
+            addr += mem::size_of::<usize>();
+        }
+        vm.set_source("pic one-two");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [3, 5, 4, 2, 3, 7, 1, 2]);
+    }
+
     #[test]
     fn test_backslash() {
         let vm = &mut VM::new();
@@ -4684,6 +6676,35 @@ mod tests {
         assert_eq!(vm.wordlist().len(), wordlist_len);
     }
 
+    #[test]
+    fn test_code_is_read_only() {
+        let vm = &mut VM::new();
+        vm.set_source(": five   5 ;  variable v  : pair   [ 1 2 ] 2literal ;");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        for source in &[
+            "0 ' five >body !",
+            "0 ' five >body c!",
+            "0 ' dup >body 1- c!",
+            "v ' five >body 1 cells move",
+            "-1 cells allot",
+        ] {
+            vm.set_source(source);
+            vm.evaluate_input();
+            assert_eq!(vm.last_error(), Some(WRITE_TO_A_READ_ONLY_LOCATION));
+            vm.reset();
+            vm.clear_stacks();
+        }
+        vm.set_source("7 v !  v @  five  pair");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [7, 5, 1, 2]);
+        // Space of forgotten words is no longer code.
+        vm.set_source("marker -m  : six   6 ;  ' six >body  -m  0 swap !");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+    }
+
     #[test]
     fn test_abort() {
         let vm = &mut VM::new();
@@ -4693,6 +6714,88 @@ mod tests {
         assert_eq!(vm.s_stack().len(), 0);
     }
 
+    #[test]
+    fn test_catch_throw() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : t1   1 2 ;
+            : t2   9 99 throw 8 ;
+            : inner   1 >r 2 throw ;
+            : outer   ['] inner catch 10 + throw ;
+            ' t1 catch  5 ' t2 catch  ' outer catch  0 throw
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [1, 2, 0, 5, 99, 12]);
+        assert_eq!(vm.state().catch_frame, 0);
+        vm.s_stack().reset();
+        // The outer frame is restored after an inner catch returns normally.
+        vm.set_source(": t6   ['] t1 catch 2drop drop 3 throw ;  ' t6 catch");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [3]);
+        assert_eq!(vm.r_stack().len(), 0);
+        vm.s_stack().reset();
+        // Errors raised by the system are caught.
+        vm.set_source(
+            "
+            : t3   1 0 / ;
+            : t4   2drop drop ;
+            : t5   0.5e 1e -7 throw ;
+            7 ' t3 catch  ' t4 catch  2.5e ' t5 catch  ' abort catch
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [7, -10, -4, -7, -1]);
+        assert_eq!(vm.f_stack().as_slice(), [2.5]);
+        vm.s_stack().reset();
+        vm.f_stack().reset();
+        // Uncaught exception
+        vm.set_source("1 2 -300 throw 3");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(Exception::from(-300)));
+        assert_eq!(vm.s_stack().len(), 0);
+    }
+
+    #[test]
+    fn test_exception_and_abort_quote() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : spindle   s\" Spindle not at speed\" ;
+            spindle exception constant spindle-error
+            : t1   spindle-error throw ;
+            : t2 ( flag -- )   abort\" Door open\" ;
+            ' t1 catch  spindle-error =
+            0 ' t2 catch  -1 ' t2 catch
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, 0, -1, -2]);
+        let e = Exception::from(-4096);
+        assert_eq!(vm.exceptions().description(e), "Spindle not at speed");
+        assert_eq!(vm.exceptions().description(ABORT_QUOTE), "Door open");
+        vm.s_stack().reset();
+        // Uncaught
+        vm.set_source("t1");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(e));
+        vm.dot_error();
+        assert_eq!(vm.output_buffer().as_ref().unwrap(), "Spindle not at speed");
+    }
+
+    #[test]
+    fn test_exception_overflow() {
+        let vm = &mut VM::new();
+        vm.set_source("here 1+ -1 exception");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_MEMORY_ADDRESS));
+    }
+
     #[test]
     fn test_do_loop() {
         let vm = &mut VM::new();
@@ -4842,4 +6945,98 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_spawn_kill() {
+        let vm = &mut VM::new();
+        assert_eq!(vm.num_tasks(), NUM_TASKS);
+        vm.set_source(
+            "
+            variable counter
+            : up   begin 1 counter +! pause again ;
+            : watch ( n -- )   0 ?do pause loop ;
+            ' up spawn  5 watch  counter @
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [2, 5]);
+        assert!(vm.in_use(1));
+        // Killed task no longer runs and its slot is reclaimed.
+        vm.set_source("drop kill  3 watch  counter @  ' up spawn");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [5, 2]);
+        vm.s_stack().reset();
+        vm.kill(1);
+        assert!(!vm.in_use(1));
+        assert!(!vm.awake(1));
+        // A task terminates itself when its execution token returns.
+        vm.set_source(": once   1 counter +! ;  ' once spawn  2 watch  counter @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [2, 6]);
+        assert!(!vm.in_use(1));
+        // Operator task cannot be killed.
+        vm.set_source("1 kill");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_NUMERIC_ARGUMENT));
+    }
+
+    #[test]
+    fn test_with_tasks() {
+        let vm = &mut VM::with_tasks(2);
+        assert_eq!(vm.num_tasks(), 2);
+        vm.set_source(": nap   begin pause again ;  ' nap spawn  ' nap spawn");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [2, 0]);
+        assert_eq!(vm.spawn(0), None);
+        vm.kill(1);
+        assert_eq!(vm.spawn(0), Some(1));
+        vm.set_source("3 kill");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_NUMERIC_ARGUMENT));
+    }
+
+    #[test]
+    fn test_run_for() {
+        let vm = &mut VM::new();
+        vm.set_source(": main   3 0 do i loop ;  : oops   1 0 / ;");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.run_for(10, u64::MAX), RunStatus::Idle);
+        let main = vm.find("main").expect("main");
+        vm.execute_word(main);
+        let mut steps = 0;
+        while vm.run_for(2, u64::MAX) == RunStatus::Paused {
+            steps += 1;
+        }
+        assert!(steps > 1);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1, 2]);
+        vm.s_stack().reset();
+        // Deadline
+        vm.execute_word(main);
+        let now = vm.system_time_ns();
+        assert_eq!(vm.run_for(usize::MAX, now), RunStatus::Paused);
+        assert_eq!(vm.s_stack().len(), 0);
+        vm.advance();
+        assert_eq!(vm.run_for(usize::MAX, now + 1), RunStatus::Paused);
+        assert_eq!(vm.run_for(usize::MAX, u64::MAX), RunStatus::Idle);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1, 2]);
+        // Error
+        let oops = vm.find("oops").expect("oops");
+        vm.execute_word(oops);
+        assert_eq!(
+            vm.run_for(usize::MAX, u64::MAX),
+            RunStatus::Error(DIVISION_BY_ZERO)
+        );
+        assert_eq!(
+            vm.run_for(usize::MAX, u64::MAX),
+            RunStatus::Error(DIVISION_BY_ZERO)
+        );
+        vm.reset();
+        vm.state().instruction_pointer = 0;
+        assert_eq!(vm.run_for(usize::MAX, u64::MAX), RunStatus::Idle);
+    }
 }
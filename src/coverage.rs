@@ -0,0 +1,305 @@
+//! Code coverage
+//!
+//! While measuring coverage, `run` marks every cell of the compiled threads
+//! it executes, and for each `0branch`, whether it jumped to its
+//! destination or fell through. Colon definitions compiled while measuring
+//! coverage record the source lines they come from, so the parts never
+//! executed are reported by source line.
+//!
+//! ```text
+//! coverage-on  include tests.fs  .coverage
+//! ```
+
+use core::{Core, CoverageMap};
+use memory::Memory;
+use std::fmt::Write;
+use std::mem;
+use tools::{Instruction, Tools};
+
+/// Part of a colon definition not covered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Gap {
+    /// Instruction never executed.
+    NotExecuted(Instruction),
+    /// `0branch` executed but never jumping to its destination.
+    NotTaken(Instruction),
+    /// `0branch` executed but never falling through.
+    NotFallen(Instruction),
+}
+
+impl Gap {
+    pub fn instruction(&self) -> &Instruction {
+        match *self {
+            Gap::NotExecuted(ref ins) | Gap::NotTaken(ref ins) | Gap::NotFallen(ref ins) => ins,
+        }
+    }
+}
+
+/// Coverage of a colon definition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordCoverage {
+    pub xt: usize,
+    /// Number of instructions executed.
+    pub executed: usize,
+    /// Number of instructions in the thread.
+    pub instructions: usize,
+    /// Parts not covered, in the order of their addresses.
+    pub gaps: Vec<Gap>,
+}
+
+pub trait Coverage: Tools {
+    /// Add code coverage primitives.
+    fn add_coverage(&mut self) {
+        self.add_primitive("coverage-on", Coverage::coverage_on);
+        self.add_primitive("coverage-off", Coverage::coverage_off);
+        self.add_primitive("0coverage", Coverage::clear_coverage);
+        self.add_primitive(".coverage", Coverage::dot_coverage);
+    }
+
+    /// Start or stop measuring coverage of the words compiled from now on.
+    /// The coverage measured so far is dropped.
+    fn set_coverage(&mut self, enabled: bool) {
+        self.instrumentation_mut().coverage = if enabled {
+            let cells = self.data_space().capacity() / mem::size_of::<usize>();
+            let here = self.data_space().here();
+            let start = self.data_space().offset(here);
+            CoverageMap::new(cells, start)
+        } else {
+            CoverageMap::default()
+        };
+        self.update_hooks();
+    }
+
+    /// Coverage of the colon definitions compiled while measuring coverage,
+    /// in the order of their definitions.
+    fn coverage(&mut self) -> Vec<WordCoverage> {
+        let nest: fn(&mut Self) = Core::nest;
+        let start = self.instrumentation().coverage.start();
+        let mut dfas: Vec<(usize, usize)> = Vec::new();
+        for xt in 1..self.wordlist().len() {
+            let action = self.wordlist()[xt].action() as usize;
+            let dfa = self.wordlist()[xt].dfa();
+            if action == nest as usize && self.data_space().offset(dfa) >= start {
+                dfas.push((dfa, xt));
+            }
+        }
+        dfas.sort();
+        let idx_zero_branch = self.references().idx_zero_branch;
+        let mut result = Vec::new();
+        for (dfa, xt) in dfas {
+            let instructions = match self.decode_thread(dfa) {
+                Ok(instructions) | Err(instructions) => instructions,
+            };
+            let mut executed = 0;
+            let mut gaps = Vec::new();
+            for ins in instructions.iter() {
+                let offset = self.data_space().offset(ins.addr);
+                let marks = self.instrumentation().coverage.marks(offset);
+                if marks & CoverageMap::EXECUTED == 0 {
+                    gaps.push(Gap::NotExecuted(ins.clone()));
+                    continue;
+                }
+                executed += 1;
+                let fused = self.fused_words(ins.xt).map(|(_, second)| second);
+                if ins.xt == idx_zero_branch || fused == Some(idx_zero_branch) {
+                    if marks & CoverageMap::TAKEN == 0 {
+                        gaps.push(Gap::NotTaken(ins.clone()));
+                    } else if marks & CoverageMap::FALLEN == 0 {
+                        gaps.push(Gap::NotFallen(ins.clone()));
+                    }
+                }
+            }
+            result.push(WordCoverage {
+                xt,
+                executed,
+                instructions: instructions.len(),
+                gaps,
+            });
+        }
+        result
+    }
+
+    /// Source path and line followed by `:`, or the address if the
+    /// instruction at `addr` was not compiled from a source.
+    fn coverage_location(&mut self, addr: usize) -> String {
+        let offset = self.data_space().offset(addr);
+        match self.instrumentation().coverage.line(offset) {
+            Some((path, line)) => format!("{}:{}:", path, line),
+            None => format!("{:X}:", addr),
+        }
+    }
+
+    /// Coverage report, with for each word the number of instructions
+    /// executed out of those in the word, followed by the parts not
+    /// covered. Consecutive instructions never executed from the same
+    /// source line are reported on one line.
+    fn coverage_report(&mut self) -> String {
+        let mut report = String::new();
+        for c in self.coverage() {
+            let name = self.word_name(c.xt);
+            let name = if name.is_empty() { ":noname" } else { &name };
+            writeln!(report, "{} {}/{}", name, c.executed, c.instructions).unwrap();
+            let mut pending: Option<(String, String, usize)> = None;
+            for gap in &c.gaps {
+                let ins = gap.instruction();
+                let location = self.coverage_location(ins.addr);
+                let text = self.instruction_text(ins);
+                if let Gap::NotExecuted(_) = *gap {
+                    if let Some((ref l, ref mut t, ref mut next)) = pending {
+                        if *l == location && *next == ins.addr {
+                            write!(t, " {}", text).unwrap();
+                            *next = ins.next;
+                            continue;
+                        }
+                    }
+                    if let Some((l, t, _)) = pending.take() {
+                        writeln!(report, "  {} never executed: {}", l, t).unwrap();
+                    }
+                    pending = Some((location, text, ins.next));
+                    continue;
+                }
+                if let Some((l, t, _)) = pending.take() {
+                    writeln!(report, "  {} never executed: {}", l, t).unwrap();
+                }
+                let what = match *gap {
+                    Gap::NotTaken(_) => "never taken",
+                    _ => "never falls through",
+                };
+                writeln!(report, "  {} {} {}", location, text, what).unwrap();
+            }
+            if let Some((l, t, _)) = pending.take() {
+                writeln!(report, "  {} never executed: {}", l, t).unwrap();
+            }
+        }
+        report
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Start measuring coverage of the words compiled from now on.
+    fn coverage_on(&mut self) {
+        self.set_coverage(true);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Stop measuring coverage.
+    fn coverage_off(&mut self) {
+        self.set_coverage(false);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Clear the coverage measured, keeping the words to measure.
+    fn clear_coverage(&mut self) {
+        self.instrumentation_mut().coverage.clear();
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Display the coverage of the words compiled since `coverage-on`: for
+    /// each word, the number of instructions executed out of those in the
+    /// word, followed by the instructions never executed, and the `0branch`
+    /// never taken or never falling through, with their source lines.
+    ///
+    /// Example:
+    ///
+    /// ```text
+    /// sign 5/8
+    ///   tests.fs:3: 0branch 7F3A2C4E1D60 never falls through
+    ///   tests.fs:3: never executed: lit -1 exit
+    /// ```
+    fn dot_coverage(&mut self) {
+        let report = self.coverage_report();
+        if let Some(buf) = self.output_buffer().as_mut() {
+            buf.push_str(&report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Coverage, Gap};
+    use core::Core;
+    use mock_vm::VM;
+    use std::env;
+    use std::fs;
+    use tools::Tools;
+
+    #[test]
+    fn test_coverage() {
+        let path = env::temp_dir().join(format!("rtf-test-{}.fs", std::process::id()));
+        let path = path.to_str().expect("path");
+        fs::write(
+            path,
+            ": sign ( n -- -1|0|1 )\n\
+             \x20   dup 0< if drop -1 exit then\n\
+             \x20   0> if 1 else 0 then ;\n",
+        )
+        .expect("source file");
+        let vm = &mut VM::new();
+        vm.set_source(&format!(
+            "
+            : path   s\" {}\" ;
+            coverage-on
+            path included
+            : twice   sign sign ;
+            5 twice
+            ",
+            path
+        ));
+        vm.evaluate_input();
+        fs::remove_file(path).expect("remove");
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [1]);
+        // Words compiled before `coverage-on` are not measured.
+        let coverage = vm.coverage();
+        let names: Vec<String> = coverage.iter().map(|c| vm.word_name(c.xt)).collect();
+        assert_eq!(names, ["sign", "twice"]);
+        let (sign, twice) = (&coverage[0], &coverage[1]);
+        assert_eq!((sign.executed, sign.instructions), (8, 12));
+        assert_eq!((twice.executed, twice.instructions), (3, 3));
+        assert!(twice.gaps.is_empty());
+        let gaps: Vec<(&str, String)> = sign
+            .gaps
+            .iter()
+            .map(|g| {
+                let kind = match *g {
+                    Gap::NotExecuted(_) => "executed",
+                    Gap::NotTaken(_) => "taken",
+                    Gap::NotFallen(_) => "fallen",
+                };
+                (kind, vm.word_name(g.instruction().xt))
+            })
+            .collect();
+        assert_eq!(
+            gaps,
+            [
+                ("fallen", "0branch".to_string()),
+                ("executed", "drop".to_string()),
+                ("executed", "lit".to_string()),
+                ("executed", "exit".to_string()),
+                ("taken", "0branch".to_string()),
+                ("executed", "lit".to_string()),
+            ]
+        );
+        let report = vm.coverage_report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "sign 8/12");
+        assert!(lines[1].starts_with(&format!("  {}:2: 0branch ", path)));
+        assert!(lines[1].ends_with(" never falls through"));
+        assert_eq!(
+            lines[2],
+            format!("  {}:2: never executed: drop lit -1 exit", path)
+        );
+        assert!(lines[3].starts_with(&format!("  {}:3: 0branch ", path)));
+        assert!(lines[3].ends_with(" never taken"));
+        assert_eq!(lines[4], format!("  {}:3: never executed: lit 0", path));
+        assert_eq!(lines[5], "twice 3/3");
+        vm.set_source("0coverage");
+        vm.evaluate_input();
+        assert_eq!(vm.coverage()[0].executed, 0);
+        assert_eq!(vm.coverage()[0].gaps.len(), 12);
+    }
+}
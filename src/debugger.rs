@@ -0,0 +1,624 @@
+//! Single-step debugger
+//!
+//! A task attached to the debugger stops at breakpoints and after steps.
+//! A stopped task is suspended, the other tasks keep being scheduled while
+//! the operator inspects it and resumes it.
+//!
+//! Writes by `!`, `c!`, `move`, `f!` and `,` into watched ranges of data
+//! space stop the task writing if it is attached to the debugger, or abort
+//! it with `WATCHPOINT_HIT` otherwise.
+//!
+//! ```text
+//! 2 debug           \ Attach task 2 and stop it before its next token.
+//! ' foo break       \ Stop task 2 before it executes `foo`.
+//! cont              \ Run task 2 until it stops again, or for at most
+//!                   \ `debug-timeout` milli-seconds.
+//! step step-over step-out
+//! undebug           \ Detach task 2 and let it run.
+//! counter 1 cells watch
+//! ```
+
+use core::{Breakpoint, Core, DebugState, Step, WatchHit, MAX_WATCHPOINTS};
+use exception::{Exception, INVALID_NUMERIC_ARGUMENT, UNSUPPORTED_OPERATION};
+use memory::Memory;
+use std::fmt::Write;
+use tools::Tools;
+use {FALSE, TRUE};
+
+pub trait Debugger: Tools {
+    /// Add debugger primitives.
+    fn add_debugger(&mut self) {
+        self.add_primitive("(debug)", Debugger::p_debug);
+        self.add_primitive("undebug", Debugger::undebug);
+        self.add_primitive("break", Debugger::p_break);
+        self.add_primitive("break-at", Debugger::break_at);
+        self.add_primitive("unbreak", Debugger::unbreak);
+        self.add_primitive("unbreak-at", Debugger::unbreak_at);
+        self.add_primitive("(resume-debug)", Debugger::p_resume_debug);
+        self.add_primitive("debug-running?", Debugger::debug_running);
+        self.add_primitive(".debug", Debugger::dot_debug);
+        self.add_primitive("watch", Debugger::p_watch);
+        self.add_primitive("unwatch", Debugger::unwatch);
+        self.add_primitive(".watch", Debugger::dot_watch);
+    }
+
+    /// Call `f` with task `i` as the current task, `None` if there is no
+    /// task `i`.
+    fn with_task<R, F: FnOnce(&mut Self) -> R>(&mut self, i: usize, f: F) -> Option<R> {
+        if i < self.num_tasks() {
+            let current_task = self.current_task();
+            self.set_current_task(i);
+            let result = f(self);
+            self.set_current_task(current_task);
+            Some(result)
+        } else {
+            None
+        }
+    }
+
+    /// Attach the debugger to task `i` and stop it before its next token.
+    ///
+    /// No operation if there is no task `i`.
+    fn attach(&mut self, i: usize) {
+        self.with_task(i, |vm| {
+            let debug = &mut vm.state().debug;
+            debug.attached = true;
+            if !debug.stopped {
+                debug.step = Step::Into;
+            }
+        });
+        self.update_hooks();
+    }
+
+    /// Detach the debugger from task `i`, clearing its breakpoints. A
+    /// stopped task runs on.
+    ///
+    /// No operation if there is no task `i`.
+    fn detach(&mut self, i: usize) {
+        let stopped = self.with_task(i, |vm| {
+            let target = vm.state().debug.target;
+            let stopped = vm.state().debug.stopped;
+            vm.state().debug = DebugState {
+                target,
+                ..DebugState::default()
+            };
+            stopped
+        });
+        self.update_hooks();
+        if stopped == Some(true) {
+            self.set_awake(i, true);
+        }
+    }
+
+    /// Stop task `i` at breakpoint `b`.
+    ///
+    /// No operation if there is no task `i`.
+    fn set_breakpoint(&mut self, i: usize, b: Breakpoint) {
+        self.with_task(i, |vm| {
+            let breakpoints = &mut vm.state().debug.breakpoints;
+            if !breakpoints.contains(&b) {
+                breakpoints.push(b);
+            }
+        });
+    }
+
+    /// Remove breakpoint `b` of task `i`.
+    ///
+    /// No operation if there is no task `i`.
+    fn clear_breakpoint(&mut self, i: usize, b: Breakpoint) {
+        self.with_task(i, |vm| vm.state().debug.breakpoints.retain(|&x| x != b));
+    }
+
+    /// Is task `i` stopped by the debugger?
+    fn is_stopped(&mut self, i: usize) -> bool {
+        self.with_task(i, |vm| vm.state().debug.stopped) == Some(true)
+    }
+
+    /// Is task `i` running on, neither stopped by the debugger, nor
+    /// suspended, nor terminated?
+    ///
+    /// A periodic task waiting for its next period is running.
+    fn is_debug_running(&mut self, i: usize) -> bool {
+        if !self.in_use(i) {
+            return false;
+        }
+        let awake = self.awake(i);
+        self.with_task(i, |vm| {
+            !vm.state().debug.stopped && (awake || vm.state().schedule.waiting)
+        }) == Some(true)
+    }
+
+    /// Resume task `i` to run until `step` or a breakpoint stops it. If
+    /// the task is running, change where it stops.
+    ///
+    /// No operation if there is no task `i`.
+    fn resume_with(&mut self, i: usize, step: Step) {
+        let stopped = self.with_task(i, |vm| {
+            let debug = &mut vm.state().debug;
+            let stopped = debug.stopped;
+            debug.stopped = false;
+            debug.resumed = stopped;
+            debug.step = step;
+            stopped
+        });
+        if stopped == Some(true) {
+            self.set_awake(i, true);
+        }
+    }
+
+    /// Resume task `i` and stop it before its next token, inside the
+    /// definition about to be called if any.
+    fn step_into(&mut self, i: usize) {
+        self.resume_with(i, Step::Into);
+    }
+
+    /// Resume task `i` and stop it before its next token in the current
+    /// definition, running the definition about to be called as a whole.
+    fn step_over(&mut self, i: usize) {
+        let step = self.with_task(i, |vm| {
+            let nest: fn(&mut Self) = Core::nest;
+            let xdoes: fn(&mut Self) = Core::xdoes;
+            let ip = vm.state().instruction_pointer;
+            let depth = vm.r_stack().len() as usize;
+            match vm.decode_instruction(ip) {
+                Some(ins) => {
+                    let action = vm.wordlist()[ins.xt].action() as usize;
+                    if action == nest as usize || action == xdoes as usize {
+                        Step::Over(depth)
+                    } else {
+                        Step::Into
+                    }
+                }
+                None => Step::Into,
+            }
+        });
+        if let Some(step) = step {
+            self.resume_with(i, step);
+        }
+    }
+
+    /// Resume task `i` and stop it when its return stack gets shallower,
+    /// that is when the current definition returns or leaves a do-loop.
+    fn step_out(&mut self, i: usize) {
+        if let Some(depth) = self.with_task(i, |vm| vm.r_stack().len() as usize) {
+            self.resume_with(i, Step::Out(depth));
+        }
+    }
+
+    /// Resume task `i` to run until a breakpoint.
+    fn continue_debug(&mut self, i: usize) {
+        self.resume_with(i, Step::Run);
+    }
+
+    /// Status of task `i`. For a stopped task, the address and the
+    /// definition it stopped in, the token it executes next, and its data,
+    /// floating-point and return stacks.
+    fn debug_report(&mut self, i: usize) -> String {
+        let mut report = String::new();
+        if i >= self.num_tasks() {
+            return report;
+        }
+        write!(report, "task {} ", i + 1).unwrap();
+        if !self.in_use(i) && i != 0 {
+            write!(report, "is free").unwrap();
+        } else if !self.is_stopped(i) {
+            write!(report, "is running").unwrap();
+        } else {
+            let current_task = self.current_task();
+            self.set_current_task(i);
+            let ip = self.state().instruction_pointer;
+            let word = match self.wordlist().find_xt(ip) {
+                Some(xt) => self.word_name(xt),
+                None => "unknown".to_string(),
+            };
+            let next = match self.decode_instruction(ip) {
+                Some(ins) => self.instruction_text(&ins),
+                None => "?".to_string(),
+            };
+            write!(report, "stopped at {:X} in {}: {}", ip, word, next).unwrap();
+            write!(report, "\nS: {:?}", self.s_stack()).unwrap();
+            write!(report, "F: {:?}", self.f_stack()).unwrap();
+            write!(report, "R: {:?}", self.r_stack()).unwrap();
+            self.set_current_task(current_task);
+        }
+        report
+    }
+
+    /// Watch writes into the `len` bytes at `addr`.
+    ///
+    /// Fail with `INVALID_NUMERIC_ARGUMENT` if the range is empty or not in
+    /// data space, or if there are already `MAX_WATCHPOINTS` watchpoints.
+    fn set_watchpoint(&mut self, addr: usize, len: usize) -> Result<(), Exception> {
+        let watchpoints = self.instrumentation().watchpoints.len();
+        if len == 0
+            || addr < self.data_space().start()
+            || addr > self.data_space().limit()
+            || len > self.data_space().limit() - addr
+            || watchpoints >= MAX_WATCHPOINTS
+        {
+            return Err(INVALID_NUMERIC_ARGUMENT);
+        }
+        let range = (addr, addr + len);
+        if !self.instrumentation().watchpoints.contains(&range) {
+            self.instrumentation_mut().watchpoints.push(range);
+        }
+        Ok(())
+    }
+
+    /// Remove the watchpoints starting at `addr`.
+    fn clear_watchpoint(&mut self, addr: usize) {
+        self.instrumentation_mut()
+            .watchpoints
+            .retain(|&(start, _)| start != addr);
+    }
+
+    /// Last write into a watched range.
+    fn watch_hit(&self) -> Option<&WatchHit> {
+        self.instrumentation().watch_hit.as_ref()
+    }
+
+    /// Task debugged by the current task, `None` if there is none.
+    fn debug_target(&mut self) -> Option<usize> {
+        match self.state().debug.target {
+            0 => None,
+            n => Some(n - 1),
+        }
+    }
+
+    /// Run-time: ( n -- )
+    ///
+    /// Make task `n` the task debugged by the current task, attach the
+    /// debugger to it and stop it before its next token. The current task
+    /// cannot debug itself.
+    fn p_debug(&mut self) {
+        let n = self.s_stack().pop();
+        if n < 1 || n as usize > self.num_tasks() || n as usize - 1 == self.current_task() {
+            return self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+        if let Some(i) = self.debug_target() {
+            if i != n as usize - 1 {
+                self.detach(i);
+            }
+        }
+        self.state().debug.target = n as usize;
+        self.attach(n as usize - 1);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Detach the debugger from the debugged task and clear its
+    /// breakpoints. A stopped task runs on.
+    fn undebug(&mut self) {
+        if let Some(i) = self.debug_target() {
+            self.detach(i);
+            self.state().debug.target = 0;
+        }
+    }
+
+    /// Breakpoint `b` for the debugged task, set if `set` or cleared
+    /// otherwise.
+    fn update_breakpoint(&mut self, b: Breakpoint, set: bool) {
+        match self.debug_target() {
+            Some(i) => {
+                if set {
+                    self.set_breakpoint(i, b);
+                } else {
+                    self.clear_breakpoint(i, b);
+                }
+            }
+            None => self.abort_with(UNSUPPORTED_OPERATION),
+        }
+    }
+
+    /// Run-time: ( xt -- )
+    ///
+    /// Stop the debugged task before it executes `xt`.
+    fn p_break(&mut self) {
+        let xt = self.s_stack().pop();
+        if xt < 1 || xt as usize >= self.wordlist().len() {
+            return self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+        self.update_breakpoint(Breakpoint::Word(xt as usize), true);
+    }
+
+    /// Run-time: ( addr -- )
+    ///
+    /// Stop the debugged task before it executes the token at `addr`.
+    fn break_at(&mut self) {
+        let addr = self.s_stack().pop();
+        self.update_breakpoint(Breakpoint::Address(addr as usize), true);
+    }
+
+    /// Run-time: ( xt -- )
+    ///
+    /// Remove the breakpoint on `xt` of the debugged task.
+    fn unbreak(&mut self) {
+        let xt = self.s_stack().pop();
+        self.update_breakpoint(Breakpoint::Word(xt as usize), false);
+    }
+
+    /// Run-time: ( addr -- )
+    ///
+    /// Remove the breakpoint at `addr` of the debugged task.
+    fn unbreak_at(&mut self) {
+        let addr = self.s_stack().pop();
+        self.update_breakpoint(Breakpoint::Address(addr as usize), false);
+    }
+
+    /// Run-time: ( n -- )
+    ///
+    /// Resume the debugged task, 0: until a breakpoint, 1: step into,
+    /// 2: step over, 3: step out.
+    fn p_resume_debug(&mut self) {
+        let n = self.s_stack().pop();
+        match self.debug_target() {
+            Some(i) => match n {
+                0 => self.continue_debug(i),
+                1 => self.step_into(i),
+                2 => self.step_over(i),
+                3 => self.step_out(i),
+                _ => self.abort_with(INVALID_NUMERIC_ARGUMENT),
+            },
+            None => self.abort_with(UNSUPPORTED_OPERATION),
+        }
+    }
+
+    /// Run-time: ( -- flag )
+    ///
+    /// Is the debugged task running on? False if there is no debugged
+    /// task.
+    fn debug_running(&mut self) {
+        let running = match self.debug_target() {
+            Some(i) => self.is_debug_running(i),
+            None => false,
+        };
+        self.s_stack().push(if running { TRUE } else { FALSE });
+    }
+
+    /// Run-time: ( addr u -- )
+    ///
+    /// Watch writes into the `u` address units at `addr`. A write by `!`,
+    /// `c!`, `move`, `f!` or `,` into the range is reported with the word
+    /// writing and the old and new values. The task writing then stops if
+    /// it is attached to the debugger, or is aborted with `WATCHPOINT_HIT`
+    /// otherwise.
+    fn p_watch(&mut self) {
+        let (addr, u) = self.s_stack().pop2();
+        if u < 0 {
+            return self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+        if let Err(e) = self.set_watchpoint(addr as usize, u as usize) {
+            self.abort_with(e);
+        }
+    }
+
+    /// Run-time: ( addr -- )
+    ///
+    /// Remove the watchpoints starting at `addr`.
+    fn unwatch(&mut self) {
+        let addr = self.s_stack().pop();
+        self.clear_watchpoint(addr as usize);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Display the watchpoints, one per line with the address and the
+    /// length, followed by the last write into a watched range.
+    fn dot_watch(&mut self) {
+        let mut report = String::new();
+        for &(start, end) in &self.instrumentation().watchpoints {
+            writeln!(report, "{:X} {}", start, end - start).unwrap();
+        }
+        if let Some(hit) = self.instrumentation().watch_hit.clone() {
+            let text = self.watch_report(&hit);
+            write!(report, "{}", text).unwrap();
+        }
+        if let Some(buf) = self.output_buffer().as_mut() {
+            write!(buf, "{}", report).unwrap();
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Display the status of the debugged task.
+    fn dot_debug(&mut self) {
+        match self.debug_target() {
+            Some(i) => {
+                let report = self.debug_report(i);
+                if let Some(buf) = self.output_buffer().as_mut() {
+                    write!(buf, "{}", report).unwrap();
+                }
+            }
+            None => self.abort_with(UNSUPPORTED_OPERATION),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use core::{Breakpoint, Core, RunStatus, WatchValue};
+    use exception::{INVALID_NUMERIC_ARGUMENT, UNSUPPORTED_OPERATION, WATCHPOINT_HIT};
+    use mock_vm::VM;
+
+    #[test]
+    fn test_step_and_break() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            variable a  variable b
+            : inc ( addr -- )   1 swap +! ;
+            : work   begin a inc pause again ;
+            : other   begin b inc pause again ;
+            : watch ( n -- )   0 ?do pause loop ;
+            ' work spawn drop  ' other spawn drop
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        // Break into task 2 and single-step it. Task 2 stops after `pause`
+        // and before the branch back to `begin`.
+        vm.set_source("3 watch  2 debug  a @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert!(vm.is_stopped(1));
+        assert!(vm.debug_report(1).starts_with("task 2 stopped at "));
+        let a = vm.s_stack().pop();
+        vm.set_source("step-over step-over step-over  a @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert!(vm.debug_report(1).contains(" in work: pause"));
+        assert_eq!(vm.s_stack().as_slice(), [a + 1]);
+        vm.s_stack().reset();
+        // Task 3 keeps being scheduled while task 2 is stopped.
+        vm.set_source("b @  5 watch  b @ -  a @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-5, a + 1]);
+        vm.s_stack().reset();
+        // Step into `inc` and out of it.
+        let inc = vm.find("inc").expect("inc");
+        vm.set_breakpoint(1, Breakpoint::Word(inc));
+        vm.set_source("cont step");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert!(vm.debug_report(1).contains(" in inc: lit 1"));
+        vm.set_source("step-out  a @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert!(vm.debug_report(1).contains(" in work: pause"));
+        assert_eq!(vm.s_stack().as_slice(), [a + 2]);
+        vm.s_stack().reset();
+        // Detached task 2 runs on.
+        vm.set_source("cont  ' inc unbreak  a @  undebug  3 watch  a @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert!(!vm.is_stopped(1));
+        assert_eq!(vm.s_stack().as_slice(), [a + 2, a + 5]);
+        // The operator gets back to the prompt if task 2 does not stop.
+        vm.set_source("2 debug");
+        vm.evaluate_input();
+        assert!(vm.is_stopped(1));
+        vm.set_output_buffer(String::new());
+        vm.set_source("0 debug-timeout !  cont");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.output_buffer().as_ref().unwrap(), "task 2 is running");
+        assert!(!vm.is_stopped(1));
+        vm.set_source("undebug");
+        vm.evaluate_input();
+        // Errors
+        vm.s_stack().reset();
+        vm.set_source("0 (resume-debug)");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(UNSUPPORTED_OPERATION));
+        vm.reset();
+        vm.set_source("1 (debug)");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_NUMERIC_ARGUMENT));
+    }
+
+    #[test]
+    fn test_run_for_stopped() {
+        let vm = &mut VM::with_tasks(1);
+        vm.set_source(": main   1 2 + drop ;");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let main = vm.find("main").expect("main");
+        let plus = vm.find("+").expect("+");
+        vm.set_breakpoint(0, Breakpoint::Word(plus));
+        vm.attach(0);
+        vm.continue_debug(0);
+        vm.execute_word(main);
+        assert_eq!(vm.run_for(usize::MAX, u64::MAX), RunStatus::Stopped);
+        assert_eq!(vm.s_stack().as_slice(), [1, 2]);
+        vm.step_into(0);
+        assert_eq!(vm.run_for(usize::MAX, u64::MAX), RunStatus::Stopped);
+        assert_eq!(vm.s_stack().as_slice(), [3]);
+        vm.detach(0);
+        assert_eq!(vm.run_for(usize::MAX, u64::MAX), RunStatus::Idle);
+        assert_eq!(vm.s_stack().as_slice(), []);
+    }
+
+    #[test]
+    fn test_watch() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            variable a  variable b  fvariable f
+            : poke   5 b ! ;
+            : pokes   2 b 1+ c! ;
+            : copy   a b 1 cells move ;
+            : fpoke   f f! ;
+            b 1 cells watch  f 1 floats watch
+            1 a !  a @
+            ' poke catch  b @
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [1, WATCHPOINT_HIT.into(), 5]);
+        let b = vm.find("b").expect("b");
+        let b = vm.wordlist()[b].dfa();
+        let (poke, store) = (vm.find("poke"), vm.find("!"));
+        let hit = vm.watch_hit().cloned().expect("hit");
+        assert_eq!((hit.task, Some(hit.xt), hit.caller), (0, store, poke));
+        assert_eq!(
+            (hit.addr, hit.old, hit.new),
+            (b, WatchValue::Cell(0), WatchValue::Cell(5))
+        );
+        assert_eq!(
+            vm.output_buffer().clone().unwrap(),
+            format!(
+                "Watchpoint at {:X} written by ! in poke of task 1: 0 -> 5\n",
+                b
+            )
+        );
+        vm.output_buffer().as_mut().unwrap().clear();
+        vm.s_stack().reset();
+        vm.set_source("' pokes catch  ' copy catch  1.5e ' fpoke catch");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [WATCHPOINT_HIT.into(); 3]);
+        let hit = vm.watch_hit().cloned().expect("hit");
+        assert_eq!(hit.new, WatchValue::Float(1.5));
+        let report = vm.output_buffer().clone().unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" by c! in pokes of task 1: 0 -> 2"));
+        assert!(lines[1].ends_with(
+            " by move in copy of task 1: 05 02 00 00 00 00 00 00 -> 01 00 00 00 00 00 00 00"
+        ));
+        assert!(lines[2].ends_with(" by f! in fpoke of task 1: 0e0 -> 1.5e0"));
+        // `,` into a watched range, and unwatched.
+        vm.s_stack().reset();
+        vm.set_source("here 1 cells watch  3 ' , catch  b unwatch  7 b !  9 ,  b @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [3, WATCHPOINT_HIT.into(), 7]);
+        assert_eq!(vm.instrumentation().watchpoints.len(), 2);
+        vm.set_source("0 0 watch");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_NUMERIC_ARGUMENT));
+    }
+
+    #[test]
+    fn test_watch_break() {
+        let vm = &mut VM::with_tasks(1);
+        vm.set_source("variable v  : main   3 v !  v @ drop ;  v 1 cells watch");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let main = vm.find("main").expect("main");
+        vm.attach(0);
+        vm.continue_debug(0);
+        vm.execute_word(main);
+        // Stopped after the write.
+        assert_eq!(vm.run_for(usize::MAX, u64::MAX), RunStatus::Stopped);
+        assert_eq!(vm.last_error(), None);
+        assert!(vm.debug_report(0).contains(" in main: v"));
+        let hit = vm.watch_hit().cloned().expect("hit");
+        assert_eq!(hit.new, WatchValue::Cell(3));
+        vm.detach(0);
+        assert_eq!(vm.run_for(usize::MAX, u64::MAX), RunStatus::Idle);
+    }
+}
@@ -0,0 +1,368 @@
+//! Double-Number word set
+//!
+//! A double-cell number occupies two cells on the data stack, with the
+//! most significant cell on top. Arithmetic is done with 128-bit
+//! intermediates.
+
+use core::Core;
+use exception::{DIVISION_BY_ZERO, RESULT_OUT_OF_RANGE};
+use memory::Memory;
+use output::Output;
+use std::mem;
+use {FALSE, TRUE};
+
+const CELL_BITS: u32 = (mem::size_of::<usize>() * 8) as u32;
+
+/// Combine the low cell `lo` and the high cell `hi` into a double-cell
+/// number.
+pub fn to_double(lo: isize, hi: isize) -> i128 {
+    ((hi as i128) << CELL_BITS) | (lo as usize as i128)
+}
+
+/// Split a double-cell number `d` into its low and high cells.
+pub fn from_double(d: i128) -> (isize, isize) {
+    (d as isize, (d >> CELL_BITS) as isize)
+}
+
+/// Combine the low cell `lo` and the high cell `hi` into an unsigned
+/// double-cell number.
+pub fn to_udouble(lo: isize, hi: isize) -> u128 {
+    ((hi as usize as u128) << CELL_BITS) | (lo as usize as u128)
+}
+
+/// Split an unsigned double-cell number `ud` into its low and high cells.
+pub fn from_udouble(ud: u128) -> (isize, isize) {
+    (ud as usize as isize, (ud >> CELL_BITS) as usize as isize)
+}
+
+pub trait Double: Output {
+    /// Add double-number primitives.
+    fn add_double(&mut self) {
+        self.add_primitive("d+", Double::d_plus);
+        self.add_primitive("d-", Double::d_minus);
+        self.add_primitive("dnegate", Double::d_negate);
+        self.add_primitive("dabs", Double::d_abs);
+        self.add_primitive("d<", Double::d_less_than);
+        self.add_primitive("d=", Double::d_equals);
+        self.add_primitive("du<", Double::d_u_less);
+        self.add_primitive("d0<", Double::d_zero_less);
+        self.add_primitive("d0=", Double::d_zero_equals);
+        self.add_primitive("d2*", Double::d_two_star);
+        self.add_primitive("d2/", Double::d_two_slash);
+        self.add_primitive("dmax", Double::d_max);
+        self.add_primitive("dmin", Double::d_min);
+        self.add_primitive("d>s", Double::d_to_s);
+        self.add_primitive("m+", Double::m_plus);
+        self.add_primitive("m*/", Double::m_star_slash);
+        self.add_primitive("d.r", Double::d_dot_r);
+        self.add_primitive("2constant", Double::two_constant);
+        self.add_doer("2constant", Double::p_two_const, Core::compile_const);
+    }
+
+    /// Pop a double-cell number from data stack.
+    fn pop_double(&mut self) -> i128 {
+        let (lo, hi) = self.s_stack().pop2();
+        to_double(lo, hi)
+    }
+
+    /// Push a double-cell number to data stack.
+    fn push_double(&mut self, d: i128) {
+        let (lo, hi) = from_double(d);
+        self.s_stack().push2(lo, hi);
+    }
+
+    /// Run-time: ( d1|ud1 d2|ud2 -- d3|ud3 )
+    ///
+    /// Add d2|ud2 to d1|ud1, giving the sum d3|ud3.
+    fn d_plus(&mut self) {
+        let d2 = self.pop_double();
+        let d1 = self.pop_double();
+        self.push_double(d1.wrapping_add(d2));
+    }
+
+    /// Run-time: ( d1|ud1 d2|ud2 -- d3|ud3 )
+    ///
+    /// Subtract d2|ud2 from d1|ud1, giving the difference d3|ud3.
+    fn d_minus(&mut self) {
+        let d2 = self.pop_double();
+        let d1 = self.pop_double();
+        self.push_double(d1.wrapping_sub(d2));
+    }
+
+    /// Run-time: ( d1 -- d2 )
+    ///
+    /// d2 is the negation of d1.
+    fn d_negate(&mut self) {
+        let d = self.pop_double();
+        self.push_double(d.wrapping_neg());
+    }
+
+    /// Run-time: ( d -- ud )
+    ///
+    /// ud is the absolute value of d.
+    fn d_abs(&mut self) {
+        let d = self.pop_double();
+        self.push_double(d.wrapping_abs());
+    }
+
+    /// Run-time: ( d1 d2 -- flag )
+    ///
+    /// flag is true if and only if d1 is less than d2.
+    fn d_less_than(&mut self) {
+        let d2 = self.pop_double();
+        let d1 = self.pop_double();
+        self.s_stack().push(if d1 < d2 { TRUE } else { FALSE });
+    }
+
+    /// Run-time: ( xd1 xd2 -- flag )
+    ///
+    /// flag is true if and only if xd1 is bit-for-bit the same as xd2.
+    fn d_equals(&mut self) {
+        let d2 = self.pop_double();
+        let d1 = self.pop_double();
+        self.s_stack().push(if d1 == d2 { TRUE } else { FALSE });
+    }
+
+    /// Run-time: ( ud1 ud2 -- flag )
+    ///
+    /// flag is true if and only if ud1 is less than ud2.
+    fn d_u_less(&mut self) {
+        let (lo2, hi2) = self.s_stack().pop2();
+        let (lo1, hi1) = self.s_stack().pop2();
+        let flag = to_udouble(lo1, hi1) < to_udouble(lo2, hi2);
+        self.s_stack().push(if flag { TRUE } else { FALSE });
+    }
+
+    /// Run-time: ( d -- flag )
+    ///
+    /// flag is true if and only if d is less than zero.
+    fn d_zero_less(&mut self) {
+        let d = self.pop_double();
+        self.s_stack().push(if d < 0 { TRUE } else { FALSE });
+    }
+
+    /// Run-time: ( xd -- flag )
+    ///
+    /// flag is true if and only if xd is equal to zero.
+    fn d_zero_equals(&mut self) {
+        let d = self.pop_double();
+        self.s_stack().push(if d == 0 { TRUE } else { FALSE });
+    }
+
+    /// Run-time: ( xd1 -- xd2 )
+    ///
+    /// xd2 is the result of shifting xd1 one bit toward the most-significant
+    /// bit, filling the vacated least-significant bit with zero.
+    fn d_two_star(&mut self) {
+        let d = self.pop_double();
+        self.push_double(d.wrapping_shl(1));
+    }
+
+    /// Run-time: ( xd1 -- xd2 )
+    ///
+    /// xd2 is the result of shifting xd1 one bit toward the least-significant
+    /// bit, leaving the most-significant bit unchanged.
+    fn d_two_slash(&mut self) {
+        let d = self.pop_double();
+        self.push_double(d >> 1);
+    }
+
+    /// Run-time: ( d1 d2 -- d3 )
+    ///
+    /// d3 is the greater of d1 and d2.
+    fn d_max(&mut self) {
+        let d2 = self.pop_double();
+        let d1 = self.pop_double();
+        self.push_double(if d1 < d2 { d2 } else { d1 });
+    }
+
+    /// Run-time: ( d1 d2 -- d3 )
+    ///
+    /// d3 is the lesser of d1 and d2.
+    fn d_min(&mut self) {
+        let d2 = self.pop_double();
+        let d1 = self.pop_double();
+        self.push_double(if d1 < d2 { d1 } else { d2 });
+    }
+
+    /// Run-time: ( d -- n )
+    ///
+    /// n is the equivalent of d. Abort with `RESULT_OUT_OF_RANGE` if d lies
+    /// outside the range of a signed single-cell number.
+    fn d_to_s(&mut self) {
+        let d = self.pop_double();
+        if d < isize::MIN as i128 || d > isize::MAX as i128 {
+            self.abort_with(RESULT_OUT_OF_RANGE);
+        } else {
+            self.s_stack().push(d as isize);
+        }
+    }
+
+    /// Run-time: ( d1|ud1 n -- d2|ud2 )
+    ///
+    /// Add n to d1|ud1, giving the sum d2|ud2.
+    fn m_plus(&mut self) {
+        let n = self.s_stack().pop();
+        let d = self.pop_double();
+        self.push_double(d.wrapping_add(n as i128));
+    }
+
+    /// Run-time: ( d1 n1 +n2 -- d2 )
+    ///
+    /// Multiply d1 by n1 producing the triple-cell intermediate result t.
+    /// Divide t by +n2 giving the double-cell quotient d2. Like `/`, the
+    /// division is symmetric.
+    ///
+    /// The intermediate is kept in 128 bits, so `RESULT_OUT_OF_RANGE` is
+    /// raised when d1 * n1 or the quotient does not fit.
+    fn m_star_slash(&mut self) {
+        let (n1, n2) = self.s_stack().pop2();
+        let d1 = self.pop_double();
+        if n2 == 0 {
+            self.abort_with(DIVISION_BY_ZERO);
+            return;
+        }
+        match d1
+            .checked_mul(n1 as i128)
+            .and_then(|t| t.checked_div(n2 as i128))
+        {
+            Some(d2) => self.push_double(d2),
+            None => self.abort_with(RESULT_OUT_OF_RANGE),
+        }
+    }
+
+    /// Run-time: ( d n -- )
+    ///
+    /// Display d right aligned in a field n characters wide.
+    fn d_dot_r(&mut self) {
+        let n = self.s_stack().pop();
+        let d = self.pop_double();
+        self.hold_buffer().clear();
+        if self.hold_signed(d) {
+            self.type_held(n);
+        }
+    }
+
+    /// Run-time: ( x1 x2 "<spaces>name" -- )
+    ///
+    /// Skip leading space delimiters. Parse name delimited by a space. Create
+    /// a definition for name with the execution semantics defined below.
+    ///
+    /// name Execution: ( -- x1 x2 )
+    ///
+    /// Place cell pair x1 x2 on the stack.
+    fn two_constant(&mut self) {
+        let (x1, x2) = self.s_stack().pop2();
+        self.define(Double::p_two_const, Core::compile_const);
+        if self.last_error().is_none() {
+            self.data_space().compile_isize(x2);
+            self.data_space().compile_isize(x1);
+        }
+    }
+
+    /// Run time behavior of words created by `2constant`.
+    fn p_two_const(&mut self) {
+        let wp = self.state().word_pointer();
+        let dfa = self.wordlist()[wp].dfa();
+        let x2 = unsafe { self.data_space().get_isize(dfa) };
+        let x1 = unsafe { self.data_space().get_isize(dfa + mem::size_of::<isize>()) };
+        self.s_stack().push2(x1, x2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_double, to_double};
+    use core::Core;
+    use exception::{DIVISION_BY_ZERO, RESULT_OUT_OF_RANGE};
+    use mock_vm::VM;
+
+    #[test]
+    fn test_to_from_double() {
+        assert_eq!(to_double(-1, -1), -1);
+        assert_eq!(to_double(-1, 0), u64::MAX as i128);
+        assert_eq!(from_double(-1), (-1, -1));
+        assert_eq!(from_double(1 << 64), (0, 1));
+    }
+
+    #[test]
+    fn test_d_plus_minus() {
+        let vm = &mut VM::new();
+        vm.set_source("-1 0 1 0 d+  5 0 6 0 d-  1 0 dnegate");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1, -1, -1, -1, -1]);
+    }
+
+    #[test]
+    fn test_d_compare() {
+        let vm = &mut VM::new();
+        vm.set_source("-1 -1 0 0 d<  0 1 -1 0 d<  -1 0 0 1 du<  0 1 0 1 d=  0 0 d0=  0 -1 d0<");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, 0, -1, -1, -1, -1]);
+    }
+
+    #[test]
+    fn test_d_to_s() {
+        let vm = &mut VM::new();
+        vm.set_source("-5 s>d d>s  -5 -1 dabs d>s");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-5, 5]);
+        vm.s_stack().reset();
+        vm.set_source("0 1 d>s");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(RESULT_OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn test_d_shift_max_min() {
+        let vm = &mut VM::new();
+        vm.set_source("-1 0 d2*  0 1 d2/  1 0 -1 -1 dmax  1 0 -1 -1 dmin");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(
+            vm.s_stack().as_slice(),
+            [-2, 1, isize::MIN, 0, 1, 0, -1, -1]
+        );
+    }
+
+    #[test]
+    fn test_m_plus_m_star_slash() {
+        let vm = &mut VM::new();
+        vm.set_source("-1 0 1 m+  max-n s>d max-n max-n m*/  -7 s>d 1 2 m*/");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1, isize::MAX, 0, -3, -1]);
+        vm.s_stack().reset();
+        vm.set_source("1 0 1 0 m*/");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(DIVISION_BY_ZERO));
+        vm.reset();
+        vm.set_source("0 max-n invert 1 -1 m*/");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(RESULT_OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn test_d_dot() {
+        let vm = &mut VM::new();
+        vm.set_source("0 1 d.  -1 -1 d.  5 0 4 d.r");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(
+            vm.output_buffer().as_ref().unwrap(),
+            "18446744073709551616 -1    5"
+        );
+    }
+
+    #[test]
+    fn test_2constant() {
+        let vm = &mut VM::new();
+        vm.set_source("0 1 2constant big  big  : x big ; x");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1, 0, 1]);
+    }
+}
@@ -3,11 +3,17 @@
 use std::convert::From;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct Exception(i16);
+pub struct Exception(isize);
 
 impl From<Exception> for isize {
     fn from(e: Exception) -> Self {
-        e.0 as _
+        e.0
+    }
+}
+
+impl From<isize> for Exception {
+    fn from(n: isize) -> Self {
+        Exception(n)
     }
 }
 
@@ -127,6 +133,73 @@ pub const QUIT: Exception = Exception(-56);
 pub const EXCEPTION_IN_SENDING_OR_RECEIVING_A_CHARACTER: Exception = Exception(-57);
 /// = -58, ANS Forth
 pub const BRACKET_IF_ELSE_OR_THEN_EXCEPTION: Exception = Exception(-58);
+/// = -59, Forth 2012
+pub const ALLOCATE: Exception = Exception(-59);
+/// = -60, Forth 2012
+pub const FREE: Exception = Exception(-60);
+/// = -61, Forth 2012
+pub const RESIZE: Exception = Exception(-61);
+/// = -256, rtForth
+pub const DEADLINE_MISSED: Exception = Exception(-256);
+/// = -257, rtForth
+pub const INVALID_IMAGE: Exception = Exception(-257);
+/// = -258, rtForth
+pub const WATCHPOINT_HIT: Exception = Exception(-258);
+
+/// First exception code allocated to applications. Later codes are
+/// allocated downwards.
+pub const FIRST_USER_EXCEPTION: isize = -4096;
+
+/// Registry of exceptions defined by applications.
+#[derive(Default)]
+pub struct Exceptions {
+    messages: Vec<String>,
+    abort_message: String,
+}
+
+impl Exceptions {
+    pub fn new() -> Exceptions {
+        Exceptions::default()
+    }
+
+    /// Allocate a new exception code with description `message`.
+    pub fn allocate(&mut self, message: &str) -> Exception {
+        let e = Exception(FIRST_USER_EXCEPTION - self.messages.len() as isize);
+        self.messages.push(message.to_string());
+        e
+    }
+
+    /// Messages of allocated exceptions, in the order of allocation.
+    pub fn messages(&self) -> &[String] {
+        &self.messages
+    }
+
+    /// Replace allocated exceptions with `messages`.
+    pub fn set_messages(&mut self, messages: Vec<String>) {
+        self.messages = messages;
+    }
+
+    /// Set the message displayed for the last `ABORT"`.
+    pub fn set_abort_message(&mut self, message: &str) {
+        self.abort_message.clear();
+        self.abort_message.push_str(message);
+    }
+
+    /// Description of `e`, either a standard or rtForth exception, an
+    /// exception allocated by the application or the message of the last
+    /// `ABORT"`.
+    pub fn description(&self, e: Exception) -> &str {
+        if e == ABORT_QUOTE && !self.abort_message.is_empty() {
+            return &self.abort_message;
+        }
+        let i = FIRST_USER_EXCEPTION.wrapping_sub(e.0);
+        if 0 <= i && (i as usize) < self.messages.len() {
+            &self.messages[i as usize]
+        } else {
+            description(e)
+        }
+    }
+}
 
 /// Description of the exception
 pub fn description(e: Exception) -> &'static str {
@@ -191,6 +264,12 @@ pub fn description(e: Exception) -> &'static str {
             "Exception in sending or receiving a character"
         }
         BRACKET_IF_ELSE_OR_THEN_EXCEPTION => "[IF],[ELSE],[THEN] exception",
+        ALLOCATE => "ALLOCATE",
+        FREE => "FREE",
+        RESIZE => "RESIZE",
+        DEADLINE_MISSED => "Deadline missed",
+        INVALID_IMAGE => "Invalid image",
+        WATCHPOINT_HIT => "Watchpoint hit",
         _ => "",
     }
 }
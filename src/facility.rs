@@ -1,6 +1,9 @@
 //! Facility word set
 
-use core::Core;
+use core::{Core, Schedule};
+use exception::{DEADLINE_MISSED, INVALID_MEMORY_ADDRESS, INVALID_NUMERIC_ARGUMENT};
+use memory::{DataSpace, Memory};
+use std::mem;
 
 pub trait Facility: Core {
     /// Run-time: ( --  )
@@ -9,6 +12,19 @@ pub trait Facility: Core {
     fn add_facility(&mut self) {
         self.add_primitive("mtime", Facility::mtime);
         self.add_primitive("utime", Facility::utime);
+        self.add_primitive("period", Facility::period);
+        self.add_primitive("next-period", Facility::next_period);
+        self.add_primitive("priority", Facility::priority);
+        self.add_primitive("task-stats", Facility::task_stats);
+        self.add_primitive("0task-stats", Facility::clear_task_stats);
+        self.add_primitive("begin-structure", Facility::begin_structure);
+        self.add_primitive("end-structure", Facility::end_structure);
+        self.add_primitive("+field", Facility::plus_field);
+        self.add_primitive("field:", Facility::field_colon);
+        self.add_primitive("cfield:", Facility::cfield_colon);
+        self.add_primitive("2field:", Facility::two_field_colon);
+        self.add_primitive("ffield:", Facility::ffield_colon);
+        self.add_doer("+field", Facility::p_field, Core::compile_word);
     }
 
     /// System time in milli-seconds. `mtime ( -- milli-seconds )`
@@ -22,4 +38,403 @@ pub trait Facility: Core {
         let now = self.system_time_ns() / 1_000;
         self.s_stack().push(now as isize);
     }
+
+    /// Schedule of task `i`, `None` if there is no task `i`.
+    fn schedule(&mut self, i: usize) -> Option<Schedule> {
+        if i < self.num_tasks() {
+            let current_task = self.current_task();
+            self.set_current_task(i);
+            let schedule = self.state().schedule;
+            self.set_current_task(current_task);
+            Some(schedule)
+        } else {
+            None
+        }
+    }
+
+    /// Set priority of task `i`.
+    ///
+    /// No operation if there is no task `i`.
+    fn set_priority(&mut self, i: usize, priority: isize) {
+        if i < self.num_tasks() {
+            let current_task = self.current_task();
+            self.set_current_task(i);
+            self.state().schedule.priority = priority;
+            self.set_current_task(current_task);
+        }
+    }
+
+    /// Set period of task `i` to `period_ns` nano-seconds, and start its
+    /// first period now. A period of 0 makes the task not periodic.
+    ///
+    /// No operation if there is no task `i`.
+    fn set_period(&mut self, i: usize, period_ns: u64) {
+        if i < self.num_tasks() {
+            let now = self.system_time_ns();
+            let current_task = self.current_task();
+            self.set_current_task(i);
+            {
+                let schedule = &mut self.state().schedule;
+                schedule.period_ns = period_ns;
+                schedule.release_ns = now;
+                schedule.waiting = false;
+                schedule.start(now);
+            }
+            self.set_current_task(current_task);
+        }
+    }
+
+    /// Run-time: ( F: t -- )
+    ///
+    /// Make the current task periodic with period `t` seconds, starting
+    /// from now. `0e period` makes the task not periodic.
+    ///
+    /// Example: `1e msec period`
+    fn period(&mut self) {
+        let t = self.f_stack().pop();
+        if t >= 0.0 && t * 1e9 < u64::MAX as f64 {
+            let i = self.current_task();
+            self.set_period(i, (t * 1e9) as u64);
+        } else {
+            self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// End the job of the current period and wait for the next period. A
+    /// task which is not periodic just pauses.
+    ///
+    /// If the job ends after the end of its period, the deadline is missed.
+    /// The task skips the periods already passed, stays in the current
+    /// one and `DEADLINE_MISSED` is raised.
+    fn next_period(&mut self) {
+        let now = self.system_time_ns();
+        let i = self.current_task();
+        let missed = {
+            let schedule = &mut self.state().schedule;
+            if schedule.period_ns == 0 {
+                None
+            } else {
+                schedule.exec_ns = now.saturating_sub(schedule.start_ns);
+                schedule.max_exec_ns = schedule.max_exec_ns.max(schedule.exec_ns);
+                let deadline = schedule.release_ns + schedule.period_ns;
+                if now > deadline {
+                    schedule.misses += 1;
+                    let passed = (now - schedule.release_ns) / schedule.period_ns;
+                    schedule.release_ns += passed * schedule.period_ns;
+                    schedule.start(now);
+                    Some(true)
+                } else {
+                    schedule.release_ns = deadline;
+                    schedule.waiting = true;
+                    Some(false)
+                }
+            }
+        };
+        match missed {
+            Some(true) => self.abort_with(DEADLINE_MISSED),
+            Some(false) => {
+                self.set_awake(i, false);
+                self.pause();
+            }
+            None => self.pause(),
+        }
+    }
+
+    /// Run-time: ( n -- )
+    ///
+    /// Set priority of the current task to `n`. Among tasks which are
+    /// awake, the one with the highest priority is resumed first. The
+    /// default priority is 0.
+    fn priority(&mut self) {
+        let n = self.s_stack().pop();
+        let i = self.current_task();
+        self.set_priority(i, n);
+    }
+
+    /// Run-time: ( n -- releases misses max-jitter max-exec )
+    ///
+    /// Statistics of task `n`: number of releases, number of missed
+    /// deadlines, maximum release jitter and maximum execution time of a
+    /// job in nano-seconds.
+    fn task_stats(&mut self) {
+        let i = (self.s_stack().pop() - 1) as usize;
+        match self.schedule(i) {
+            Some(s) => {
+                self.s_stack().push2(s.releases as isize, s.misses as isize);
+                self.s_stack()
+                    .push2(s.max_jitter_ns as isize, s.max_exec_ns as isize);
+            }
+            None => self.abort_with(INVALID_NUMERIC_ARGUMENT),
+        }
+    }
+
+    /// Run-time: ( "<spaces>name" -- struct-sys 0 )
+    ///
+    /// Skip leading space delimiters. Parse name delimited by a space. Create
+    /// a definition for name, which returns the size of the structure, and
+    /// begin the definition of the structure. The size is filled in by
+    /// `end-structure`.
+    ///
+    /// Example:
+    ///
+    /// ```text
+    /// begin-structure axis
+    ///   field: axis.id
+    ///   cfield: axis.homed
+    ///   ffield: axis.position
+    /// end-structure
+    /// ```
+    fn begin_structure(&mut self) {
+        self.define(Core::p_const, Core::compile_const);
+        if self.last_error().is_none() {
+            let struct_sys = self.data_space().here();
+            self.data_space().compile_isize(0);
+            self.s_stack().push2(struct_sys as isize, 0);
+        }
+    }
+
+    /// Run-time: ( struct-sys +n -- )
+    ///
+    /// End the structure begun by `begin-structure`, whose size is `+n`.
+    fn end_structure(&mut self) {
+        let (struct_sys, n) = self.s_stack().pop2();
+        let addr = struct_sys as usize;
+        let here = self.data_space().here();
+        if self.data_space().start() <= addr && addr <= here.saturating_sub(mem::size_of::<isize>())
+        {
+            // Written like `!`, so that code is protected and watchpoints
+            // are triggered.
+            self.s_stack().push2(n, struct_sys);
+            self.store();
+        } else {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+        }
+    }
+
+    /// Define field `name` at offset `n1`, and return `n1 + size`.
+    fn define_field(&mut self, n1: isize, size: isize) {
+        self.define(Facility::p_field, Core::compile_word);
+        if self.last_error().is_none() {
+            self.data_space().compile_isize(n1);
+            self.s_stack().push(n1.wrapping_add(size));
+        }
+    }
+
+    /// Run-time: ( n1 n2 "<spaces>name" -- n3 )
+    ///
+    /// Skip leading space delimiters. Parse name delimited by a space. Create
+    /// a field `name` at offset `n1` in a structure, `n2` is the size of the
+    /// field. `n3 = n1 + n2`.
+    ///
+    /// `name` execution: ( addr1 -- addr2 )
+    ///
+    /// Add the offset of the field to `addr1`.
+    fn plus_field(&mut self) {
+        let (n1, n2) = self.s_stack().pop2();
+        self.define_field(n1, n2);
+    }
+
+    /// Run-time: ( addr1 -- addr2 )
+    ///
+    /// Action of fields created by `+field` and its variants.
+    fn p_field(&mut self) {
+        let wp = self.state().word_pointer();
+        let dfa = self.wordlist()[wp].dfa();
+        let offset = unsafe { self.data_space().get_isize(dfa) };
+        let addr = self.s_stack().pop();
+        self.s_stack().push(addr.wrapping_add(offset));
+    }
+
+    /// Run-time: ( n1 "<spaces>name" -- n2 )
+    ///
+    /// Create a cell field `name` at offset `n1` aligned to a cell.
+    fn field_colon(&mut self) {
+        let n1 = DataSpace::aligned(self.s_stack().pop() as usize);
+        self.define_field(n1 as isize, mem::size_of::<isize>() as isize);
+    }
+
+    /// Run-time: ( n1 "<spaces>name" -- n2 )
+    ///
+    /// Create a character field `name` at offset `n1`.
+    fn cfield_colon(&mut self) {
+        let n1 = self.s_stack().pop();
+        self.define_field(n1, mem::size_of::<u8>() as isize);
+    }
+
+    /// Run-time: ( n1 "<spaces>name" -- n2 )
+    ///
+    /// Create a double-cell field `name` at offset `n1` aligned to a cell.
+    fn two_field_colon(&mut self) {
+        let n1 = DataSpace::aligned(self.s_stack().pop() as usize);
+        self.define_field(n1 as isize, 2 * mem::size_of::<isize>() as isize);
+    }
+
+    /// Run-time: ( n1 "<spaces>name" -- n2 )
+    ///
+    /// Create a float field `name` at offset `n1` aligned to a float.
+    fn ffield_colon(&mut self) {
+        let n1 = DataSpace::aligned_f64(self.s_stack().pop() as usize);
+        self.define_field(n1 as isize, mem::size_of::<f64>() as isize);
+    }
+
+    /// Run-time: ( n -- )
+    ///
+    /// Clear statistics of task `n`.
+    fn clear_task_stats(&mut self) {
+        let i = (self.s_stack().pop() - 1) as usize;
+        if i < self.num_tasks() {
+            let current_task = self.current_task();
+            self.set_current_task(i);
+            self.state().schedule.clear_stats();
+            self.set_current_task(current_task);
+        } else {
+            self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Facility;
+    use core::{Core, RunStatus};
+    use exception::{DEADLINE_MISSED, INVALID_NUMERIC_ARGUMENT, WRITE_TO_A_READ_ONLY_LOCATION};
+    use memory::Memory;
+    use mock_vm::VM;
+
+    #[test]
+    fn test_period() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            variable counter
+            : job   1e msec period  begin 1 counter +! next-period again ;
+            : watch ( n -- )   0 ?do pause loop ;
+            ' job spawn drop  3 watch  counter @
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().pop(), 1);
+        assert!(!vm.awake(1));
+        // Released at the next period.
+        vm.advance();
+        vm.set_source("3 watch  counter @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().pop(), 2);
+        let s = vm.schedule(1).expect("schedule");
+        assert_eq!(s.period_ns, 1_000_000);
+        assert_eq!(s.releases, 2);
+        assert_eq!(s.misses, 0);
+        // Late release and missed deadline.
+        vm.advance();
+        vm.advance();
+        vm.advance();
+        vm.set_source("1 watch");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(DEADLINE_MISSED));
+        vm.reset();
+        vm.clear_stacks();
+        vm.set_source("2 task-stats");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [4, 1, 2_000_000, 0]);
+        vm.s_stack().reset();
+        vm.set_source("2 0task-stats  2 task-stats");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 0, 0, 0]);
+        vm.s_stack().reset();
+        vm.set_source("9 task-stats");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_NUMERIC_ARGUMENT));
+    }
+
+    #[test]
+    fn test_period_single_task() {
+        let vm = &mut VM::with_tasks(1);
+        vm.set_source(
+            "
+            variable counter
+            : job   1e msec period  begin 1 counter +! next-period again ;
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let counter = vm.find("counter").expect("counter");
+        let counter = vm.wordlist()[counter].dfa();
+        let job = vm.find("job").expect("job");
+        vm.execute_word(job);
+        // With no other task awake, the task sleeps until its next period
+        // instead of running on.
+        assert_eq!(vm.run_for(1000, u64::MAX), RunStatus::Paused);
+        assert_eq!(unsafe { vm.data_space().get_isize(counter) }, 1);
+        assert!(!vm.awake(0));
+        assert_eq!(vm.run_for(1000, u64::MAX), RunStatus::Paused);
+        assert_eq!(unsafe { vm.data_space().get_isize(counter) }, 1);
+        // Released at the next period.
+        vm.advance();
+        assert_eq!(vm.run_for(1000, u64::MAX), RunStatus::Paused);
+        assert_eq!(unsafe { vm.data_space().get_isize(counter) }, 2);
+        assert_eq!(vm.schedule(0).expect("schedule").releases, 2);
+    }
+
+    #[test]
+    fn test_priority() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            variable low  variable high
+            : lo   begin 1 low +! pause again ;
+            : hi   1 priority  1e msec period  begin 1 high +! next-period again ;
+            : watch ( n -- )   0 ?do pause loop ;
+            ' lo spawn  ' hi spawn  2drop  2 watch
+            low @  high @
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [2, 1]);
+        vm.s_stack().reset();
+        // The released task with higher priority runs before `lo`.
+        vm.advance();
+        vm.set_source("1 watch  low @  high @");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [2, 2]);
+        assert_eq!(vm.schedule(2).expect("schedule").priority, 1);
+    }
+
+    #[test]
+    fn test_structure() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            begin-structure axis
+              cfield: axis.homed
+              field: axis.id
+              cfield: axis.enabled
+              ffield: axis.position
+              2field: axis.limits
+              3 +field axis.name
+            end-structure
+            axis  0 axis.homed  0 axis.id  0 axis.enabled  0 axis.position
+            0 axis.limits  0 axis.name
+            create x  axis allot align
+            : position!   x axis.position f! ;
+            2.5e position!  x axis.position f@
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [51, 0, 8, 16, 24, 32, 48]);
+        assert_eq!(vm.f_stack().as_slice(), [2.5]);
+        vm.s_stack().reset();
+        // Code cannot be overwritten.
+        vm.set_source(": t   1 2 + ;  ' t >body 0 end-structure");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(WRITE_TO_A_READ_ONLY_LOCATION));
+    }
 }
@@ -1,7 +1,9 @@
 //! File access word set
 
+use double::{from_udouble, to_udouble};
 use exception::{
-    FILE_IO_EXCEPTION, INVALID_MEMORY_ADDRESS, INVALID_NUMERIC_ARGUMENT, RESULT_OUT_OF_RANGE,
+    FILE_IO_EXCEPTION, INVALID_MEMORY_ADDRESS, INVALID_NUMERIC_ARGUMENT,
+    WRITE_TO_A_READ_ONLY_LOCATION,
 };
 use std::fs::{self, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -30,12 +32,6 @@ pub trait FileAccess: Core {
     /// the implementation-defined I/O result code. This operation does not
     /// affect the value returned by FILE- POSITION. ud is undefined if ior is
     /// non-zero.
-    ///
-    /// Note: As rtForth does not support double-length integers, the higher
-    /// part of ud is 0. rtForth also does not support unsigned integers, the
-    /// maximum value of ud allowed is isize::max_value(). So an exception
-    /// RESULT_OUT_OF_RANGE will be returned for a file size larger than
-    /// isize::max_value().
     fn file_size(&mut self) {
         let fileid = self.s_stack().pop();
         if fileid <= 0 {
@@ -46,22 +42,13 @@ pub trait FileAccess: Core {
         let fileid = fileid as usize - 1;
         if fileid < self.files().len() {
             let ud = match &self.files()[fileid] {
-                &Some(ref f) => match f.metadata() {
-                    Ok(m) => {
-                        let ud = m.len();
-                        if ud <= isize::max_value() as u64 {
-                            Ok(ud)
-                        } else {
-                            Err(RESULT_OUT_OF_RANGE)
-                        }
-                    }
-                    Err(_) => Err(FILE_IO_EXCEPTION),
-                },
+                &Some(ref f) => f.metadata().map(|m| m.len()).map_err(|_| FILE_IO_EXCEPTION),
                 &None => Err(INVALID_NUMERIC_ARGUMENT),
             };
             match ud {
                 Ok(ud) => {
-                    self.s_stack().push3(ud as isize, 0, 0);
+                    let (lo, hi) = from_udouble(ud as u128);
+                    self.s_stack().push3(lo, hi, 0);
                 }
                 Err(e) => {
                     self.s_stack().push3(-1, -1, e.into());
@@ -78,12 +65,6 @@ pub trait FileAccess: Core {
     /// ud is the current file position for the file identified by fileid. ior
     /// is the implementation-defined I/O result code. ud is undefined if ior
     /// is non-zero.
-    ///
-    /// Note: As rtForth does not support double-length integers, the higher
-    /// part of ud is 0. rtForth also does not support unsigned integers, the
-    /// maximum value of ud allowed is isize::max_value(). So an exception
-    /// RESULT_OUT_OF_RANGE will be returned for a file position larger than
-    /// isize::max_value().
     fn file_position(&mut self) {
         let fileid = self.s_stack().pop();
         if fileid <= 0 {
@@ -94,21 +75,13 @@ pub trait FileAccess: Core {
         let fileid = fileid as usize - 1;
         if fileid < self.files().len() {
             let ud = match &mut self.files_mut()[fileid] {
-                &mut Some(ref mut f) => match f.seek(SeekFrom::Current(0)) {
-                    Ok(ud) => {
-                        if ud <= isize::max_value() as u64 {
-                            Ok(ud)
-                        } else {
-                            Err(RESULT_OUT_OF_RANGE)
-                        }
-                    }
-                    Err(_) => Err(FILE_IO_EXCEPTION),
-                },
+                &mut Some(ref mut f) => f.seek(SeekFrom::Current(0)).map_err(|_| FILE_IO_EXCEPTION),
                 &mut None => Err(INVALID_NUMERIC_ARGUMENT),
             };
             match ud {
                 Ok(ud) => {
-                    self.s_stack().push3(ud as isize, 0, 0);
+                    let (lo, hi) = from_udouble(ud as u128);
+                    self.s_stack().push3(lo, hi, 0);
                 }
                 Err(e) => self.s_stack().push3(-1, -1, e.into()),
             }
@@ -341,7 +314,11 @@ pub trait FileAccess: Core {
         } else {
             let mut file = self.files_mut()[fileid].take().unwrap();
             let result = {
-                if self.data_space().start() <= caddr && caddr + u1 <= self.data_space().limit() {
+                if self.data_space().is_code(caddr, caddr + u1) {
+                    Err(WRITE_TO_A_READ_ONLY_LOCATION.into())
+                } else if self.data_space().start() <= caddr
+                    && caddr + u1 <= self.data_space().limit()
+                {
                     let mut buf = unsafe { self.data_space().buffer_from_raw_parts_mut(caddr, u1) };
                     file.read(&mut buf).or(Err(FILE_IO_EXCEPTION.into()))
                 } else {
@@ -421,11 +398,8 @@ pub trait FileAccess: Core {
     /// At the conclusion of the operation, FILE-SIZE returns the value ud and
     /// FILE- POSITION returns an unspecified value.
     ///
-    /// Note: As rtForth does not support double-length integers, the higher
-    /// part of ud is 0. rtForth also does not support unsigned integers, the
-    /// maximum value of ud allowed is isize::max_value(). So an exception
-    /// INVALID_NUMERIC_ARGUMENT will be returned for a ud larger than
-    /// isize::max_value().
+    /// Note: An exception INVALID_NUMERIC_ARGUMENT will be returned for a ud
+    /// larger than u64::MAX.
     fn resize_file(&mut self) {
         let (ud_lower, ud_upper, fileid) = self.s_stack().pop3();
         if fileid <= 0 {
@@ -433,17 +407,15 @@ pub trait FileAccess: Core {
             return;
         }
         let fileid = fileid as usize - 1;
-        let ud_lower = ud_lower as usize;
-        if ud_upper != 0 {
-            self.s_stack().push(INVALID_NUMERIC_ARGUMENT.into());
-        } else if ud_lower > isize::max_value() as usize {
+        let ud = to_udouble(ud_lower, ud_upper);
+        if ud > u64::MAX as u128 {
             self.s_stack().push(INVALID_NUMERIC_ARGUMENT.into());
         } else if fileid >= self.files().len() {
             self.s_stack().push(INVALID_NUMERIC_ARGUMENT.into());
         } else {
             match self.files_mut()[fileid].take() {
                 Some(f) => {
-                    match f.set_len(ud_lower as u64) {
+                    match f.set_len(ud as u64) {
                         Ok(_) => {
                             self.s_stack().push(0);
                         }
@@ -468,11 +440,8 @@ pub trait FileAccess: Core {
     ///
     /// At the conclusion of the operation, FILE-POSITION returns the value ud.
     ///
-    /// Note: As rtForth does not support double-length integers, the higher
-    /// part of ud is 0. rtForth also does not support unsigned integers, the
-    /// maximum value of ud allowed is isize::max_value(). So an exception
-    /// INVALID_NUMERIC_ARGUMENT will be returned for a ud larger than
-    /// isize::max_value().
+    /// Note: An exception INVALID_NUMERIC_ARGUMENT will be returned for a ud
+    /// larger than u64::MAX.
     fn reposition_file(&mut self) {
         let (ud_lower, ud_upper, fileid) = self.s_stack().pop3();
         if fileid <= 0 {
@@ -480,17 +449,15 @@ pub trait FileAccess: Core {
             return;
         }
         let fileid = fileid as usize - 1;
-        let ud_lower = ud_lower as usize;
-        if ud_upper != 0 {
-            self.s_stack().push(INVALID_NUMERIC_ARGUMENT.into());
-        } else if ud_lower > isize::max_value() as usize {
+        let ud = to_udouble(ud_lower, ud_upper);
+        if ud > u64::MAX as u128 {
             self.s_stack().push(INVALID_NUMERIC_ARGUMENT.into());
         } else if fileid >= self.files().len() {
             self.s_stack().push(INVALID_NUMERIC_ARGUMENT.into());
         } else {
             match self.files_mut()[fileid].take() {
                 Some(mut f) => {
-                    match f.seek(SeekFrom::Start(ud_lower as u64)) {
+                    match f.seek(SeekFrom::Start(ud as u64)) {
                         Ok(_) => {
                             self.s_stack().push(0);
                         }
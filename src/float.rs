@@ -1,7 +1,7 @@
 //! Floating-point word set
 
-use core::Core;
-use exception::INVALID_MEMORY_ADDRESS;
+use core::{Core, WatchValue};
+use exception::{INVALID_MEMORY_ADDRESS, WRITE_TO_A_READ_ONLY_LOCATION};
 use memory::{DataSpace, Memory};
 use std::f64::consts::PI;
 use std::mem;
@@ -10,6 +10,7 @@ use {FALSE, TRUE};
 pub trait Float: Core {
     fn add_float(&mut self) {
         self.add_primitive("fconstant", Float::fconstant);
+        self.add_doer("fconstant", Float::p_fconst, Core::compile_fconst);
         self.add_primitive("float+", Float::float_plus);
         self.add_primitive("floats", Float::floats);
         self.add_primitive("faligned", Float::faligned);
@@ -124,10 +125,16 @@ pub trait Float: Core {
         let n = self.f_stack().pop();
         // Because t is aligned to f64 boundary, and memory is 4K-page aligned,
         // checking start() <= t < limit() is enough.
-        if self.data_space().start() <= t && t < self.data_space().limit() {
+        if !(self.data_space().start() <= t && t < self.data_space().limit()) {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+        } else if self.data_space().is_code(t, t + mem::size_of::<f64>()) {
+            self.abort_with(WRITE_TO_A_READ_ONLY_LOCATION);
+        } else if self.is_watched(t, mem::size_of::<f64>()) {
+            let old = unsafe { self.data_space().get_f64(t) };
             unsafe { self.data_space().put_f64(n, t) };
+            self.watch_triggered(t, WatchValue::Float(old), WatchValue::Float(n));
         } else {
-            self.abort_with(INVALID_MEMORY_ADDRESS);
+            unsafe { self.data_space().put_f64(n, t) };
         }
     }
 
@@ -334,10 +341,10 @@ pub trait Float: Core {
 #[cfg(test)]
 mod tests {
     use super::Float;
+    use approx::assert_ulps_eq;
     use core::Core;
     use exception::UNDEFINED_WORD;
     use mock_vm::VM;
-    use approx::assert_ulps_eq;
 
     #[test]
     fn test_ans_forth_float() {
@@ -0,0 +1,542 @@
+//! Dictionary image
+//!
+//! An image holds the data space from its start to `here`, including the
+//! system variables, and all words and word lists of a VM, so that a VM can
+//! be restored without evaluating Forth source again.
+//!
+//! ```text
+//! +-------+-------------+-----------+-------+-------+-----+------+------+-----------+------+-------+-----------+------------+
+//! | magic | fingerprint | cell size | start | limit | len | data | code | addresses | heap | words | wordlists | exceptions |
+//! +-------+-------------+-----------+-------+-------+-----+------+------+-----------+------+-------+-----------+------------+
+//! ```
+//!
+//! All numbers are 64-bit little-endian. Code and the heap of `ALLOCATE` are
+//! saved as ranges of offsets from the start of data space, and the cells
+//! holding addresses as offsets. Messages of exceptions allocated by
+//! `exception` are saved as lengths followed by UTF-8 bytes. Actions and compilation semantics
+//! of words are saved as indices into the registries of `Wordlist`. An image
+//! can only be loaded by a VM whose fingerprint, which identifies the
+//! primitives and the registries, is the same as the VM which saved it.
+//!
+//! The data space is usually loaded at a different address. Compiled code
+//! holds offsets from the start of data space and needs no relocation.
+//! Fields of words are relocated, and so are the cells stored by `a,`,
+//! constants defined by `aconstant` and literals compiled by `aliteral`.
+//! Any other cell is restored as it was saved, so that numbers are never
+//! rewritten. An address stored with `,` or `!` would keep pointing into the
+//! old data space, so an image with an aligned cell not marked as an address
+//! whose value lies in the old data space is only loaded at the same
+//! address:
+//!
+//! ```text
+//! create list  here a,                    \ relocated
+//! list aconstant head                     \ relocated
+//! : list-end   [ list cell+ ] aliteral ;  \ relocated
+//! list constant tail                      \ refused elsewhere
+//! variable v  here v !                    \ refused elsewhere
+//! ```
+
+use core::{Core, Word, BUCKET_SIZE};
+use exception::{
+    Exception, DICTIONARY_OVERFLOW, FILE_IO_EXCEPTION, INVALID_IMAGE, INVALID_MEMORY_ADDRESS,
+};
+use memory::Memory;
+use std::fs;
+use std::mem;
+use std::result;
+use std::str;
+
+const MAGIC: &[u8; 8] = b"rtfimg06";
+
+const IMMEDIATE: u64 = 1;
+const COMPILE_ONLY: u64 = 2;
+const HIDDEN: u64 = 4;
+
+/// Reader of numbers in an image.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> result::Result<&'a [u8], Exception> {
+        if len <= self.bytes.len() {
+            let (head, tail) = self.bytes.split_at(len);
+            self.bytes = tail;
+            Ok(head)
+        } else {
+            Err(INVALID_IMAGE)
+        }
+    }
+
+    fn u64(&mut self) -> result::Result<u64, Exception> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn usize(&mut self) -> result::Result<usize, Exception> {
+        let v = self.u64()?;
+        if v <= usize::MAX as u64 {
+            Ok(v as usize)
+        } else {
+            Err(INVALID_IMAGE)
+        }
+    }
+}
+
+fn put_u64(image: &mut Vec<u8>, v: u64) {
+    image.extend_from_slice(&v.to_le_bytes());
+}
+
+pub trait Image: Core {
+    /// Add dictionary image primitives.
+    fn add_image(&mut self) {
+        self.add_primitive("save-image", Image::p_save_image);
+        self.add_primitive("a,", Image::address_comma);
+    }
+
+    /// Image of the dictionary.
+    fn image(&mut self) -> Vec<u8> {
+        let start = self.data_space().start();
+        let limit = self.data_space().limit();
+        let len = self.data_space().here() - start;
+        let mut image = Vec::with_capacity(len + self.wordlist().len() * 80);
+        image.extend_from_slice(MAGIC);
+        put_u64(&mut image, self.wordlist().fingerprint());
+        put_u64(&mut image, mem::size_of::<usize>() as u64);
+        put_u64(&mut image, start as u64);
+        put_u64(&mut image, limit as u64);
+        put_u64(&mut image, len as u64);
+        image.extend_from_slice(unsafe { self.data_space().buffer_from_raw_parts(start, len) });
+        put_u64(&mut image, self.data_space().code().len() as u64);
+        for &(s, e) in self.data_space().code() {
+            put_u64(&mut image, s as u64);
+            put_u64(&mut image, e as u64);
+        }
+        put_u64(&mut image, self.data_space().addresses().len() as u64);
+        for &a in self.data_space().addresses() {
+            put_u64(&mut image, a as u64);
+        }
+        let (heap_start, heap_end) = self.data_space().heap();
+        put_u64(&mut image, heap_start as u64);
+        put_u64(&mut image, heap_end as u64);
+        put_u64(&mut image, self.wordlist().len() as u64);
+        for i in 0..self.wordlist().len() {
+            let (action, compilation) = {
+                let w = &self.wordlist()[i];
+                (w.action, w.compilation_semantics)
+            };
+            let action = self.wordlist_mut().action_index(action);
+            let compilation = self.wordlist_mut().compilation_index(compilation);
+            let w = &self.wordlist()[i];
+            let mut flags = 0;
+            if w.is_immediate {
+                flags |= IMMEDIATE;
+            }
+            if w.is_compile_only {
+                flags |= COMPILE_ONLY;
+            }
+            if w.hidden {
+                flags |= HIDDEN;
+            }
+            for &v in &[
+                flags,
+                w.wid as u64,
+                w.link as u64,
+                w.hash as u64,
+                w.nfa as u64,
+                w.dfa as u64,
+                w.doer as u64,
+                action as u64,
+                compilation as u64,
+            ] {
+                put_u64(&mut image, v);
+            }
+        }
+        let wordlist = self.wordlist();
+        put_u64(&mut image, wordlist.buckets.len() as u64);
+        for (buckets, &name) in wordlist.buckets.iter().zip(&wordlist.names) {
+            put_u64(&mut image, name as u64);
+            for &w in buckets.iter() {
+                put_u64(&mut image, w as u64);
+            }
+        }
+        put_u64(&mut image, wordlist.search_order.len() as u64);
+        for &wid in &wordlist.search_order {
+            put_u64(&mut image, wid as u64);
+        }
+        put_u64(&mut image, wordlist.current as u64);
+        put_u64(&mut image, wordlist.last as u64);
+        put_u64(&mut image, self.exceptions().messages().len() as u64);
+        for message in self.exceptions().messages() {
+            put_u64(&mut image, message.len() as u64);
+            image.extend_from_slice(message.as_bytes());
+        }
+        image
+    }
+
+    /// Replace the dictionary with `image`.
+    ///
+    /// Nothing is changed if the image is invalid.
+    fn restore_image(&mut self, image: &[u8]) -> result::Result<(), Exception> {
+        let mut r = Reader { bytes: image };
+        if r.bytes(MAGIC.len())? != MAGIC
+            || r.u64()? != self.wordlist().fingerprint()
+            || r.usize()? != mem::size_of::<usize>()
+        {
+            return Err(INVALID_IMAGE);
+        }
+        let old_start = r.usize()?;
+        let old_limit = r.usize()?;
+        let len = r.usize()?;
+        let data = r.bytes(len)?;
+        if len < mem::size_of::<usize>() || old_limit < old_start || old_limit - old_start < len {
+            return Err(INVALID_IMAGE);
+        }
+        let start = self.data_space().start();
+        if len > self.data_space().capacity() {
+            return Err(DICTIONARY_OVERFLOW);
+        }
+        let relocate = |addr: usize| addr - old_start + start;
+        let in_image = |addr: usize| old_start <= addr && addr <= old_limit;
+
+        // Code
+        let code_count = r.usize()?;
+        if code_count > r.bytes.len() / 16 {
+            return Err(INVALID_IMAGE);
+        }
+        let mut code = Vec::with_capacity(code_count);
+        let mut end = 0;
+        for _ in 0..code_count {
+            let s = r.usize()?;
+            let e = r.usize()?;
+            if s < end || e <= s || len < e {
+                return Err(INVALID_IMAGE);
+            }
+            end = e;
+            code.push((s, e));
+        }
+
+        // Addresses
+        let cell = mem::size_of::<usize>();
+        let address_count = r.usize()?;
+        if address_count > r.bytes.len() / 8 {
+            return Err(INVALID_IMAGE);
+        }
+        let mut addresses = Vec::with_capacity(address_count);
+        let mut end = 0;
+        for _ in 0..address_count {
+            let a = r.usize()?;
+            if a < end || len < cell || len - cell < a {
+                return Err(INVALID_IMAGE);
+            }
+            end = a + cell;
+            addresses.push(a);
+        }
+
+        // Heap
+        let heap = (r.usize()?, r.usize()?);
+        if heap.1 < heap.0 || len < heap.1 {
+            return Err(INVALID_IMAGE);
+        }
+
+        // Words
+        let word_count = r.usize()?;
+        if word_count > r.bytes.len() / 72 {
+            return Err(INVALID_IMAGE);
+        }
+        let mut words = Vec::with_capacity(word_count);
+        for _ in 0..word_count {
+            let flags = r.u64()?;
+            let wid = r.usize()?;
+            let link = r.usize()?;
+            let hash = r.u64()? as u32;
+            let nfa = r.usize()?;
+            let dfa = r.usize()?;
+            let doer = r.usize()?;
+            let action = r.usize()?;
+            let compilation = r.usize()?;
+            if action >= self.wordlist().actions.len()
+                || compilation >= self.wordlist().compilations.len()
+                || link >= word_count
+                || !in_image(nfa)
+                || !in_image(dfa)
+                || (doer != 0 && !in_image(doer))
+            {
+                return Err(INVALID_IMAGE);
+            }
+            let mut w = Word::new(
+                self.wordlist().actions[action],
+                self.wordlist().compilations[compilation],
+                relocate(nfa),
+                relocate(dfa),
+            );
+            w.is_immediate = flags & IMMEDIATE != 0;
+            w.is_compile_only = flags & COMPILE_ONLY != 0;
+            w.hidden = flags & HIDDEN != 0;
+            w.wid = wid;
+            w.link = link;
+            w.hash = hash;
+            w.doer = if doer == 0 { 0 } else { relocate(doer) };
+            words.push(w);
+        }
+
+        // Word lists
+        let wordlist_count = r.usize()?;
+        if wordlist_count > r.bytes.len() / ((BUCKET_SIZE + 1) * 8) {
+            return Err(INVALID_IMAGE);
+        }
+        let mut names = Vec::with_capacity(wordlist_count);
+        let mut buckets = Vec::with_capacity(wordlist_count);
+        for _ in 0..wordlist_count {
+            let name = r.usize()?;
+            if name != 0 && !in_image(name) {
+                return Err(INVALID_IMAGE);
+            }
+            names.push(if name == 0 { 0 } else { relocate(name) });
+            let mut b = [0; BUCKET_SIZE];
+            for head in b.iter_mut() {
+                *head = r.usize()?;
+                if *head >= word_count {
+                    return Err(INVALID_IMAGE);
+                }
+            }
+            buckets.push(b);
+        }
+        let order_len = r.usize()?;
+        if order_len > r.bytes.len() / 8 {
+            return Err(INVALID_IMAGE);
+        }
+        let mut search_order = Vec::with_capacity(order_len);
+        for _ in 0..order_len {
+            search_order.push(r.usize()?);
+        }
+        let current = r.usize()?;
+        let last = r.usize()?;
+
+        // Exceptions
+        let message_count = r.usize()?;
+        if message_count > r.bytes.len() / 8 {
+            return Err(INVALID_IMAGE);
+        }
+        let mut messages = Vec::with_capacity(message_count);
+        for _ in 0..message_count {
+            let len = r.usize()?;
+            let message = str::from_utf8(r.bytes(len)?).map_err(|_| INVALID_IMAGE)?;
+            messages.push(message.to_string());
+        }
+        if wordlist_count == 0
+            || current >= wordlist_count
+            || last >= word_count
+            || search_order.iter().any(|&wid| wid >= wordlist_count)
+            || words.iter().any(|w| w.wid >= wordlist_count)
+            || !r.bytes.is_empty()
+        {
+            return Err(INVALID_IMAGE);
+        }
+
+        // Data space, relocated before anything in the VM is changed.
+        let cell_at = |data: &[u8], a: usize| {
+            let mut b = [0; mem::size_of::<usize>()];
+            b.copy_from_slice(&data[a..a + cell]);
+            usize::from_ne_bytes(b)
+        };
+        if start != old_start {
+            for a in (0..=len - cell).step_by(cell) {
+                if addresses.binary_search(&a).is_err() && in_image(cell_at(data, a)) {
+                    return Err(INVALID_IMAGE);
+                }
+            }
+        }
+        let mut data = data.to_vec();
+        for &a in &addresses {
+            let v = cell_at(&data, a);
+            if in_image(v) {
+                data[a..a + cell].copy_from_slice(&relocate(v).to_ne_bytes());
+            }
+        }
+        self.data_space().set_here(start + len)?;
+        unsafe {
+            self.data_space()
+                .buffer_from_raw_parts_mut(start, len)
+                .copy_from_slice(&data);
+        }
+        self.data_space().set_code(code);
+        self.data_space().set_addresses(addresses);
+        self.data_space().set_heap(heap);
+
+        let wordlist = self.wordlist_mut();
+        wordlist.words = words;
+        wordlist.buckets = buckets;
+        wordlist.names = names;
+        wordlist.search_order = search_order;
+        wordlist.current = current;
+        wordlist.last = last;
+        self.exceptions_mut().set_messages(messages);
+        Ok(())
+    }
+
+    /// Save the dictionary image to file `path`.
+    fn save_image(&mut self, path: &str) -> result::Result<(), Exception> {
+        let image = self.image();
+        fs::write(path, image).map_err(|_| FILE_IO_EXCEPTION)
+    }
+
+    /// Replace the dictionary with the image in file `path`.
+    fn load_image(&mut self, path: &str) -> result::Result<(), Exception> {
+        let image = fs::read(path).map_err(|_| FILE_IO_EXCEPTION)?;
+        self.restore_image(&image)
+    }
+
+    /// Run-time: ( a-addr -- )
+    ///
+    /// Reserve one cell of data space and store `a-addr` in it. Unlike `,`,
+    /// the cell is relocated when the dictionary image is restored.
+    fn address_comma(&mut self) {
+        let here = self.data_space().here();
+        self.comma();
+        if self.last_error().is_none() {
+            self.data_space().mark_address(here);
+        }
+    }
+
+    /// Run-time: ( c-addr u -- ior )
+    ///
+    /// Save the dictionary image to the file named by `c-addr u`.
+    fn p_save_image(&mut self) {
+        let (caddr, u) = self.s_stack().pop2();
+        let (caddr, u) = (caddr as usize, u as usize);
+        if self.data_space().start() <= caddr && caddr + u <= self.data_space().limit() {
+            let path = unsafe { self.data_space().str_from_raw_parts(caddr, u) }.to_string();
+            let ior = match self.save_image(&path) {
+                Ok(()) => 0,
+                Err(e) => e.into(),
+            };
+            self.s_stack().push(ior);
+        } else {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Image;
+    use core::Core;
+    use exception::{Exception, INVALID_IMAGE, UNDEFINED_WORD};
+    use memory::Memory;
+    use mock_vm::VM;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_save_load_image() {
+        let path = env::temp_dir().join(format!("rtf-test-{}.img", std::process::id()));
+        let path = path.to_str().expect("path");
+        {
+            let vm = &mut VM::new();
+            vm.set_source(
+                "
+                variable v  42 v !
+                create list  here a,
+                : list-end   [ list cell+ ] aliteral ;
+                3 constant three
+                : 2const   create , , does> 2@ ;
+                1 2 2const one-two
+                vocabulary extra  also extra definitions
+                : sum ( n -- n' )   0 swap 0 ?do i + loop ;
+                : greet   s\" hi\" type ;
+                also forth definitions previous
+                : jog   s\" Jog limit\" ;  jog exception constant jog-limit
+                256 heap  100 allocate throw aconstant buf
+            ",
+            );
+            vm.evaluate_input();
+            assert_eq!(vm.last_error(), None);
+            assert_eq!(vm.save_image(path), Ok(()));
+        }
+        let vm = &mut VM::from_image(path).expect("image");
+        fs::remove_file(path).expect("remove");
+        vm.set_source("v @  three  one-two  list dup @ =  list-end list cell+ =  4 sum  greet");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [42, 3, 1, 2, -1, -1, 6]);
+        vm.s_stack().reset();
+        assert_eq!(vm.output_buffer().as_ref().unwrap(), "hi");
+        assert_eq!(
+            vm.exceptions().description(Exception::from(-4096)),
+            "Jog limit"
+        );
+        // The heap is restored.
+        vm.set_source("buf free");
+        vm.evaluate_input();
+        assert_eq!(vm.s_stack().pop(), 0);
+        vm.s_stack().reset();
+        // Word lists and search order are restored.
+        vm.set_source("previous sum");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(UNDEFINED_WORD));
+        vm.reset();
+        vm.clear_stacks();
+        // Words can be defined after loading.
+        vm.set_source(": five   2 three + ;  five  here v !  v @ here =");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [5, -1]);
+    }
+
+    #[test]
+    fn test_invalid_image() {
+        let vm = &mut VM::new();
+        let mut image = vm.image();
+        // Fingerprint mismatch
+        vm.add_primitive("extra", Core::noop);
+        assert_eq!(vm.restore_image(&image), Err(INVALID_IMAGE));
+        let vm = &mut VM::new();
+        let here = vm.data_space().here();
+        let len = image.len();
+        image.truncate(len - 1);
+        assert_eq!(vm.restore_image(&image), Err(INVALID_IMAGE));
+        assert_eq!(vm.restore_image(b"garbage"), Err(INVALID_IMAGE));
+        assert_eq!(vm.data_space().here(), here);
+        // A cell marked as an address past the data space.
+        vm.set_source("create list  here a,");
+        vm.evaluate_input();
+        let mut image = vm.image();
+        let len = vm.data_space().here() - vm.data_space().start();
+        let at = 48 + len + 8 + 16 * vm.data_space().code().len();
+        assert_eq!(image[at..at + 8], 1u64.to_le_bytes());
+        image[at + 8..at + 16].copy_from_slice(&(len as u64).to_le_bytes());
+        let vm = &mut VM::new();
+        let here = vm.data_space().here();
+        assert_eq!(vm.restore_image(&image), Err(INVALID_IMAGE));
+        assert_eq!(vm.data_space().here(), here);
+    }
+    #[test]
+    fn test_unmarked_address() {
+        let vm = &mut VM::new();
+        vm.set_source("create buf 16 allot  buf constant p");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let image = vm.image();
+        // Elsewhere, `p` would point into the old data space.
+        let other = &mut VM::new();
+        let here = other.data_space().here();
+        assert_eq!(other.restore_image(&image), Err(INVALID_IMAGE));
+        assert_eq!(other.data_space().here(), here);
+        // At the same address, it does not.
+        assert_eq!(vm.restore_image(&image), Ok(()));
+        vm.set_source("p buf =");
+        vm.evaluate_input();
+        assert_eq!(vm.s_stack().as_slice(), [-1]);
+        // Constants defined by `aconstant` are relocated.
+        let vm = &mut VM::new();
+        vm.set_source("create buf 16 allot  buf aconstant p");
+        vm.evaluate_input();
+        let image = vm.image();
+        assert_eq!(other.restore_image(&image), Ok(()));
+        other.set_source("p buf =");
+        other.evaluate_input();
+        assert_eq!(other.s_stack().as_slice(), [-1]);
+    }
+}
@@ -11,18 +11,31 @@
 extern crate approx;
 pub extern crate hibitset;
 
+pub mod block;
 pub mod core;
+pub mod coverage;
+pub mod debugger;
+pub mod double;
 pub mod env;
 pub mod exception;
 pub mod facility;
 pub mod file_access;
 pub mod float;
+pub mod image;
 pub mod loader;
+pub mod locals;
 pub mod memory;
+pub mod memory_allocation;
 pub mod mock_vm;
+pub mod optimizer;
 pub mod output;
 pub(crate) mod parser;
+pub mod profiler;
+pub mod queue;
+pub mod search_order;
+pub mod string;
 pub mod tools;
+pub mod trace;
 pub mod units;
 
 use core::Core;
@@ -32,6 +45,7 @@ use std::result;
 
 pub const TRUE: isize = -1;
 pub const FALSE: isize = 0;
+/// Default number of tasks.
 pub const NUM_TASKS: usize = 5;
 
 pub type Result = result::Result<(), Exception>;
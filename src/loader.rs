@@ -4,13 +4,42 @@ use core::Core;
 use exception::{Exception, FILE_IO_EXCEPTION, INVALID_NUMERIC_ARGUMENT};
 use memory::Memory;
 use output::Output;
-use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Cursor;
 
 pub struct Source {
-    reader: BufReader<File>,
+    reader: Box<dyn BufRead>,
     path: String,
+    block: usize,
+    /// Number of lines read.
+    line: usize,
+}
+
+impl Source {
+    /// Source of the text of block `u`.
+    pub fn from_block(u: usize, text: String) -> Source {
+        Source {
+            reader: Box::new(Cursor::new(text.into_bytes())),
+            path: format!("block {}", u),
+            block: u,
+            line: 0,
+        }
+    }
+
+    /// Block number of the source, 0 if the source is not a block.
+    pub fn block(&self) -> usize {
+        self.block
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Number of the line last read, starting from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
 }
 
 pub trait HasLoader: Core + Output {
@@ -41,23 +70,17 @@ pub trait HasLoader: Core + Output {
         if id > 0 && id - 1 < self.files().len() as isize {
             match self.files_mut()[id as usize - 1].take() {
                 Some(file) => {
-                    let position = self.sources().iter().position(|x| x.is_none());
-                    let reader = BufReader::new(file);
+                    let reader = Box::new(BufReader::new(file));
                     let path = String::from(unsafe {
                         self.data_space().str_from_raw_parts(caddr as _, u as _)
                     });
-                    match position {
-                        Some(sid) => {
-                            self.sources_mut()[sid] = Some(Source { reader, path });
-                            self.s_stack().push(sid as isize + 1);
-                        }
-                        None => {
-                            let sid = self.sources().len() as isize;
-                            self.s_stack().push(sid as isize + 1);
-                            self.sources_mut().push(Some(Source { reader, path }));
-                            self.lines_mut().push(Some(String::with_capacity(128)));
-                        }
-                    }
+                    let sid = self.add_source(Source {
+                        reader,
+                        path,
+                        block: 0,
+                        line: 0,
+                    });
+                    self.s_stack().push(sid);
                 }
                 None => {
                     self.abort_with(INVALID_NUMERIC_ARGUMENT);
@@ -68,6 +91,22 @@ pub trait HasLoader: Core + Output {
         }
     }
 
+    /// Add input source `source` and return its source id.
+    fn add_source(&mut self, source: Source) -> isize {
+        let position = self.sources().iter().position(|x| x.is_none());
+        match position {
+            Some(sid) => {
+                self.sources_mut()[sid] = Some(source);
+                sid as isize + 1
+            }
+            None => {
+                self.sources_mut().push(Some(source));
+                self.lines_mut().push(Some(String::with_capacity(128)));
+                self.sources().len() as isize
+            }
+        }
+    }
+
     /// ( source-id -- )
     ///
     /// Close input source.
@@ -164,6 +203,9 @@ pub trait HasLoader: Core + Output {
         let result = match source.reader.read_line(&mut line) {
             Ok(len) => {
                 let not_eof = !(len == 0);
+                if not_eof {
+                    source.line += 1;
+                }
                 if line.ends_with('\n') {
                     line.truncate(len - 1);
                     if line.ends_with('\r') {
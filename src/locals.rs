@@ -0,0 +1,370 @@
+//! Locals word set
+//!
+//! Locals are kept in a frame on the return stack of the executing task, so
+//! that they survive `pause` and every invocation of a recursive word has its
+//! own locals. The frame is created by the first local of a definition and
+//! torn down by `;`, `exit` and `does>`.
+//!
+//! ```text
+//! Return stack
+//! +--------+-------------+--------+--------+--
+//! | caller | outer frame | slot 0 | slot 1 |
+//! +--------+-------------+--------+--------+--
+//!                        ^
+//!                        |
+//!                   local frame
+//! ```
+//!
+//! A float local takes one slot, which holds the bits of the float.
+//!
+//! Example:
+//!
+//! ```text
+//! : hypot {: f: x f: y | f: r -- f: r :}   x x f* y y f* f+ to r  r fsqrt ;
+//! ```
+
+use core::Core;
+use exception::{INVALID_MEMORY_ADDRESS, UNDEFINED_WORD, UNEXPECTED_END_OF_FILE};
+use memory::Memory;
+use std::mem;
+
+pub trait Locals: Core {
+    /// Add locals primitives.
+    fn add_locals(&mut self) {
+        self.add_compile_only("_local-frame", Locals::local_frame);
+        self.add_compile_only("_local-exit", Locals::local_exit);
+        self.add_compile_only("_local@", Locals::local_fetch);
+        self.add_compile_only("_flocal@", Locals::flocal_fetch);
+        self.add_compile_only("_to-local", Locals::to_local);
+        self.add_compile_only("_fto-local", Locals::fto_local);
+        self.add_compile_only("_f>l", Locals::f_to_l);
+        self.add_compile_only("(local)", Locals::paren_local);
+        self.add_immediate_and_compile_only("{:", Locals::brace_colon);
+        self.add_immediate_and_compile_only("locals|", Locals::locals_bar);
+        self.add_immediate_and_compile_only("to", Locals::to);
+
+        self.references().idx__local_fetch = self.find("_local@").expect("_local@ undefined");
+        self.references().idx__flocal_fetch = self.find("_flocal@").expect("_flocal@ undefined");
+        self.references().idx__local_exit =
+            self.find("_local-exit").expect("_local-exit undefined");
+    }
+
+    /// Slot compiled after the current token.
+    fn slot(&mut self) -> u8 {
+        let ip = self.state().instruction_pointer;
+        let slot = unsafe { self.data_space().get_usize(ip) };
+        self.state().instruction_pointer += mem::size_of::<isize>();
+        slot as u8
+    }
+
+    /// Run-time: ( -- ) ( R: -- outer-frame )
+    ///
+    /// Create a local frame for the executing definition.
+    fn local_frame(&mut self) {
+        let outer = self.state().local_frame as isize;
+        self.r_stack().push(outer);
+        self.state().local_frame = self.r_stack().len();
+    }
+
+    /// Run-time: ( -- ) ( R: outer-frame i*x -- )
+    ///
+    /// Drop the local frame of the executing definition.
+    fn local_exit(&mut self) {
+        let frame = self.state().local_frame;
+        self.r_stack().len = frame;
+        let outer = self.r_stack().pop();
+        self.state().local_frame = outer as u8;
+    }
+
+    /// Run-time: ( -- x )
+    ///
+    /// Push the value of the local in the slot following `_local@`.
+    fn local_fetch(&mut self) {
+        let slot = self.slot();
+        let frame = self.state().local_frame;
+        let x = self.r_stack()[frame.wrapping_add(slot)];
+        self.s_stack().push(x);
+    }
+
+    /// Run-time: ( F: -- r )
+    ///
+    /// Push the value of the float local in the slot following `_flocal@`.
+    fn flocal_fetch(&mut self) {
+        let slot = self.slot();
+        let frame = self.state().local_frame;
+        let bits = self.r_stack()[frame.wrapping_add(slot)];
+        self.f_stack().push(f64::from_bits(bits as u64));
+    }
+
+    /// Run-time: ( x -- )
+    ///
+    /// Store `x` in the local in the slot following `_to-local`.
+    fn to_local(&mut self) {
+        let slot = self.slot();
+        let frame = self.state().local_frame;
+        let x = self.s_stack().pop();
+        self.r_stack()[frame.wrapping_add(slot)] = x;
+    }
+
+    /// Run-time: ( F: r -- )
+    ///
+    /// Store `r` in the float local in the slot following `_fto-local`.
+    fn fto_local(&mut self) {
+        let slot = self.slot();
+        let frame = self.state().local_frame;
+        let r = self.f_stack().pop();
+        self.r_stack()[frame.wrapping_add(slot)] = r.to_bits() as isize;
+    }
+
+    /// Run-time: ( F: r -- ) ( R: -- x )
+    ///
+    /// Move `r` to a new float local.
+    fn f_to_l(&mut self) {
+        let r = self.f_stack().pop();
+        self.r_stack().push(r.to_bits() as isize);
+    }
+
+    /// Add local `name` to the current definition, a float local if `float`
+    /// is true. Compile the creation of the local frame before the first
+    /// local and the initialization of the local from the stack.
+    fn compile_local(&mut self, name: &str, float: bool) {
+        if self.compiler().locals.is_empty() {
+            let idx = self.find("_local-frame").expect("_local-frame undefined");
+            self.compile_word(idx);
+        }
+        self.compiler_mut()
+            .locals
+            .push((name.to_ascii_lowercase(), float));
+        let idx = if float {
+            self.find("_f>l").expect("_f>l undefined")
+        } else {
+            self.references().idx_to_r
+        };
+        self.compile_word(idx);
+    }
+
+    /// Parse the next name, aborting with `UNEXPECTED_END_OF_FILE` at the
+    /// end of the input.
+    fn parse_local_name(&mut self) -> Option<String> {
+        self.parse_word();
+        let name = self.last_token().as_ref().expect("last token").clone();
+        if name.is_empty() {
+            self.abort_with(UNEXPECTED_END_OF_FILE);
+            None
+        } else {
+            Some(name)
+        }
+    }
+
+    /// Compilation: ( "arg ... [| val ...] [-- out ...] :}" -- )
+    ///
+    /// Declare locals of the current definition. Each `arg` is initialized
+    /// from the stack, the last one from the top of the stack. Each `val` is
+    /// initialized to zero. Names between `--` and `:}` are a comment. A name
+    /// preceded by `f:` is a float local, initialized from the floating-point
+    /// stack.
+    ///
+    /// Example: `: area {: w h | a -- a :}   w h * to a  a ;`
+    fn brace_colon(&mut self) {
+        let mut args = Vec::new();
+        let mut vals = Vec::new();
+        let mut in_vals = false;
+        let mut in_comment = false;
+        let mut float = false;
+        loop {
+            let name = match self.parse_local_name() {
+                Some(name) => name.to_ascii_lowercase(),
+                None => return,
+            };
+            match name.as_str() {
+                ":}" => break,
+                "--" => in_comment = true,
+                _ if in_comment => {}
+                "|" => in_vals = true,
+                "f:" => float = true,
+                _ => {
+                    if in_vals {
+                        vals.push((name, float));
+                    } else {
+                        args.push((name, float));
+                    }
+                    float = false;
+                }
+            }
+        }
+        for (name, float) in args.into_iter().rev() {
+            self.compile_local(&name, float);
+        }
+        for (name, float) in vals {
+            if float {
+                self.compile_float(0.0);
+            } else {
+                self.compile_integer(0);
+            }
+            self.compile_local(&name, float);
+        }
+    }
+
+    /// Compilation: ( "name ... |" -- )
+    ///
+    /// Declare locals of the current definition, each initialized from the
+    /// stack. The first one is initialized from the top of the stack.
+    ///
+    /// Example: `: swap' locals| b a |   b a ;`
+    fn locals_bar(&mut self) {
+        loop {
+            let name = match self.parse_local_name() {
+                Some(name) => name,
+                None => return,
+            };
+            if name == "|" {
+                break;
+            }
+            self.compile_local(&name, false);
+        }
+    }
+
+    /// Execution: ( c-addr u -- )
+    ///
+    /// Declare a local named by `c-addr u` in the current definition, which
+    /// is initialized from the top of the stack. `0 0 (local)` ends the
+    /// declaration and does nothing.
+    fn paren_local(&mut self) {
+        let (caddr, u) = self.s_stack().pop2();
+        if u == 0 {
+            return;
+        }
+        let limit = self.data_space().limit();
+        if u > 0
+            && self.data_space().start() <= caddr as usize
+            && u as usize <= limit.saturating_sub(caddr as usize)
+        {
+            let (caddr, u) = (caddr as usize, u as usize);
+            let name = unsafe { self.data_space().str_from_raw_parts(caddr, u) }.to_string();
+            self.compile_local(&name, false);
+        } else {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+        }
+    }
+
+    /// Compilation: ( "name" -- )
+    ///
+    /// Append the run-time semantics of storing to local `name`.
+    ///
+    /// Run-time: ( x -- ) or ( F: r -- )
+    ///
+    /// Store `x`, or `r` if `name` is a float local, in local `name`.
+    fn to(&mut self) {
+        let name = match self.parse_local_name() {
+            Some(name) => name,
+            None => return,
+        };
+        match self.compiler().find_local(&name) {
+            Some((slot, float)) => {
+                let idx = if float {
+                    self.find("_fto-local").expect("_fto-local undefined")
+                } else {
+                    self.find("_to-local").expect("_to-local undefined")
+                };
+                self.compile_word(idx);
+                self.data_space().compile_usize(slot);
+            }
+            None => self.abort_with(UNDEFINED_WORD),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::Core;
+    use exception::{INVALID_MEMORY_ADDRESS, UNDEFINED_WORD};
+    use mock_vm::VM;
+
+    #[test]
+    fn test_brace_colon() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : t1 {: a b | c -- d :}   a b - to c  c c * a ;
+            : t2 {: n :}   n 1 > if n 1- recurse n * exit then 1 ;
+            : t3 {: f: x f: y | f: r :}   x x f* y y f* f+ to r  r fsqrt ;
+            : t4 {: a :}   a 3 0 do i + loop a ;
+            7 4 t1  5 t2  3e 4e t3  10 t4
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [9, 7, 120, 13, 10]);
+        assert_eq!(vm.f_stack().as_slice(), [5.0]);
+        assert_eq!(vm.state().local_frame, 0);
+        assert_eq!(vm.r_stack().len(), 0);
+        assert!(vm.compiler().find_local("a").is_none());
+        vm.s_stack().reset();
+        // Locals are not visible after the definition.
+        vm.set_source(": t5   a ;");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(UNDEFINED_WORD));
+    }
+
+    #[test]
+    fn test_locals_bar() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : t1 locals| b a |   a b - ;
+            : name   s\" x\" ;
+            : declare   name (local)  0 0 (local) ; immediate
+            : t2 declare   x x * ;
+            1 5 t1  6 t2
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-4, 36]);
+    }
+
+    #[test]
+    fn test_local_name_overflow() {
+        let vm = &mut VM::new();
+        vm.set_source(": bad   here 1+ -1 (local) ; immediate  : t3 bad ;");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_MEMORY_ADDRESS));
+    }
+
+    #[test]
+    fn test_locals_exit_does_catch() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : t1 {: a b :}   a 0= if b exit then a b + ;
+            : pair {: a b :}   create a , b ,  does> {: addr :}  addr @ addr cell+ @ ;
+            3 4 pair p
+            : t2 {: n :}   n 0= if -9 throw then n ;
+            : t3 {: x :}   0 ['] t2 catch nip x ;
+            0 5 t1  2 5 t1  p  8 t3
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [5, 7, 3, 4, -9, 8]);
+        assert_eq!(vm.state().local_frame, 0);
+        assert_eq!(vm.r_stack().len(), 0);
+    }
+
+    #[test]
+    fn test_locals_across_pause() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            variable result
+            : job {: a b :}   pause a b - result ! ;
+            : spawned   10 3 job ;
+            : t {: x y :}   ['] spawned spawn drop  pause pause  x y + ;
+            1 2 t  result @
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [3, 7]);
+    }
+}
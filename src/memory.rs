@@ -9,12 +9,17 @@ use std::slice;
 pub struct SystemVariables {
     null: isize,
     base: isize,
+    blk: isize,
 }
 
 impl SystemVariables {
     pub fn base_addr(&self) -> usize {
         &self.base as *const _ as usize
     }
+
+    pub fn blk_addr(&self) -> usize {
+        &self.blk as *const _ as usize
+    }
 }
 
 #[allow(dead_code)]
@@ -23,6 +28,13 @@ pub struct DataSpace {
     layout: Layout,
     cap: usize,
     len: usize,
+    code: Vec<(usize, usize)>,
+    /// One bit per cell, set if the cell may hold code, to find quickly
+    /// that a cell holds none.
+    code_cells: Vec<u64>,
+    addresses: Vec<usize>,
+    heap: (usize, usize),
+    blocks: (usize, usize),
     marker: marker::PhantomData<SystemVariables>,
 }
 
@@ -46,10 +58,16 @@ impl DataSpace {
             layout,
             cap,
             len: mem::size_of::<SystemVariables>(),
+            code: Vec::new(),
+            code_cells: vec![0; (cap / mem::size_of::<usize>()).div_ceil(64)],
+            addresses: Vec::new(),
+            heap: (0, 0),
+            blocks: (0, 0),
             marker: marker::PhantomData,
         };
         result.system_variables_mut().null = 0;
         result.system_variables_mut().base = 10;
+        result.system_variables_mut().blk = 0;
         result
     }
 
@@ -62,6 +80,152 @@ impl DataSpace {
     pub fn system_variables_mut(&mut self) -> &mut SystemVariables {
         unsafe { &mut *(self.inner.offset(0) as *mut SystemVariables) }
     }
+
+    // Code
+
+    /// Mark addresses from `start` up to but not including `end` as code.
+    ///
+    /// Code holds word headers and compiled threads, and cannot be written
+    /// by Forth programs.
+    pub fn mark_code(&mut self, start: usize, end: usize) {
+        let mut s = self.offset(start);
+        let mut e = self.offset(end);
+        if s >= e {
+            return;
+        }
+        // Ranges are sorted and disjoint. Merge those which overlap or
+        // touch the new one.
+        let i = self.code.partition_point(|r| r.1 < s);
+        let mut j = i;
+        while j < self.code.len() && self.code[j].0 <= e {
+            s = s.min(self.code[j].0);
+            e = e.max(self.code[j].1);
+            j += 1;
+        }
+        self.code.splice(i..j, Some((s, e)));
+        self.mark_code_cells(s, e);
+    }
+
+    /// Is any address from `start` up to but not including `end` code?
+    #[inline]
+    pub fn is_code(&self, start: usize, end: usize) -> bool {
+        let s = self.offset(start);
+        let e = self.offset(end);
+        // Stores of a cell or a character are the most frequent, and most
+        // of them are into cells without code.
+        if s < e
+            && e - s <= mem::size_of::<usize>()
+            && !self.is_code_cell(s)
+            && !self.is_code_cell(e - 1)
+        {
+            return false;
+        }
+        let i = self.code.partition_point(|r| r.1 <= s);
+        s < e && i < self.code.len() && self.code[i].0 < e
+    }
+
+    /// May the cell holding offset `offset` hold code?
+    #[inline]
+    fn is_code_cell(&self, offset: usize) -> bool {
+        let cell = offset / mem::size_of::<usize>();
+        self.code_cells
+            .get(cell / 64)
+            .is_some_and(|&bits| bits & (1 << (cell % 64)) != 0)
+    }
+
+    /// Mark the cells holding offsets from `s` up to but not including `e`
+    /// as holding code.
+    fn mark_code_cells(&mut self, s: usize, e: usize) {
+        let cell = mem::size_of::<usize>();
+        for c in s / cell..e.div_ceil(cell) {
+            if let Some(bits) = self.code_cells.get_mut(c / 64) {
+                *bits |= 1 << (c % 64);
+            }
+        }
+    }
+
+    /// Clear the cells which begin at offsets from `s` up to but not
+    /// including `e`.
+    fn clear_code_cells(&mut self, s: usize, e: usize) {
+        let cell = mem::size_of::<usize>();
+        for c in s.div_ceil(cell)..e.div_ceil(cell) {
+            if let Some(bits) = self.code_cells.get_mut(c / 64) {
+                *bits &= !(1 << (c % 64));
+            }
+        }
+    }
+
+    /// Mark the cells holding code again after code is replaced.
+    fn remark_code_cells(&mut self) {
+        for bits in self.code_cells.iter_mut() {
+            *bits = 0;
+        }
+        for k in 0..self.code.len() {
+            let (s, e) = self.code[k];
+            self.mark_code_cells(s, e);
+        }
+    }
+
+    /// Code as sorted ranges of offsets from the start.
+    pub fn code(&self) -> &[(usize, usize)] {
+        &self.code
+    }
+
+    /// Replace code with sorted and disjoint ranges of offsets `code`.
+    pub fn set_code(&mut self, code: Vec<(usize, usize)>) {
+        self.code = code;
+        self.remark_code_cells();
+    }
+
+    // Addresses
+
+    /// Mark the cell at `pos` as holding an address in data space.
+    ///
+    /// Marked cells are relocated when a dictionary image is restored.
+    pub fn mark_address(&mut self, pos: usize) {
+        let offset = self.offset(pos);
+        if let Err(i) = self.addresses.binary_search(&offset) {
+            self.addresses.insert(i, offset);
+        }
+    }
+
+    /// Offsets from the start of the cells marked as holding addresses,
+    /// sorted.
+    pub fn addresses(&self) -> &[usize] {
+        &self.addresses
+    }
+
+    /// Replace the cells marked as holding addresses with sorted offsets
+    /// `addresses`.
+    pub fn set_addresses(&mut self, addresses: Vec<usize>) {
+        self.addresses = addresses;
+    }
+
+    // Heap
+
+    /// Range of offsets from the start occupied by the heap of
+    /// `ALLOCATE`, `(0, 0)` if there is no heap.
+    pub fn heap(&self) -> (usize, usize) {
+        self.heap
+    }
+
+    /// Set the range of offsets occupied by the heap.
+    pub fn set_heap(&mut self, heap: (usize, usize)) {
+        self.heap = heap;
+    }
+
+    // Blocks
+
+    /// Range of offsets from the start occupied by the block buffers,
+    /// `(0, 0)` if there are no block buffers.
+    pub fn blocks(&self) -> (usize, usize) {
+        self.blocks
+    }
+
+    /// Set the range of offsets occupied by the block buffers.
+    pub fn set_blocks(&mut self, blocks: (usize, usize)) {
+        self.blocks = blocks;
+    }
 }
 
 impl Drop for DataSpace {
@@ -93,7 +257,26 @@ impl Memory for DataSpace {
         // here is allowed to be 1 place after the last memory address.
         if self.start() <= pos && pos <= self.limit() {
             let len = pos as isize - self.start() as isize;
-            self.len = len as usize;
+            let len = len as usize;
+            if len < self.len {
+                // Released space is no longer code.
+                self.code.retain(|r| r.0 < len);
+                if let Some(last) = self.code.last_mut() {
+                    last.1 = last.1.min(len);
+                }
+                let old_len = self.len;
+                self.clear_code_cells(len, old_len);
+                self.addresses
+                    .retain(|&a| a + mem::size_of::<usize>() <= len);
+                // So are the heap and the block buffers.
+                if len < self.heap.1 {
+                    self.heap = (0, 0);
+                }
+                if len < self.blocks.1 {
+                    self.blocks = (0, 0);
+                }
+            }
+            self.len = len;
             Ok(())
         } else {
             Err(INVALID_MEMORY_ADDRESS)
@@ -118,6 +301,19 @@ pub trait Memory {
         self.start() <= pos && pos < self.limit()
     }
 
+    /// Offset of address `pos` from the start.
+    ///
+    /// Compiled code holds offsets instead of addresses, so that it does not
+    /// depend on where the memory is allocated.
+    fn offset(&self, pos: usize) -> usize {
+        pos.wrapping_sub(self.start())
+    }
+
+    /// Address at `offset` from the start.
+    fn address(&self, offset: usize) -> usize {
+        self.start().wrapping_add(offset)
+    }
+
     /// Next free space
     fn here(&self) -> usize;
 
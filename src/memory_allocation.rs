@@ -0,0 +1,551 @@
+//! Memory-Allocation word set
+//!
+//! `ALLOCATE`, `FREE` and `RESIZE` work on a heap of fixed size carved out
+//! of the data space by `heap`. All of them take bounded time, so that they
+//! can be used by real-time tasks.
+//!
+//! Free blocks are kept in segregated free lists, one for each power of two,
+//! with a bitmap telling which lists are not empty. Allocation takes the
+//! first block of the smallest non-empty list whose blocks are all large
+//! enough and splits it. Freed blocks are merged with free neighbours, found
+//! with the sizes in the header and the footer of every block.
+//!
+//! ```text
+//! Heap
+//! +-------+--------+----------+---------+---------+-----+----------+
+//! | lists | bitmap | prologue | block 0 | block 1 | ... | epilogue |
+//! +-------+--------+----------+---------+---------+-----+----------+
+//!
+//! Block
+//! +------+------+------+-----+------+
+//! | size | next | prev | ... | size |
+//! +------+------+------+-----+------+
+//!        ^
+//!        |
+//!        a-addr
+//! ```
+//!
+//! Sizes include the header and the footer, and have the lowest bit set if
+//! the block is in use. Links of free blocks are offsets from the start of
+//! data space, 0 if there is no such block.
+//!
+//! As the heap lies in data space, a program may overwrite sizes and links.
+//! Every size and link is checked against the bounds of the heap before it
+//! is followed, and `allocate`, `free` and `resize` fail if the heap is
+//! corrupted.
+
+use core::Core;
+use exception::{Exception, ALLOCATE, DICTIONARY_OVERFLOW, FREE, INVALID_NUMERIC_ARGUMENT, RESIZE};
+use memory::{DataSpace, Memory};
+use std::mem;
+use std::ptr;
+use std::result;
+
+const USED: usize = 1;
+
+/// Number of free lists, one for each bit of a cell.
+const LISTS: usize = mem::size_of::<usize>() * 8;
+
+/// Statistics of the heap.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HeapStats {
+    /// Bytes which can be allocated, in all free blocks.
+    pub free: usize,
+    /// Largest size which can be allocated at once.
+    pub largest: usize,
+    /// Number of free blocks.
+    pub free_blocks: usize,
+    /// Number of blocks in use.
+    pub used_blocks: usize,
+}
+
+pub trait MemoryAllocation: Core {
+    /// Add memory-allocation primitives.
+    fn add_memory_allocation(&mut self) {
+        self.add_primitive("heap", MemoryAllocation::p_heap);
+        self.add_primitive("allocate", MemoryAllocation::p_allocate);
+        self.add_primitive("free", MemoryAllocation::p_free);
+        self.add_primitive("resize", MemoryAllocation::p_resize);
+        self.add_primitive("heap-stats", MemoryAllocation::p_heap_stats);
+    }
+
+    /// Cell at `addr`.
+    fn heap_get(&mut self, addr: usize) -> usize {
+        unsafe { self.data_space().get_usize(addr) }
+    }
+
+    /// Store `v` in the cell at `addr`.
+    fn heap_put(&mut self, v: usize, addr: usize) {
+        unsafe { self.data_space().put_usize(v, addr) }
+    }
+
+    /// Start and end addresses of the heap, `None` if there is no heap.
+    fn heap_range(&mut self) -> Option<(usize, usize)> {
+        let (start, end) = self.data_space().heap();
+        if start == end {
+            None
+        } else {
+            Some((
+                self.data_space().address(start),
+                self.data_space().address(end),
+            ))
+        }
+    }
+
+    /// Create a heap of at least `size` bytes at `here`, replacing the
+    /// previous heap if any.
+    fn create_heap(&mut self, size: usize) -> result::Result<(), Exception> {
+        let cell = mem::size_of::<usize>();
+        if size > self.data_space().capacity() {
+            return Err(DICTIONARY_OVERFLOW);
+        }
+        let size = DataSpace::aligned(size);
+        if size < 4 * cell {
+            return Err(INVALID_NUMERIC_ARGUMENT);
+        }
+        self.data_space().align();
+        let start = self.data_space().here();
+        let first = start + (LISTS + 2) * cell;
+        let end = match first.checked_add(size + cell) {
+            Some(end) if end <= self.data_space().limit() => end,
+            _ => return Err(DICTIONARY_OVERFLOW),
+        };
+        self.data_space().set_here(end)?;
+        for i in 0..LISTS + 1 {
+            self.heap_put(0, start + i * cell);
+        }
+        // Prologue footer and epilogue header are never free.
+        self.heap_put(USED, first - cell);
+        self.heap_put(USED, end - cell);
+        let start = self.data_space().offset(start);
+        let end = self.data_space().offset(end);
+        self.data_space().set_heap((start, end));
+        // All free lists are empty.
+        self.insert_free(first, size).expect("empty heap");
+        Ok(())
+    }
+
+    /// Size in bytes of a block holding `u` bytes, `None` if too large.
+    fn block_size(&self, u: usize) -> Option<usize> {
+        let cell = mem::size_of::<usize>();
+        if u > isize::MAX as usize / 2 {
+            None
+        } else {
+            Some(DataSpace::aligned(u + 2 * cell).max(4 * cell))
+        }
+    }
+
+    /// Free list of blocks of `size` bytes.
+    fn list_of(size: usize) -> usize {
+        LISTS - 1 - size.leading_zeros() as usize
+    }
+
+    /// Header of the block at `b`, `None` if there is no such block in the
+    /// heap.
+    fn heap_block(&mut self, b: usize) -> Option<usize> {
+        let cell = mem::size_of::<usize>();
+        let (start, end) = self.heap_range()?;
+        let first = start + (LISTS + 2) * cell;
+        if b & (cell - 1) != 0 || b < first || b > end - 5 * cell {
+            return None;
+        }
+        let header = self.heap_get(b);
+        let size = header & !USED;
+        if size & (cell - 1) != 0
+            || size < 4 * cell
+            || size > end - cell - b
+            || self.heap_get(b + size - cell) != header
+        {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// Size of free block `b`, `None` if there is no such free block.
+    fn free_size(&mut self, b: usize) -> Option<usize> {
+        match self.heap_block(b) {
+            Some(header) if header & USED == 0 => Some(header),
+            _ => None,
+        }
+    }
+
+    /// Free block linked by `offset` and its size, `None` if there is no
+    /// such free block.
+    fn linked(&mut self, offset: usize) -> Option<(usize, usize)> {
+        let b = self.data_space().address(offset);
+        self.free_size(b).map(|size| (b, size))
+    }
+
+    /// Add free block `b` of `size` bytes to its free list. `None` if the
+    /// list is corrupted.
+    fn insert_free(&mut self, b: usize, size: usize) -> Option<()> {
+        let cell = mem::size_of::<usize>();
+        let (start, _) = self.heap_range()?;
+        let k = Self::list_of(size);
+        let head = start + k * cell;
+        let next = self.heap_get(head);
+        let next_block = if next != 0 {
+            Some(self.linked(next)?.0)
+        } else {
+            None
+        };
+        self.heap_put(size, b);
+        self.heap_put(size, b + size - cell);
+        self.heap_put(next, b + cell);
+        self.heap_put(0, b + 2 * cell);
+        let offset = self.data_space().offset(b);
+        if let Some(next) = next_block {
+            self.heap_put(offset, next + 2 * cell);
+        }
+        self.heap_put(offset, head);
+        let bitmap = self.heap_get(start + LISTS * cell);
+        self.heap_put(bitmap | 1 << k, start + LISTS * cell);
+        Some(())
+    }
+
+    /// Remove free block `b` of `size` bytes from its free list. `None` if
+    /// the links of `b` are corrupted.
+    fn remove_free(&mut self, b: usize, size: usize) -> Option<()> {
+        let cell = mem::size_of::<usize>();
+        let (start, _) = self.heap_range()?;
+        let k = Self::list_of(size);
+        let head = start + k * cell;
+        let next = self.heap_get(b + cell);
+        let prev = self.heap_get(b + 2 * cell);
+        let next_block = if next != 0 {
+            Some(self.linked(next)?.0)
+        } else {
+            None
+        };
+        let prev_block = if prev != 0 {
+            Some(self.linked(prev)?.0)
+        } else {
+            None
+        };
+        match prev_block {
+            Some(prev) => self.heap_put(next, prev + cell),
+            None => self.heap_put(next, head),
+        }
+        if let Some(next) = next_block {
+            self.heap_put(prev, next + 2 * cell);
+        }
+        if self.heap_get(head) == 0 {
+            let bitmap = self.heap_get(start + LISTS * cell);
+            self.heap_put(bitmap & !(1 << k), start + LISTS * cell);
+        }
+        Some(())
+    }
+
+    /// Mark block `b` of `size` bytes in use. If `b` is larger than `need`
+    /// bytes, split the rest off as a free block. `None` if the heap is
+    /// corrupted.
+    fn use_block(&mut self, b: usize, size: usize, need: usize) -> Option<()> {
+        let cell = mem::size_of::<usize>();
+        let rest = size - need;
+        let size = if rest >= 4 * cell { need } else { size };
+        self.heap_put(size | USED, b);
+        self.heap_put(size | USED, b + size - cell);
+        if size == need && rest > 0 {
+            self.release(b + need, rest)?;
+        }
+        Some(())
+    }
+
+    /// Free block `b` of `size` bytes, merging it with free neighbours.
+    /// `None` if the neighbours are corrupted.
+    fn release(&mut self, mut b: usize, mut size: usize) -> Option<()> {
+        let cell = mem::size_of::<usize>();
+        let left = self.heap_get(b - cell);
+        let right = self.heap_get(b + size);
+        if left & USED == 0 && self.free_size(b.checked_sub(left)?) != Some(left) {
+            return None;
+        }
+        if right & USED == 0 && self.free_size(b + size) != Some(right) {
+            return None;
+        }
+        if left & USED == 0 {
+            b -= left;
+            size += left;
+            self.remove_free(b, left)?;
+        }
+        if right & USED == 0 {
+            self.remove_free(b + size, right)?;
+            size += right;
+        }
+        self.insert_free(b, size)
+    }
+
+    /// Block and its size of `a-addr` returned by `allocate`.
+    fn block_of(&mut self, addr: usize) -> Option<(usize, usize)> {
+        let cell = mem::size_of::<usize>();
+        let b = addr.wrapping_sub(cell);
+        match self.heap_block(b) {
+            Some(header) if header & USED != 0 && addr & (cell - 1) == 0 => {
+                Some((b, header & !USED))
+            }
+            _ => None,
+        }
+    }
+
+    /// Allocate `u` bytes from the heap.
+    fn allocate(&mut self, u: usize) -> result::Result<usize, Exception> {
+        let cell = mem::size_of::<usize>();
+        let (start, _) = self.heap_range().ok_or(ALLOCATE)?;
+        let need = self.block_size(u).ok_or(ALLOCATE)?;
+        // The first block of the list of `need` may be large enough,
+        // otherwise every block in list `k` and above is.
+        let head = self.heap_get(start + Self::list_of(need) * cell);
+        let fit = if head != 0 {
+            Some(self.linked(head).ok_or(ALLOCATE)?)
+        } else {
+            None
+        };
+        let (b, size) = match fit {
+            Some((b, size)) if size >= need => (b, size),
+            _ => {
+                let k = Self::list_of(need) + 1;
+                let bitmap = self.heap_get(start + LISTS * cell);
+                let lists = if k < LISTS { bitmap >> k << k } else { 0 };
+                if lists == 0 {
+                    return Err(ALLOCATE);
+                }
+                let head = self.heap_get(start + lists.trailing_zeros() as usize * cell);
+                self.linked(head).ok_or(ALLOCATE)?
+            }
+        };
+        if size < need {
+            return Err(ALLOCATE);
+        }
+        self.remove_free(b, size).ok_or(ALLOCATE)?;
+        self.use_block(b, size, need).ok_or(ALLOCATE)?;
+        Ok(b + cell)
+    }
+
+    /// Free `addr` returned by `allocate` or `resize`.
+    fn free(&mut self, addr: usize) -> result::Result<(), Exception> {
+        let (b, size) = self.block_of(addr).ok_or(FREE)?;
+        self.release(b, size).ok_or(FREE)
+    }
+
+    /// Change the size of `addr` returned by `allocate` or `resize` to `u`
+    /// bytes, and return the address of the block, which may have been
+    /// moved. The contents are kept up to the lesser of the old and new
+    /// sizes.
+    fn resize(&mut self, addr: usize, u: usize) -> result::Result<usize, Exception> {
+        let cell = mem::size_of::<usize>();
+        let (b, size) = self.block_of(addr).ok_or(RESIZE)?;
+        let need = self.block_size(u).ok_or(RESIZE)?;
+        if need <= size {
+            self.use_block(b, size, need).ok_or(RESIZE)?;
+            return Ok(addr);
+        }
+        if let Some(right) = self.free_size(b + size) {
+            if size + right >= need {
+                self.remove_free(b + size, right).ok_or(RESIZE)?;
+                self.use_block(b, size + right, need).ok_or(RESIZE)?;
+                return Ok(addr);
+            }
+        }
+        let new = self.allocate(u).map_err(|_| RESIZE)?;
+        unsafe {
+            ptr::copy_nonoverlapping(addr as *const u8, new as *mut u8, size - 2 * cell);
+        }
+        self.release(b, size).ok_or(RESIZE)?;
+        Ok(new)
+    }
+
+    /// Statistics of the heap.
+    ///
+    /// Unlike allocation, this walks through all blocks of the heap, up to
+    /// the first corrupted one.
+    fn heap_stats(&mut self) -> HeapStats {
+        let cell = mem::size_of::<usize>();
+        let mut stats = HeapStats::default();
+        if let Some((start, _)) = self.heap_range() {
+            let mut b = start + (LISTS + 2) * cell;
+            while let Some(header) = self.heap_block(b) {
+                let size = header & !USED;
+                if header & USED == 0 {
+                    stats.free += size - 2 * cell;
+                    stats.largest = stats.largest.max(size - 2 * cell);
+                    stats.free_blocks += 1;
+                } else {
+                    stats.used_blocks += 1;
+                }
+                b += size;
+            }
+        }
+        stats
+    }
+
+    /// Run-time: ( u -- )
+    ///
+    /// Carve a heap of `u` bytes out of the data space for `allocate`,
+    /// replacing the previous heap if any.
+    ///
+    /// Example: `64 1024 * heap`
+    fn p_heap(&mut self) {
+        let u = self.s_stack().pop();
+        if u < 0 {
+            self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        } else if let Err(e) = self.create_heap(u as usize) {
+            self.abort_with(e);
+        }
+    }
+
+    /// Run-time: ( u -- a-addr ior )
+    ///
+    /// Allocate `u` contiguous bytes from the heap. `a-addr` is the aligned
+    /// address of the allocated space. If the allocation fails, `a-addr` is 0
+    /// and `ior` is -59.
+    fn p_allocate(&mut self) {
+        let u = self.s_stack().pop();
+        match self.allocate(u as usize) {
+            Ok(addr) => self.s_stack().push2(addr as isize, 0),
+            Err(e) => self.s_stack().push2(0, e.into()),
+        }
+    }
+
+    /// Run-time: ( a-addr -- ior )
+    ///
+    /// Return the space at `a-addr` to the heap. `ior` is -60 if `a-addr` was
+    /// not allocated by `allocate` or `resize`.
+    fn p_free(&mut self) {
+        let addr = self.s_stack().pop();
+        let ior = match self.free(addr as usize) {
+            Ok(()) => 0,
+            Err(e) => e.into(),
+        };
+        self.s_stack().push(ior);
+    }
+
+    /// Run-time: ( a-addr1 u -- a-addr2 ior )
+    ///
+    /// Change the size of the space at `a-addr1` to `u` bytes, moving it to
+    /// `a-addr2` if needed. If the operation fails, `a-addr2` is `a-addr1`
+    /// and `ior` is -61.
+    fn p_resize(&mut self) {
+        let (addr, u) = self.s_stack().pop2();
+        match self.resize(addr as usize, u as usize) {
+            Ok(new) => self.s_stack().push2(new as isize, 0),
+            Err(e) => self.s_stack().push2(addr, e.into()),
+        }
+    }
+
+    /// Run-time: ( -- u-free u-largest u-blocks )
+    ///
+    /// Free space of the heap in bytes, the largest size which can be
+    /// allocated at once and the number of free blocks. The heap is
+    /// fragmented if `u-largest` is much less than `u-free`.
+    fn p_heap_stats(&mut self) {
+        let stats = self.heap_stats();
+        self.s_stack().push3(
+            stats.free as isize,
+            stats.largest as isize,
+            stats.free_blocks as isize,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeapStats, MemoryAllocation};
+    use core::Core;
+    use exception::{ALLOCATE, FREE, RESIZE};
+    use mock_vm::VM;
+
+    #[test]
+    fn test_allocate_free() {
+        let vm = &mut VM::new();
+        assert_eq!(vm.allocate(8), Err(ALLOCATE));
+        vm.set_source("1024 heap  heap-stats");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [1008, 1008, 1]);
+        vm.s_stack().reset();
+        let a = vm.allocate(100).expect("a");
+        let b = vm.allocate(200).expect("b");
+        let c = vm.allocate(300).expect("c");
+        assert!(a < b && b < c);
+        assert_eq!(vm.free(b), Ok(()));
+        assert_eq!(vm.free(b), Err(FREE));
+        assert_eq!(vm.free(b + 8), Err(FREE));
+        let stats = vm.heap_stats();
+        assert_eq!(stats.free_blocks, 2);
+        assert_eq!(stats.used_blocks, 2);
+        // The freed block is reused.
+        assert_eq!(vm.allocate(150), Ok(b));
+        assert_eq!(vm.allocate(2000), Err(ALLOCATE));
+        for &addr in &[a, b, c] {
+            assert_eq!(vm.free(addr), Ok(()));
+        }
+        // Free blocks are merged.
+        assert_eq!(
+            vm.heap_stats(),
+            HeapStats {
+                free: 1008,
+                largest: 1008,
+                free_blocks: 1,
+                used_blocks: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_resize() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            1024 heap
+            16 allocate throw  dup 42 swap !  constant a
+            16 allocate throw  constant b
+            a 100 resize throw  constant a2
+            a2 a <>  a2 @
+            b 8 resize throw  b =
+            b -1 resize nip
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, 42, -1, RESIZE.into()]);
+        vm.s_stack().reset();
+        // Grow in place into the free neighbour.
+        vm.set_source("a2 free throw  b 200 resize throw b =  heap-stats nip nip");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, 2]);
+    }
+    #[test]
+    fn test_corrupted_heap() {
+        let vm = &mut VM::new();
+        // Footer of the first block overwritten.
+        vm.set_source(
+            "4096 heap  16 allocate drop  16 allocate drop  swap 100000000000000 swap 16 + !  free",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [FREE.into()]);
+        vm.s_stack().reset();
+        // Links of free blocks overwritten.
+        vm.set_source(
+            "
+            4096 heap
+            32 allocate throw constant a
+            32 allocate throw constant b
+            32 allocate throw constant c
+            a free throw  100000000000000 a !
+            100000000000000 c 48 + !
+            b free
+            32 allocate nip
+            c 200 resize nip
+            heap-stats nip nip
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(
+            vm.s_stack().as_slice(),
+            [FREE.into(), ALLOCATE.into(), RESIZE.into(), 2]
+        );
+    }
+}
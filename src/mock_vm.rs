@@ -1,16 +1,29 @@
-use core::{Control, Core, ForwardReferences, Stack, State, Wordlist};
+use block::Block;
+use core::{Compiler, Control, Core, ForwardReferences, Instrumentation, Stack, State, Wordlist};
+use coverage::Coverage;
+use debugger::Debugger;
+use double::Double;
 use env::Environment;
-use exception::Exception;
+use exception::{Exception, Exceptions};
 use facility::Facility;
 use file_access::FileAccess;
 use float::Float;
 use hibitset::BitSet;
+use image::Image;
 use loader::HasLoader;
 use loader::Source;
+use locals::Locals;
 use memory::DataSpace;
+use memory_allocation::MemoryAllocation;
+use optimizer::Optimizer;
 use output::Output;
+use profiler::Profiler;
+use queue::Queue;
+use search_order::SearchOrder;
 use std::fs::File;
+use string::Strings;
 use tools::Tools;
+use trace::Tracer;
 use units::Units;
 use NUM_TASKS;
 
@@ -23,6 +36,7 @@ const LABEL_COUNT: u32 = 1000;
 /// dictionary and output buffer owned by virtual machine.
 pub struct Task {
     awake: bool,
+    in_use: bool,
     state: State,
     s_stk: Stack<isize>,
     r_stk: Stack<isize>,
@@ -39,6 +53,7 @@ impl Task {
     pub fn new_background() -> Task {
         Task {
             awake: false,
+            in_use: false,
             state: State::new(),
             s_stk: Stack::new(0x12345678),
             r_stk: Stack::new(0x12345678),
@@ -62,7 +77,7 @@ impl Task {
 /// Virtual machine
 pub struct VM {
     current_task: usize,
-    tasks: [Task; NUM_TASKS],
+    tasks: Vec<Task>,
     last_error: Option<Exception>,
     handler: usize,
     wordlist: Wordlist<VM>,
@@ -70,6 +85,9 @@ pub struct VM {
     tkn: Option<String>,
     outbuf: Option<String>,
     hldbuf: String,
+    exceptions: Exceptions,
+    instrumentation: Instrumentation,
+    compiler: Compiler,
     references: ForwardReferences,
     now: u64,
     forward_bitset: BitSet,
@@ -78,21 +96,41 @@ pub struct VM {
 }
 
 impl VM {
-    /// Create VM
+    /// Create VM with `NUM_TASKS` tasks.
     pub fn new() -> VM {
+        VM::with_tasks(NUM_TASKS)
+    }
+
+    /// Create VM with `num_tasks` tasks.
+    ///
+    /// There is at least one task, the operator task.
+    pub fn with_tasks(num_tasks: usize) -> VM {
+        let mut vm = VM::with_primitives(num_tasks);
+        vm.load_core_fth();
+        vm
+    }
+
+    /// Create VM with `NUM_TASKS` tasks from the image in file `path`.
+    pub fn from_image(path: &str) -> Result<VM, Exception> {
+        let mut vm = VM::with_primitives(NUM_TASKS);
+        vm.load_image(path)?;
+        Ok(vm)
+    }
+
+    /// Create VM with `num_tasks` tasks and primitives only.
+    fn with_primitives(num_tasks: usize) -> VM {
         let data_capacity = 64 * 1024;
+        let mut tasks = Vec::with_capacity(num_tasks.max(1));
+        // Only operator task has its own input buffer.
+        tasks.push(Task::new_terminal());
+        for _ in 1..num_tasks {
+            tasks.push(Task::new_background());
+        }
         let mut labels = Vec::with_capacity(LABEL_COUNT as _);
         labels.resize(LABEL_COUNT as _, 0);
         let mut vm = VM {
             current_task: 0,
-            tasks: [
-                // Only operator task has its own input buffer.
-                Task::new_terminal(),
-                Task::new_background(),
-                Task::new_background(),
-                Task::new_background(),
-                Task::new_background(),
-            ],
+            tasks,
             last_error: None,
             handler: 0,
             wordlist: Wordlist::with_capacity(1000),
@@ -100,6 +138,9 @@ impl VM {
             tkn: Some(String::with_capacity(64)),
             outbuf: Some(String::with_capacity(128)),
             hldbuf: String::with_capacity(128),
+            exceptions: Exceptions::new(),
+            instrumentation: Instrumentation::new(),
+            compiler: Compiler::new(),
             references: ForwardReferences::new(),
             now: 0,
             forward_bitset: BitSet::with_capacity(LABEL_COUNT),
@@ -107,17 +148,27 @@ impl VM {
             labels,
         };
         vm.add_core();
+        vm.add_search_order();
         vm.add_output();
+        vm.add_double();
         vm.add_tools();
+        vm.add_debugger();
+        vm.add_profiler();
+        vm.add_tracer();
+        vm.add_coverage();
+        vm.add_optimizer();
+        vm.add_locals();
         vm.add_environment();
         vm.add_facility();
+        vm.add_queue();
+        vm.add_memory_allocation();
+        vm.add_string();
+        vm.add_block();
         vm.add_float();
         vm.add_units();
         vm.add_file_access();
         vm.add_loader();
-
-        vm.load_core_fth();
-
+        vm.add_image();
         vm
     }
 
@@ -149,6 +200,24 @@ impl Core for VM {
     fn hold_buffer(&mut self) -> &mut String {
         &mut self.hldbuf
     }
+    fn exceptions(&self) -> &Exceptions {
+        &self.exceptions
+    }
+    fn exceptions_mut(&mut self) -> &mut Exceptions {
+        &mut self.exceptions
+    }
+    fn instrumentation(&self) -> &Instrumentation {
+        &self.instrumentation
+    }
+    fn instrumentation_mut(&mut self) -> &mut Instrumentation {
+        &mut self.instrumentation
+    }
+    fn compiler(&self) -> &Compiler {
+        &self.compiler
+    }
+    fn compiler_mut(&mut self) -> &mut Compiler {
+        &mut self.compiler
+    }
     fn output_buffer(&mut self) -> &mut Option<String> {
         &mut self.outbuf
     }
@@ -221,30 +290,47 @@ impl Core for VM {
     fn system_time_ns(&self) -> u64 {
         self.now
     }
+    fn num_tasks(&self) -> usize {
+        self.tasks.len()
+    }
     fn current_task(&self) -> usize {
         self.current_task
     }
     fn set_current_task(&mut self, i: usize) {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.current_task = i;
         } else {
             // Do nothing.
         }
     }
     fn awake(&self, i: usize) -> bool {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.tasks[i].awake
         } else {
             false
         }
     }
     fn set_awake(&mut self, i: usize, v: bool) {
-        if i < NUM_TASKS {
+        if i < self.tasks.len() {
             self.tasks[i].awake = v;
         } else {
             // Do nothing.
         }
     }
+    fn in_use(&self, i: usize) -> bool {
+        if i < self.tasks.len() {
+            self.tasks[i].in_use
+        } else {
+            false
+        }
+    }
+    fn set_in_use(&mut self, i: usize, v: bool) {
+        if i < self.tasks.len() {
+            self.tasks[i].in_use = v;
+        } else {
+            // Do nothing.
+        }
+    }
     fn forward_bitset(&self) -> &BitSet {
         &self.forward_bitset
     }
@@ -265,6 +351,7 @@ impl Core for VM {
     }
 }
 
+impl Double for VM {}
 impl Environment for VM {}
 impl Facility for VM {}
 impl Float for VM {}
@@ -273,3 +360,15 @@ impl FileAccess for VM {}
 impl HasLoader for VM {}
 impl Output for VM {}
 impl Tools for VM {}
+impl Debugger for VM {}
+impl Profiler for VM {}
+impl Tracer for VM {}
+impl Coverage for VM {}
+impl Optimizer for VM {}
+impl Locals for VM {}
+impl MemoryAllocation for VM {}
+impl Strings for VM {}
+impl Block for VM {}
+impl SearchOrder for VM {}
+impl Queue for VM {}
+impl Image for VM {}
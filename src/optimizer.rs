@@ -0,0 +1,257 @@
+//! Peephole optimizer
+//!
+//! While optimizing, the compiler fuses common pairs of tokens into
+//! superinstructions, executed with a single dispatch, and folds arithmetic
+//! and logic on literals into a single literal.
+//!
+//! ```text
+//! optimize-on
+//! : f ( n -- n' )   over over = if 3 4 + + then ;
+//! see f   \ : f   over over = if 7 + then ;
+//! ```
+//!
+//! `see`, `.debug` and backtraces show a superinstruction as the words
+//! fused into it.
+
+use core::Core;
+use exception::INVALID_MEMORY_ADDRESS;
+use memory::Memory;
+use std::mem;
+use {FALSE, TRUE};
+
+/// Superinstructions, with the two words fused into each.
+const SUPERINSTRUCTIONS: [(&str, &str, &str); 6] = [
+    ("_lit+", "lit", "+"),
+    ("_lit=", "lit", "="),
+    ("_over-over", "over", "over"),
+    ("_dup-0branch", "dup", "0branch"),
+    ("_r>-drop", "r>", "drop"),
+    ("_@+", "@", "+"),
+];
+
+pub trait Optimizer: Core {
+    /// Add superinstructions and optimizer primitives.
+    fn add_optimizer(&mut self) {
+        self.add_compile_only("_lit+", Optimizer::lit_plus);
+        self.add_compile_only("_lit=", Optimizer::lit_equals);
+        self.add_compile_only("_over-over", Optimizer::over_over);
+        self.add_compile_only("_dup-0branch", Optimizer::dup_zero_branch);
+        self.add_compile_only("_r>-drop", Optimizer::r_from_drop);
+        self.add_compile_only("_@+", Optimizer::fetch_plus);
+        self.add_primitive("optimize-on", Optimizer::optimize_on);
+        self.add_primitive("optimize-off", Optimizer::optimize_off);
+        for &(fused, first, second) in SUPERINSTRUCTIONS.iter() {
+            let fused = self.find(fused).expect("superinstruction");
+            let first = self.find(first).expect("first word");
+            let second = self.find(second).expect("second word");
+            self.compiler_mut()
+                .superinstructions
+                .push((fused, first, second));
+        }
+    }
+
+    /// Start or stop optimizing the code compiled from now on.
+    fn set_optimizing(&mut self, enabled: bool) {
+        self.compiler_mut().optimizing = enabled;
+        self.compiler_mut().peephole.clear();
+    }
+
+    /// Run-time: ( n1 -- n2 )
+    ///
+    /// `lit +`: add the literal following to `n1`.
+    fn lit_plus(&mut self) {
+        let ip = self.state().instruction_pointer;
+        let v = unsafe { self.data_space().get_isize(ip) };
+        let slen = self.s_stack().len;
+        let t = self.s_stack()[slen.wrapping_sub(1)];
+        self.s_stack()[slen.wrapping_sub(1)] = t.wrapping_add(v);
+        self.state().instruction_pointer += mem::size_of::<isize>();
+    }
+
+    /// Run-time: ( x -- flag )
+    ///
+    /// `lit =`: is `x` equal to the literal following?
+    fn lit_equals(&mut self) {
+        let ip = self.state().instruction_pointer;
+        let v = unsafe { self.data_space().get_isize(ip) };
+        let slen = self.s_stack().len;
+        let t = self.s_stack()[slen.wrapping_sub(1)];
+        self.s_stack()[slen.wrapping_sub(1)] = if t == v { TRUE } else { FALSE };
+        self.state().instruction_pointer += mem::size_of::<isize>();
+    }
+
+    /// Run-time: ( x1 x2 -- x1 x2 x1 x2 )
+    ///
+    /// `over over`.
+    fn over_over(&mut self) {
+        let slen = self.s_stack().len.wrapping_add(2);
+        self.s_stack().len = slen;
+        self.s_stack()[slen.wrapping_sub(1)] = self.s_stack()[slen.wrapping_sub(3)];
+        self.s_stack()[slen.wrapping_sub(2)] = self.s_stack()[slen.wrapping_sub(4)];
+    }
+
+    /// Run-time: ( x -- x )
+    ///
+    /// `dup 0branch`: continue execution at the destination following if
+    /// `x` is zero.
+    fn dup_zero_branch(&mut self) {
+        let slen = self.s_stack().len;
+        if self.s_stack()[slen.wrapping_sub(1)] == 0 {
+            self.branch();
+        } else {
+            self.state().instruction_pointer += mem::size_of::<isize>();
+        }
+    }
+
+    /// Run-time: ( -- ) ( R: x -- )
+    ///
+    /// `r> drop`.
+    fn r_from_drop(&mut self) {
+        let rlen = self.r_stack().len;
+        self.r_stack().len = rlen.wrapping_sub(1);
+    }
+
+    /// Run-time: ( n1 a-addr -- n2 )
+    ///
+    /// `@ +`: add the cell at `a-addr` to `n1`.
+    fn fetch_plus(&mut self) {
+        let t = self.s_stack().pop() as usize;
+        if self.data_space().start() < t && t + mem::size_of::<isize>() <= self.data_space().limit()
+        {
+            let value = unsafe { self.data_space().get_isize(t) };
+            let slen = self.s_stack().len;
+            let n = self.s_stack()[slen.wrapping_sub(1)];
+            self.s_stack()[slen.wrapping_sub(1)] = n.wrapping_add(value);
+        } else {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Fuse tokens into superinstructions and fold literal arithmetic in
+    /// the definitions compiled from now on.
+    fn optimize_on(&mut self) {
+        self.set_optimizing(true);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Stop optimizing.
+    fn optimize_off(&mut self) {
+        self.set_optimizing(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Optimizer;
+    use core::Core;
+    use exception::INVALID_MEMORY_ADDRESS;
+    use mock_vm::VM;
+    use tools::{Operand, Tools};
+
+    fn tokens(vm: &mut VM, name: &str) -> Vec<(String, Operand)> {
+        let xt = vm.find(name).expect("word");
+        let dfa = vm.wordlist()[xt].dfa();
+        let instructions = vm.decode_thread(dfa).expect("thread");
+        instructions
+            .into_iter()
+            .map(|ins| (vm.word_name(ins.xt), ins.operand))
+            .collect()
+    }
+
+    fn see(vm: &mut VM, name: &str) -> String {
+        vm.set_output_buffer(String::new());
+        vm.set_source(&format!("see {}", name));
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        vm.output_buffer().clone().unwrap()
+    }
+
+    const SOURCE: &str = "
+        : t1   over over = if 3 4 + + then ;
+        : t2   >r 5 = r> drop ;
+        : t3   @ + 1 2 3 * - ;
+        : t4   begin dup while 1- repeat ;
+    ";
+
+    #[test]
+    fn test_superinstructions() {
+        let vm = &mut VM::new();
+        vm.set_source("optimize-on");
+        vm.evaluate_input();
+        vm.set_source(SOURCE);
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let t1: Vec<_> = tokens(vm, "t1").into_iter().map(|t| t.0).collect();
+        assert_eq!(t1, ["_over-over", "=", "0branch", "_lit+", "exit"]);
+        assert_eq!(tokens(vm, "t1")[3].1, Operand::Cell(7));
+        let t2: Vec<_> = tokens(vm, "t2").into_iter().map(|t| t.0).collect();
+        assert_eq!(t2, [">r", "_lit=", "_r>-drop", "exit"]);
+        // Chained folding: 1 2 3 * - is 1 6 - is -5.
+        assert_eq!(tokens(vm, "t3")[1], ("lit".to_string(), Operand::Cell(-5)));
+        assert_eq!(tokens(vm, "t3")[0].0, "_@+");
+        // The branch target of repeat is before dup, so dup 0branch fuses.
+        assert_eq!(tokens(vm, "t4")[0].0, "_dup-0branch");
+        // SEE shows the words fused into superinstructions.
+        assert_eq!(see(vm, "t1"), ": t1   over over = if 7 + then ;");
+        assert_eq!(see(vm, "t2"), ": t2   >r 5 = r> drop ;");
+        assert_eq!(see(vm, "t3"), ": t3   @ + -5 ;");
+        assert_eq!(see(vm, "t4"), ": t4   begin dup while 1- repeat ;");
+        // Same results as without the optimizer.
+        vm.set_source("1 2 t1  8 5 t2  variable v 7 v !  3 v t3  3 t4");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [1, 2, 0, 10, -5, 0]);
+        vm.s_stack().reset();
+        vm.set_source("optimize-off");
+        vm.evaluate_input();
+        vm.set_source(SOURCE);
+        vm.evaluate_input();
+        vm.set_source("1 2 t1  8 5 t2  3 v t3  3 t4");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [1, 2, 0, 10, -5, 0]);
+        assert_eq!(tokens(vm, "t1")[0].0, "over");
+    }
+
+    #[test]
+    fn test_branch_targets() {
+        let vm = &mut VM::new();
+        vm.set_optimizing(true);
+        vm.set_source(
+            "
+            : t1   1 begin 2 + dup 9 > until ;
+            : t2   dup if 1 then 2 + ;
+            : t3   over [ here drop ] over ;
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        // begin is a branch target between 1 and 2, so they are not folded.
+        let t1: Vec<_> = tokens(vm, "t1").into_iter().map(|t| t.0).collect();
+        assert_eq!(t1, ["lit", "_lit+", "dup", "lit", ">", "0branch", "exit"]);
+        // then is a branch target between 1 and 2.
+        let t2: Vec<_> = tokens(vm, "t2").into_iter().map(|t| t.0).collect();
+        assert_eq!(t2, ["_dup-0branch", "lit", "_lit+", "exit"]);
+        let t3: Vec<_> = tokens(vm, "t3").into_iter().map(|t| t.0).collect();
+        assert_eq!(t3, ["over", "over", "exit"]);
+        vm.set_source("3 t1  0 t2");
+        vm.evaluate_input();
+        assert_eq!(vm.s_stack().as_slice(), [3, 11, 2]);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let vm = &mut VM::new();
+        vm.set_optimizing(true);
+        vm.set_source(": t1   1 0 @ + ;  t1");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_MEMORY_ADDRESS));
+        vm.set_output_buffer(String::new());
+        vm.dot_backtrace();
+        let backtrace = vm.output_buffer().clone().unwrap();
+        assert!(backtrace.starts_with("backtrace: @ + "), "{}", backtrace);
+    }
+}
@@ -1,9 +1,19 @@
 //! Words output to console
 
 use core::Core;
-use exception::{STACK_UNDERFLOW, UNSUPPORTED_OPERATION};
+use double::{from_udouble, to_udouble};
+use exception::{
+    INVALID_MEMORY_ADDRESS, PICTURED_NUMERIC_OUTPUT_STRING_OVERFLOW, STACK_UNDERFLOW,
+    UNSUPPORTED_OPERATION,
+};
 use memory::Memory;
 use std::fmt::Write;
+use std::mem;
+
+/// Offset of PAD from HERE, see `pad` in core.fth.
+///
+/// The pictured numeric output string is made available right below PAD.
+const PAD_OFFSET: usize = 512;
 
 /// Types that can output to console.
 pub trait Output: Core {
@@ -13,12 +23,22 @@ pub trait Output: Core {
         self.add_primitive("type", Output::p_type);
         self.add_immediate_and_compile_only("s\"", Output::s_quote);
         self.add_immediate_and_compile_only(".\"", Output::dot_quote);
+        self.add_immediate_and_compile_only("abort\"", Output::abort_quote);
         self.add_immediate(".(", Output::dot_paren);
         self.add_primitive(".r", Output::dot_r);
+        self.add_primitive("u.r", Output::u_dot_r);
+        self.add_primitive("<#", Output::less_number_sign);
+        self.add_primitive("#", Output::number_sign);
+        self.add_primitive("#s", Output::number_sign_s);
+        self.add_primitive("#>", Output::number_sign_greater);
+        self.add_primitive("hold", Output::hold);
+        self.add_primitive("holds", Output::holds);
+        self.add_primitive("sign", Output::sign);
         self.add_primitive("f.r", Output::fdot_r);
         self.add_primitive("flush-output", Output::flush_output);
         self.references().idx_s_quote = self.find("_s\"").expect("_s\" undefined");
         self.references().idx_type = self.find("type").expect("type undefined");
+        self.references().idx__abort_quote = self.find("_abort\"").expect("_abort\" undefined");
     }
 
     fn push_output(&mut self, text: &str) {
@@ -115,6 +135,21 @@ pub trait Output: Core {
         self.compile_word(idx_type);
     }
 
+    /// Compilation: ( "ccc<quote>" -- )
+    ///
+    /// Parse ccc delimited by " (double-quote). Append the run-time semantics given below to the
+    /// current definition.
+    ///
+    /// Run-time: ( i*x x1 -- | i*x ) ( R: j*x -- | j*x )
+    ///
+    /// Remove x1 from the stack. If any bit of x1 is not zero, perform the function of -2 THROW,
+    /// displaying ccc if there is no exception frame on the exception stack.
+    fn abort_quote(&mut self) {
+        self.s_quote();
+        let idx = self.references().idx__abort_quote;
+        self.compile_word(idx);
+    }
+
     /// Execution: ( "ccc&lt;paren&gt;" -- )
     ///
     /// Parse and display ccc delimited by ) (right parenthesis). .( is an immediate word.
@@ -132,39 +167,210 @@ pub trait Output: Core {
     ///
     /// Display `n1` right aligned in a field `n2` characters wide.
     fn dot_r(&mut self) {
+        let (n1, n2) = self.s_stack().pop2();
+        self.hold_buffer().clear();
+        if self.hold_signed(n1 as i128) {
+            self.type_held(n2);
+        }
+    }
+
+    /// Run-time: ( u n -- )
+    ///
+    /// Display u right aligned in a field n characters wide.
+    fn u_dot_r(&mut self) {
+        let (u, n) = self.s_stack().pop2();
+        self.hold_buffer().clear();
+        if self.hold_unsigned(u as usize as u128) {
+            self.type_held(n);
+        }
+    }
+
+    // ----------------------------
+    // Pictured numeric output
+    // ----------------------------
+    //
+    // Characters are held in `hold_buffer` in reverse order, the last one
+    // held first, so that holding a character is a push instead of an
+    // insertion. The capacity of `hold_buffer` is the size of the pictured
+    // numeric output string; it is never grown.
+
+    /// Value of `BASE`, or `None` after aborting with
+    /// `UNSUPPORTED_OPERATION` if it is not between 2 and 36.
+    fn numeric_base(&mut self) -> Option<u128> {
         let base_addr = self.data_space().system_variables().base_addr();
         let base = unsafe { self.data_space().get_isize(base_addr) };
-        let mut valid_base = true;
-        let (n1, n2) = self.s_stack().pop2();
-        if let Some(mut buf) = self.output_buffer().take() {
-            self.hold_buffer().clear();
-            match base {
-                2 => {
-                    write!(self.hold_buffer(), "{:b}", n1).unwrap();
-                }
-                8 => {
-                    write!(self.hold_buffer(), "{:o}", n1).unwrap();
-                }
-                10 => {
-                    write!(self.hold_buffer(), "{}", n1).unwrap();
-                }
-                16 => {
-                    write!(self.hold_buffer(), "{:X}", n1).unwrap();
-                }
-                _ => {
-                    valid_base = false;
-                }
-            }
-            if valid_base {
-                for _ in 0..(n2 - self.hold_buffer().len() as isize) {
-                    buf.push(' ');
+        if (2..=36).contains(&base) {
+            Some(base as u128)
+        } else {
+            self.abort_with(UNSUPPORTED_OPERATION);
+            None
+        }
+    }
+
+    /// Add `c` to the beginning of the pictured numeric output string.
+    ///
+    /// Abort with `PICTURED_NUMERIC_OUTPUT_STRING_OVERFLOW` and return false
+    /// if the string is full.
+    fn hold_char(&mut self, c: char) -> bool {
+        let full = {
+            let buf = self.hold_buffer();
+            buf.len() + c.len_utf8() > buf.capacity()
+        };
+        if full {
+            self.abort_with(PICTURED_NUMERIC_OUTPUT_STRING_OVERFLOW);
+            false
+        } else {
+            self.hold_buffer().push(c);
+            true
+        }
+    }
+
+    /// Add the least significant digit of `ud` in `base` to the beginning of
+    /// the pictured numeric output string. Returns `ud` divided by `base`.
+    fn hold_digit(&mut self, ud: u128, base: u128) -> Option<u128> {
+        let c = ::std::char::from_digit((ud % base) as u32, base as u32)
+            .unwrap()
+            .to_ascii_uppercase();
+        if self.hold_char(c) {
+            Some(ud / base)
+        } else {
+            None
+        }
+    }
+
+    /// Convert all digits of `ud` in current base, at least one digit.
+    ///
+    /// Returns false if aborted.
+    fn hold_unsigned(&mut self, mut ud: u128) -> bool {
+        match self.numeric_base() {
+            Some(base) => loop {
+                match self.hold_digit(ud, base) {
+                    Some(0) => return true,
+                    Some(q) => ud = q,
+                    None => return false,
                 }
-                buf.push_str(self.hold_buffer());
+            },
+            None => false,
+        }
+    }
+
+    /// Convert all digits of `d` in current base, with a leading minus sign
+    /// if `d` is negative.
+    ///
+    /// Returns false if aborted.
+    fn hold_signed(&mut self, d: i128) -> bool {
+        self.hold_unsigned(d.unsigned_abs()) && (d >= 0 || self.hold_char('-'))
+    }
+
+    /// Display the pictured numeric output string right aligned in a field
+    /// `width` characters wide.
+    fn type_held(&mut self, width: isize) {
+        if let Some(mut buf) = self.output_buffer().take() {
+            for _ in 0..(width - self.hold_buffer().chars().count() as isize) {
+                buf.push(' ');
             }
+            buf.extend(self.hold_buffer().chars().rev());
             self.set_output_buffer(buf);
         }
-        if !valid_base {
-            self.abort_with(UNSUPPORTED_OPERATION);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Initialize the pictured numeric output conversion process.
+    fn less_number_sign(&mut self) {
+        self.hold_buffer().clear();
+    }
+
+    /// Run-time: ( ud1 -- ud2 )
+    ///
+    /// Divide ud1 by the number in BASE giving the quotient ud2 and the
+    /// remainder n. Convert n to external form and add the resulting
+    /// character to the beginning of the pictured numeric output string.
+    fn number_sign(&mut self) {
+        let (lo, hi) = self.s_stack().pop2();
+        if let Some(base) = self.numeric_base() {
+            if let Some(ud) = self.hold_digit(to_udouble(lo, hi), base) {
+                let (lo, hi) = from_udouble(ud);
+                self.s_stack().push2(lo, hi);
+            }
+        }
+    }
+
+    /// Run-time: ( ud1 -- ud2 )
+    ///
+    /// Convert one digit of ud1 according to the rule for #. Continue
+    /// conversion until the quotient is zero. ud2 is zero.
+    fn number_sign_s(&mut self) {
+        let (lo, hi) = self.s_stack().pop2();
+        if self.hold_unsigned(to_udouble(lo, hi)) {
+            self.s_stack().push2(0, 0);
+        }
+    }
+
+    /// Run-time: ( xd -- c-addr u )
+    ///
+    /// Drop xd. Make the pictured numeric output string available as a
+    /// character string. c-addr and u specify the resulting character string.
+    ///
+    /// The string is copied into a transient region in data space right
+    /// below PAD, so it is valid until the next pictured numeric output
+    /// conversion or until data space is allotted.
+    fn number_sign_greater(&mut self) {
+        self.s_stack().pop2();
+        let len = self.hold_buffer().chars().count();
+        let end = self.data_space().here() + PAD_OFFSET;
+        if end > self.data_space().limit() {
+            self.abort_with(PICTURED_NUMERIC_OUTPUT_STRING_OVERFLOW);
+            return;
+        }
+        let start = end - len;
+        let held = mem::take(self.hold_buffer());
+        for (i, c) in held.chars().rev().enumerate() {
+            unsafe { self.data_space().put_u8(c as u8, start + i) };
+        }
+        *self.hold_buffer() = held;
+        self.s_stack().push2(start as isize, len as isize);
+    }
+
+    /// Run-time: ( char -- )
+    ///
+    /// Add char to the beginning of the pictured numeric output string.
+    fn hold(&mut self) {
+        let c = self.s_stack().pop();
+        self.hold_char(c as u8 as char);
+    }
+
+    /// Run-time: ( c-addr u -- )
+    ///
+    /// Adds the string represented by c-addr u to the pictured numeric output
+    /// string.
+    fn holds(&mut self) {
+        let (addr, len) = self.s_stack().pop2();
+        let limit = self.data_space().limit();
+        if len < 0
+            || (addr as usize) < self.data_space().start()
+            || len as usize > limit.saturating_sub(addr as usize)
+        {
+            self.abort_with(INVALID_MEMORY_ADDRESS);
+            return;
+        }
+        let (addr, len) = (addr as usize, len as usize);
+        for i in (addr..addr + len).rev() {
+            let c = unsafe { self.data_space().get_u8(i) };
+            if !self.hold_char(c as char) {
+                return;
+            }
+        }
+    }
+
+    /// Run-time: ( n -- )
+    ///
+    /// If n is negative, add a minus sign to the beginning of the pictured
+    /// numeric output string.
+    fn sign(&mut self) {
+        let n = self.s_stack().pop();
+        if n < 0 {
+            self.hold_char('-');
         }
     }
 
@@ -223,6 +429,10 @@ pub trait Output: Core {
 #[cfg(test)]
 mod tests {
     use core::Core;
+    use exception::{
+        INVALID_MEMORY_ADDRESS, PICTURED_NUMERIC_OUTPUT_STRING_OVERFLOW, UNSUPPORTED_OPERATION,
+    };
+    use memory::Memory;
     use mock_vm::VM;
 
     #[test]
@@ -235,6 +445,73 @@ mod tests {
         assert_eq!(vm.output_buffer().clone().unwrap(), "Hi, how are you");
     }
 
+    #[test]
+    fn test_pictured_numeric_output() {
+        let vm = &mut VM::new();
+        vm.set_source("-42 dup abs 0 <# # # #s rot sign #> type");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), []);
+        assert_eq!(vm.output_buffer().clone().unwrap(), "-042");
+    }
+
+    #[test]
+    fn test_hold_holds() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            ": t 1234 0 <# # # [char] . hold #s s\" $ \" holds #> ; \
+             t type  255 hex 0 <# #s #> type decimal",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), []);
+        assert_eq!(vm.output_buffer().clone().unwrap(), "$ 12.34FF");
+        vm.set_source("0 0 <# here -1 holds");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_MEMORY_ADDRESS));
+    }
+
+    #[test]
+    fn test_pictured_numeric_output_double() {
+        let vm = &mut VM::new();
+        vm.set_source("0 1 <# #s #>");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let (addr, len) = vm.s_stack().pop2();
+        let s = unsafe { vm.data_space().str_from_raw_parts(addr as _, len as _) };
+        assert_eq!(s, "18446744073709551616");
+        vm.set_source("2 base ! -1 -1 <# #s #> nip decimal");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [128]);
+    }
+
+    #[test]
+    fn test_hold_overflow() {
+        let vm = &mut VM::new();
+        vm.set_source(": t <# 200 0 do [char] x hold loop ; t");
+        vm.evaluate_input();
+        assert_eq!(
+            vm.last_error(),
+            Some(PICTURED_NUMERIC_OUTPUT_STRING_OVERFLOW)
+        );
+    }
+
+    #[test]
+    fn test_dot_r() {
+        let vm = &mut VM::new();
+        vm.set_source("-42 5 .r  42 0 .r space  -1 u.  35 -35 36 base ! swap . . decimal  -1 h.");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(
+            vm.output_buffer().clone().unwrap(),
+            "  -4242 18446744073709551615 Z -Z -1 "
+        );
+        vm.set_source("1 1 base ! .");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(UNSUPPORTED_OPERATION));
+    }
+
     #[test]
     fn test_emit() {
         let vm = &mut VM::new();
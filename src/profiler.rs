@@ -0,0 +1,323 @@
+//! Profilers
+//!
+//! While profiling, every execution of a word counts a call and adds to
+//! its inclusive and exclusive execution times.
+//!
+//! ```text
+//! 0profile profile-on  main  profile-off .profile
+//! ```
+//!
+//! While sampling, the chain of words being executed is sampled
+//! periodically, per task. Samples are written as folded stacks, one line
+//! per chain with its number of samples, for flame graph tools.
+//!
+//! ```text
+//! 0samples 100 sample-on  main  sample-off .samples
+//! task1;main;step;compute 12
+//! ```
+
+use core::{Core, Profile};
+use exception::{FILE_IO_EXCEPTION, INVALID_NUMERIC_ARGUMENT};
+use memory::Memory;
+use std::fmt::Write;
+use std::io::Write as IoWrite;
+
+pub trait Profiler: Core {
+    /// Add profiler primitives.
+    fn add_profiler(&mut self) {
+        self.add_primitive("profile-on", Profiler::profile_on);
+        self.add_primitive("profile-off", Profiler::profile_off);
+        self.add_primitive("0profile", Profiler::clear_profile);
+        self.add_primitive(".profile", Profiler::dot_profile);
+        self.add_primitive("sample-on", Profiler::sample_on);
+        self.add_primitive("sample-off", Profiler::sample_off);
+        self.add_primitive("0samples", Profiler::clear_samples);
+        self.add_primitive(".samples", Profiler::dot_samples);
+        self.add_primitive("write-samples", Profiler::write_samples);
+    }
+
+    /// Start profiling if `on`, or stop it otherwise.
+    ///
+    /// Words being executed when profiling starts or stops are not
+    /// recorded as called.
+    fn set_profiling(&mut self, on: bool) {
+        let now = self.system_time_ns();
+        let current_task = self.current_task();
+        for i in 0..self.num_tasks() {
+            self.set_current_task(i);
+            self.state().profile.activations.clear();
+            self.state().profile.last_ns = now;
+        }
+        self.set_current_task(current_task);
+        self.instrumentation_mut().profiling = on;
+        self.update_hooks();
+    }
+
+    /// Profiles of the words called, the longest inclusive time first.
+    fn profiles(&mut self) -> Vec<(usize, Profile)> {
+        let mut profiles: Vec<(usize, Profile)> = (1..self.wordlist().len())
+            .map(|xt| (xt, self.wordlist()[xt].profile()))
+            .filter(|&(_, p)| p.calls > 0)
+            .collect();
+        profiles.sort_by(|a, b| {
+            (b.1.inclusive_ns, b.1.exclusive_ns, a.0).cmp(&(
+                a.1.inclusive_ns,
+                a.1.exclusive_ns,
+                b.0,
+            ))
+        });
+        profiles
+    }
+
+    /// Sample every `period_ns` nano-seconds, or stop sampling if
+    /// `period_ns` is 0.
+    fn set_sampling(&mut self, period_ns: u64) {
+        let now = self.system_time_ns();
+        self.instrumentation_mut().sample_period_ns = period_ns;
+        self.instrumentation_mut().next_sample_ns = now + period_ns;
+        self.update_hooks();
+    }
+
+    /// Samples of all tasks in folded stack format.
+    fn folded_samples(&mut self) -> String {
+        let mut folded = String::new();
+        let current_task = self.current_task();
+        for i in 0..self.num_tasks() {
+            self.set_current_task(i);
+            for (chain, n) in &self.state().samples {
+                writeln!(folded, "{} {}", chain, n).unwrap();
+            }
+        }
+        self.set_current_task(current_task);
+        folded
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Start profiling.
+    fn profile_on(&mut self) {
+        self.set_profiling(true);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Stop profiling.
+    fn profile_off(&mut self) {
+        self.set_profiling(false);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Clear the calls and execution times of all words.
+    fn clear_profile(&mut self) {
+        for xt in 1..self.wordlist().len() {
+            self.wordlist_mut()[xt].profile = Profile::default();
+        }
+    }
+
+    /// Run-time: ( u -- )
+    ///
+    /// Start sampling every `u` micro-seconds.
+    fn sample_on(&mut self) {
+        let u = self.s_stack().pop();
+        if u > 0 {
+            self.set_sampling(u as u64 * 1_000);
+        } else {
+            self.abort_with(INVALID_NUMERIC_ARGUMENT);
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Stop sampling.
+    fn sample_off(&mut self) {
+        self.set_sampling(0);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Clear the samples of all tasks.
+    fn clear_samples(&mut self) {
+        let current_task = self.current_task();
+        for i in 0..self.num_tasks() {
+            self.set_current_task(i);
+            self.state().samples.clear();
+        }
+        self.set_current_task(current_task);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Display the samples of all tasks in folded stack format.
+    fn dot_samples(&mut self) {
+        let folded = self.folded_samples();
+        if let Some(buf) = self.output_buffer().as_mut() {
+            buf.push_str(&folded);
+        }
+    }
+
+    /// Run-time: ( fileid -- ior )
+    ///
+    /// Write the samples of all tasks in folded stack format to file
+    /// `fileid`.
+    fn write_samples(&mut self) {
+        let fileid = self.s_stack().pop();
+        if fileid <= 0 || fileid as usize > self.files().len() {
+            self.s_stack().push(INVALID_NUMERIC_ARGUMENT.into());
+            return;
+        }
+        let folded = self.folded_samples();
+        let i = fileid as usize - 1;
+        let ior = match self.files_mut()[i].as_mut() {
+            Some(f) => match f.write_all(folded.as_bytes()) {
+                Ok(_) => 0,
+                Err(_) => FILE_IO_EXCEPTION.into(),
+            },
+            None => INVALID_NUMERIC_ARGUMENT.into(),
+        };
+        self.s_stack().push(ior);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Display calls, inclusive and exclusive execution times in
+    /// micro-seconds of the words called, the longest inclusive time
+    /// first.
+    fn dot_profile(&mut self) {
+        let profiles = self.profiles();
+        if let Some(mut buf) = self.output_buffer().take() {
+            write!(
+                buf,
+                "{:>10} {:>12} {:>12} name",
+                "calls", "incl[us]", "excl[us]"
+            )
+            .unwrap();
+            for (xt, p) in profiles {
+                let nfa = self.wordlist()[xt].nfa();
+                let name = unsafe { self.data_space().get_str(nfa) };
+                write!(
+                    buf,
+                    "\n{:>10} {:>12} {:>12} {}",
+                    p.calls,
+                    p.inclusive_ns / 1_000,
+                    p.exclusive_ns / 1_000,
+                    name
+                )
+                .unwrap();
+            }
+            self.set_output_buffer(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profiler;
+    use core::{Core, Profile};
+    use exception::INVALID_NUMERIC_ARGUMENT;
+    use mock_vm::VM;
+
+    fn tick(vm: &mut VM) {
+        vm.advance();
+    }
+
+    fn profile(vm: &mut VM, name: &str) -> Profile {
+        let xt = vm.find(name).expect("word");
+        vm.wordlist()[xt].profile()
+    }
+
+    #[test]
+    fn test_profile() {
+        let vm = &mut VM::new();
+        vm.add_primitive("tick", tick);
+        vm.set_source(
+            "
+            : inner   tick ;
+            : outer   inner inner tick ;
+            outer  profile-on outer outer profile-off  outer
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let p = |calls, inclusive_ms: u64, exclusive_ms: u64| Profile {
+            calls,
+            inclusive_ns: inclusive_ms * 1_000_000,
+            exclusive_ns: exclusive_ms * 1_000_000,
+        };
+        assert_eq!(profile(vm, "outer"), p(2, 6, 0));
+        assert_eq!(profile(vm, "inner"), p(4, 4, 0));
+        assert_eq!(profile(vm, "tick"), p(6, 6, 6));
+        assert_eq!(profile(vm, "exit").calls, 6);
+        vm.set_source(".profile");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let report = vm.output_buffer().clone().unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "     calls     incl[us]     excl[us] name");
+        assert_eq!(lines[1], "         6         6000         6000 tick");
+        assert_eq!(lines[2], "         2         6000            0 outer");
+        assert_eq!(lines[3], "         4         4000            0 inner");
+        vm.set_source("0profile");
+        vm.evaluate_input();
+        assert_eq!(profile(vm, "tick"), Profile::default());
+    }
+
+    #[test]
+    fn test_profile_with_tasks() {
+        let vm = &mut VM::new();
+        vm.add_primitive("tick", tick);
+        vm.set_source(
+            "
+            : sleeper   begin tick pause again ;
+            : waiter   pause pause ;
+            ' sleeper spawn drop  profile-on waiter profile-off
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        // Time the other task runs is not exclusive time of `waiter`.
+        let waiter = profile(vm, "waiter");
+        assert_eq!(waiter.calls, 1);
+        assert_eq!(waiter.inclusive_ns, 2_000_000);
+        assert_eq!(waiter.exclusive_ns, 0);
+        assert_eq!(profile(vm, "tick").exclusive_ns, 2_000_000);
+        assert_eq!(profile(vm, "pause").exclusive_ns, 0);
+    }
+
+    #[test]
+    fn test_samples() {
+        let vm = &mut VM::new();
+        vm.add_primitive("tick", tick);
+        vm.set_source(
+            "
+            : inner   tick ;
+            : worker   begin inner pause again ;
+            : busy   inner tick ;
+            : spin ( n -- )   0 ?do busy pause loop ;
+            ' worker spawn drop
+            1000 sample-on  3 spin  sample-off  3 spin
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        // Each tick takes 1 ms, the sampling period.
+        assert_eq!(
+            vm.folded_samples(),
+            "task1;spin;busy;inner;tick 3\n\
+             task1;spin;busy;tick 3\n\
+             task2;_task;worker;inner;tick 3\n"
+        );
+        // Unaligned addresses on the return stack are not return addresses.
+        vm.set_source(
+            "0samples  create v 32 allot  : t   v 9 + >r tick tick r> drop ;
+            1000 sample-on  t  sample-off",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert!(vm.folded_samples().contains("task1;t;tick 2\n"));
+        vm.set_source("0samples .samples  0 sample-on");
+        vm.evaluate_input();
+        assert_eq!(vm.output_buffer().clone().unwrap(), "");
+        assert_eq!(vm.last_error(), Some(INVALID_NUMERIC_ARGUMENT));
+    }
+}
@@ -0,0 +1,288 @@
+//! Inter-task message queues
+//!
+//! A queue is a fixed-capacity ring buffer in data space created by
+//! `queue` for cells or `fqueue` for floats:
+//!
+//! ```text
+//! +----------+------+-------+-------------------------+
+//! | capacity | head | count | item 0 ... capacity - 1 |
+//! +----------+------+-------+-------------------------+
+//! ```
+//!
+//! Float items start at the first float-aligned address after the header.
+//! Sending and receiving never allocate memory. Words which pause while a
+//! queue is full or empty are defined in `core.fth`.
+
+use core::Core;
+use exception::{Exception, INVALID_MEMORY_ADDRESS, WRITE_TO_A_READ_ONLY_LOCATION};
+use memory::{DataSpace, Memory};
+use std::mem;
+use std::result;
+use {FALSE, TRUE};
+
+pub trait Queue: Core {
+    /// Add message queue primitives.
+    fn add_queue(&mut self) {
+        self.add_primitive("try-send", Queue::p_try_send);
+        self.add_primitive("try-recv", Queue::p_try_recv);
+        self.add_primitive("ftry-send", Queue::p_ftry_send);
+        self.add_primitive("ftry-recv", Queue::p_ftry_recv);
+        self.add_primitive("queue-depth", Queue::queue_depth);
+    }
+
+    /// Capacity, head, count and address of the first item of queue `q`,
+    /// a float queue if `float` is true.
+    fn queue_header(
+        &mut self,
+        q: usize,
+        float: bool,
+    ) -> result::Result<(usize, usize, usize, usize), Exception> {
+        let cell = mem::size_of::<isize>();
+        match q.checked_add(3 * cell) {
+            Some(end) if self.data_space().start() < q && end <= self.data_space().limit() => {}
+            _ => return Err(INVALID_MEMORY_ADDRESS),
+        }
+        let (capacity, head, count) = unsafe {
+            (
+                self.data_space().get_usize(q),
+                self.data_space().get_usize(q + cell),
+                self.data_space().get_usize(q + 2 * cell),
+            )
+        };
+        let (items, size) = if float {
+            (DataSpace::aligned_f64(q + 3 * cell), mem::size_of::<f64>())
+        } else {
+            (q + 3 * cell, cell)
+        };
+        let end = capacity
+            .checked_mul(size)
+            .and_then(|n| n.checked_add(items));
+        match end {
+            Some(end)
+                if end <= self.data_space().limit() && head <= capacity && count <= capacity =>
+            {
+                if self.data_space().is_code(q, end) {
+                    Err(WRITE_TO_A_READ_ONLY_LOCATION)
+                } else {
+                    Ok((capacity, head, count, items))
+                }
+            }
+            _ => Err(INVALID_MEMORY_ADDRESS),
+        }
+    }
+
+    /// Reserve a slot at the tail of queue `q` and return its address.
+    ///
+    /// Returns `None` if the queue is full.
+    fn queue_push(&mut self, q: usize, float: bool) -> result::Result<Option<usize>, Exception> {
+        let (capacity, head, count, items) = self.queue_header(q, float)?;
+        if count < capacity {
+            let size = if float {
+                mem::size_of::<f64>()
+            } else {
+                mem::size_of::<isize>()
+            };
+            let slot = items + (head + count) % capacity * size;
+            unsafe {
+                self.data_space()
+                    .put_usize(count + 1, q + 2 * mem::size_of::<isize>());
+            }
+            Ok(Some(slot))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Remove the slot at the head of queue `q` and return its address.
+    ///
+    /// Returns `None` if the queue is empty.
+    fn queue_pop(&mut self, q: usize, float: bool) -> result::Result<Option<usize>, Exception> {
+        let (capacity, head, count, items) = self.queue_header(q, float)?;
+        if count > 0 {
+            let size = if float {
+                mem::size_of::<f64>()
+            } else {
+                mem::size_of::<isize>()
+            };
+            let slot = items + head % capacity * size;
+            let cell = mem::size_of::<isize>();
+            unsafe {
+                self.data_space().put_usize((head + 1) % capacity, q + cell);
+                self.data_space().put_usize(count - 1, q + 2 * cell);
+            }
+            Ok(Some(slot))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Send `x` to cell queue `q`. Returns false if the queue is full.
+    fn try_send(&mut self, q: usize, x: isize) -> result::Result<bool, Exception> {
+        let slot = self.queue_push(q, false)?;
+        Ok(match slot {
+            Some(slot) => {
+                unsafe { self.data_space().put_isize(x, slot) };
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// Receive a cell from queue `q`. Returns `None` if the queue is empty.
+    fn try_recv(&mut self, q: usize) -> result::Result<Option<isize>, Exception> {
+        let slot = self.queue_pop(q, false)?;
+        Ok(slot.map(|slot| unsafe { self.data_space().get_isize(slot) }))
+    }
+
+    /// Send `r` to float queue `q`. Returns false if the queue is full.
+    fn ftry_send(&mut self, q: usize, r: f64) -> result::Result<bool, Exception> {
+        let slot = self.queue_push(q, true)?;
+        Ok(match slot {
+            Some(slot) => {
+                unsafe { self.data_space().put_f64(r, slot) };
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// Receive a float from queue `q`. Returns `None` if the queue is empty.
+    fn ftry_recv(&mut self, q: usize) -> result::Result<Option<f64>, Exception> {
+        let slot = self.queue_pop(q, true)?;
+        Ok(slot.map(|slot| unsafe { self.data_space().get_f64(slot) }))
+    }
+
+    /// Run-time: ( x q -- flag )
+    ///
+    /// Send `x` to queue `q`. `flag` is false if `q` is full.
+    fn p_try_send(&mut self) {
+        let (x, q) = self.s_stack().pop2();
+        match self.try_send(q as usize, x) {
+            Ok(sent) => self.s_stack().push(if sent { TRUE } else { FALSE }),
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( q -- x true | false )
+    ///
+    /// Receive `x` from queue `q`. Return false if `q` is empty.
+    fn p_try_recv(&mut self) {
+        let q = self.s_stack().pop();
+        match self.try_recv(q as usize) {
+            Ok(Some(x)) => self.s_stack().push2(x, TRUE),
+            Ok(None) => self.s_stack().push(FALSE),
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( q -- flag ) ( F: r -- )
+    ///
+    /// Send `r` to float queue `q`. `flag` is false if `q` is full.
+    fn p_ftry_send(&mut self) {
+        let q = self.s_stack().pop();
+        let r = self.f_stack().pop();
+        match self.ftry_send(q as usize, r) {
+            Ok(sent) => self.s_stack().push(if sent { TRUE } else { FALSE }),
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( q -- true | false ) ( F: -- r | )
+    ///
+    /// Receive `r` from float queue `q`. Return false if `q` is empty.
+    fn p_ftry_recv(&mut self) {
+        let q = self.s_stack().pop();
+        match self.ftry_recv(q as usize) {
+            Ok(Some(r)) => {
+                self.f_stack().push(r);
+                self.s_stack().push(TRUE);
+            }
+            Ok(None) => self.s_stack().push(FALSE),
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( q -- n )
+    ///
+    /// Number of items in queue `q`.
+    fn queue_depth(&mut self) {
+        let q = self.s_stack().pop();
+        match self.queue_header(q as usize, false) {
+            Ok((_, _, count, _)) => self.s_stack().push(count as isize),
+            Err(e) => self.abort_with(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Queue;
+    use core::Core;
+    use exception::INVALID_MEMORY_ADDRESS;
+    use mock_vm::VM;
+
+    #[test]
+    fn test_queue() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            2 queue q
+            1 q try-send  2 q try-send  3 q try-send  q queue-depth
+            q try-recv  q try-recv  q try-recv
+            4 q try-send  q try-recv
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(
+            vm.s_stack().as_slice(),
+            [-1, -1, 0, 2, 1, -1, 2, -1, 0, -1, 4, -1]
+        );
+        vm.s_stack().reset();
+        vm.set_source("0 queue-depth");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_MEMORY_ADDRESS));
+        vm.reset();
+        vm.set_source("-1 queue-depth");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_MEMORY_ADDRESS));
+    }
+
+    #[test]
+    fn test_fqueue() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            2 fqueue fq
+            1.5e fq ftry-send  2.5e fq ftry-send  3.5e fq ftry-send
+            fq ftry-recv  fq ftry-recv  fq ftry-recv  fq queue-depth
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, -1, 0, -1, -1, 0, 0]);
+        assert_eq!(vm.f_stack().as_slice(), [1.5, 2.5]);
+        let q = vm.find("fq").expect("fq");
+        let q = vm.wordlist()[q].dfa();
+        assert_eq!(vm.ftry_send(q, 4.5), Ok(true));
+        assert_eq!(vm.ftry_recv(q), Ok(Some(4.5)));
+        assert_eq!(vm.ftry_recv(q), Ok(None));
+    }
+
+    #[test]
+    fn test_send_recv() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            1 queue setpoints  variable sum
+            : servo   begin setpoints recv sum +! again ;
+            : motion   4 1 do i setpoints send loop ;
+            : watch ( n -- )   0 ?do pause loop ;
+            ' servo spawn drop  motion  2 watch  sum @  setpoints queue-depth
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [6, 0]);
+    }
+}
@@ -0,0 +1,424 @@
+//! Search-Order word set
+
+use core::{Core, FORTH_WORDLIST, SEARCH_ORDER_SIZE};
+use exception::{INVALID_NUMERIC_ARGUMENT, SEARCH_ORDER_OVERFLOW, SEARCH_ORDER_UNDERFLOW};
+use memory::Memory;
+use std::fmt::Write;
+
+pub trait SearchOrder: Core {
+    /// Add search-order words.
+    fn add_search_order(&mut self) {
+        self.add_primitive("forth-wordlist", SearchOrder::forth_wordlist);
+        self.add_primitive("wordlist", SearchOrder::p_wordlist);
+        self.add_primitive("get-current", SearchOrder::get_current);
+        self.add_primitive("set-current", SearchOrder::set_current);
+        self.add_primitive("get-order", SearchOrder::get_order);
+        self.add_primitive("set-order", SearchOrder::set_order);
+        self.add_primitive("search-wordlist", SearchOrder::search_wordlist);
+        self.add_primitive("definitions", SearchOrder::definitions);
+        self.add_primitive("only", SearchOrder::only);
+        self.add_primitive("also", SearchOrder::also);
+        self.add_primitive("previous", SearchOrder::previous);
+        self.add_primitive("order", SearchOrder::order);
+        self.add_primitive("vocabulary", SearchOrder::vocabulary);
+        self.add_doer("vocabulary", SearchOrder::p_vocabulary, Core::compile_word);
+
+        self.add_primitive("forth", SearchOrder::p_vocabulary);
+        self.data_space().compile_usize(FORTH_WORDLIST);
+        let nfa = self.wordlist()[self.wordlist().len() - 1].nfa();
+        self.wordlist_mut().set_wordlist_name(FORTH_WORDLIST, nfa);
+    }
+
+    /// Abort with `INVALID_NUMERIC_ARGUMENT` if `wid` is not a word list.
+    ///
+    /// Returns true if `wid` is a word list.
+    fn check_wid(&mut self, wid: isize) -> bool {
+        if wid >= 0 && (wid as usize) < self.wordlist().wordlist_count() {
+            true
+        } else {
+            self.abort_with(INVALID_NUMERIC_ARGUMENT);
+            false
+        }
+    }
+
+    /// Run-time: ( -- wid )
+    ///
+    /// Return wid, the identifier of the word list that includes all standard
+    /// words provided by the implementation.
+    fn forth_wordlist(&mut self) {
+        self.s_stack().push(FORTH_WORDLIST as isize);
+    }
+
+    /// Run-time: ( -- wid )
+    ///
+    /// Create a new empty word list, returning its word list identifier wid.
+    fn p_wordlist(&mut self) {
+        let wid = self.wordlist_mut().add_wordlist();
+        self.s_stack().push(wid as isize);
+    }
+
+    /// Run-time: ( -- wid )
+    ///
+    /// Return wid, the identifier of the compilation word list.
+    fn get_current(&mut self) {
+        let wid = self.wordlist().current();
+        self.s_stack().push(wid as isize);
+    }
+
+    /// Run-time: ( wid -- )
+    ///
+    /// Set the compilation word list to the word list identified by wid.
+    fn set_current(&mut self) {
+        let wid = self.s_stack().pop();
+        if self.check_wid(wid) {
+            self.wordlist_mut().set_current(wid as usize);
+        }
+    }
+
+    /// Run-time: ( -- widn ... wid1 n )
+    ///
+    /// Returns the number of word lists n in the search order and the word
+    /// list identifiers widn ... wid1 identifying these word lists. wid1
+    /// identifies the word list that is searched first, and widn the word
+    /// list that is searched last.
+    fn get_order(&mut self) {
+        let len = self.wordlist().search_order().len();
+        for i in 0..len {
+            let wid = self.wordlist().search_order()[i];
+            self.s_stack().push(wid as isize);
+        }
+        self.s_stack().push(len as isize);
+    }
+
+    /// Run-time: ( widn ... wid1 n -- )
+    ///
+    /// Set the search order to the word lists identified by widn ... wid1.
+    /// Subsequently, word list wid1 will be searched first, and word list
+    /// widn searched last. If n is zero, empty the search order. If n is
+    /// minus one, set the search order to the implementation-defined minimum
+    /// search order, which contains only FORTH-WORDLIST.
+    fn set_order(&mut self) {
+        let n = self.s_stack().pop();
+        if n == -1 {
+            self.only();
+        } else if n < 0 {
+            self.abort_with(SEARCH_ORDER_UNDERFLOW);
+        } else if n as usize > SEARCH_ORDER_SIZE {
+            self.abort_with(SEARCH_ORDER_OVERFLOW);
+        } else if n as u8 > self.s_stack().len() {
+            self.abort_with(SEARCH_ORDER_UNDERFLOW);
+        } else {
+            let base = self.s_stack().len() - n as u8;
+            for i in 0..n as u8 {
+                let wid = self.s_stack()[base + i];
+                if !self.check_wid(wid) {
+                    return;
+                }
+            }
+            self.wordlist_mut().search_order_mut().clear();
+            for i in 0..n as u8 {
+                let wid = self.s_stack()[base + i];
+                self.wordlist_mut().search_order_mut().push(wid as usize);
+            }
+            self.s_stack().len = base;
+        }
+    }
+
+    /// Run-time: ( c-addr u wid -- 0 | xt 1 | xt -1 )
+    ///
+    /// Find the definition identified by the string c-addr u in the word
+    /// list identified by wid. If the definition is not found, return zero.
+    /// If the definition is found, return its execution token xt and one (1)
+    /// if the definition is immediate, minus-one (-1) otherwise.
+    fn search_wordlist(&mut self) {
+        let (addr, len, wid) = self.s_stack().pop3();
+        if !self.check_wid(wid) {
+            return;
+        }
+        let found = {
+            let name = unsafe {
+                self.data_space()
+                    .str_from_raw_parts(addr as usize, len as usize)
+            };
+            let name = String::from(name);
+            self.find_in(wid as usize, &name)
+        };
+        match found {
+            Some(xt) => {
+                let flag = if self.wordlist()[xt].is_immediate() {
+                    1
+                } else {
+                    -1
+                };
+                self.s_stack().push2(xt as isize, flag);
+            }
+            None => self.s_stack().push(0),
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Make the compilation word list the same as the first word list in the
+    /// search order.
+    fn definitions(&mut self) {
+        match self.wordlist().search_order().last() {
+            Some(&wid) => self.wordlist_mut().set_current(wid),
+            None => self.abort_with(SEARCH_ORDER_UNDERFLOW),
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Set the search order to the minimum search order, which contains only
+    /// FORTH-WORDLIST.
+    fn only(&mut self) {
+        self.wordlist_mut().search_order_mut().clear();
+        self.wordlist_mut().search_order_mut().push(FORTH_WORDLIST);
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Transform the search order consisting of widn, ... wid2, wid1 (where
+    /// wid1 is searched first) into widn, ... wid2, wid1, wid1.
+    fn also(&mut self) {
+        let len = self.wordlist().search_order().len();
+        if len == 0 {
+            self.abort_with(SEARCH_ORDER_UNDERFLOW);
+        } else if len >= SEARCH_ORDER_SIZE {
+            self.abort_with(SEARCH_ORDER_OVERFLOW);
+        } else {
+            let wid = self.wordlist().search_order()[len - 1];
+            self.wordlist_mut().search_order_mut().push(wid);
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Transform the search order consisting of widn, ... wid2, wid1 (where
+    /// wid1 is searched first) into widn, ... wid2.
+    fn previous(&mut self) {
+        if self.wordlist_mut().search_order_mut().pop().is_none() {
+            self.abort_with(SEARCH_ORDER_UNDERFLOW);
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Display the word lists in the search order in their search order
+    /// sequence, from first searched to last searched. Also display the word
+    /// list into which new definitions will be placed.
+    fn order(&mut self) {
+        if let Some(mut buf) = self.output_buffer().take() {
+            let len = self.wordlist().search_order().len();
+            for i in (0..len).rev() {
+                let wid = self.wordlist().search_order()[i];
+                self.write_wordlist_name(&mut buf, wid);
+            }
+            write!(buf, " ").unwrap();
+            let current = self.wordlist().current();
+            self.write_wordlist_name(&mut buf, current);
+            self.set_output_buffer(buf);
+        }
+    }
+
+    /// Write the name of word list `wid` into `buf`.
+    ///
+    /// Anonymous word lists created by `wordlist` are written as their
+    /// identifiers.
+    fn write_wordlist_name(&mut self, buf: &mut String, wid: usize) {
+        let nfa = self.wordlist().wordlist_name(wid);
+        if nfa == 0 {
+            write!(buf, "{} ", wid).unwrap();
+        } else {
+            let name = unsafe { self.data_space().get_str(nfa) };
+            write!(buf, "{} ", name).unwrap();
+        }
+    }
+
+    /// Compilation: ( "<spaces>name" -- )
+    ///
+    /// Skip leading space delimiters. Parse name delimited by a space. Create
+    /// a definition for name with the execution semantics defined below, and
+    /// a new empty word list associated with it.
+    ///
+    /// name Execution: ( -- )
+    ///
+    /// Replace the first word list in the search order with the word list
+    /// associated with name.
+    fn vocabulary(&mut self) {
+        self.define(SearchOrder::p_vocabulary, Core::compile_word);
+        if self.last_error().is_none() {
+            let wid = self.wordlist_mut().add_wordlist();
+            self.data_space().compile_usize(wid);
+            let last = self.wordlist().len() - 1;
+            let nfa = self.wordlist()[last].nfa();
+            self.wordlist_mut().set_wordlist_name(wid, nfa);
+        }
+    }
+
+    /// Run time behavior of words created by `vocabulary`.
+    fn p_vocabulary(&mut self) {
+        let wp = self.state().word_pointer();
+        let dfa = self.wordlist()[wp].dfa();
+        let wid = unsafe { self.data_space().get_usize(dfa) };
+        if let Some(top) = self.wordlist_mut().search_order_mut().last_mut() {
+            *top = wid;
+            return;
+        }
+        self.abort_with(SEARCH_ORDER_UNDERFLOW);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::Core;
+    use exception::{SEARCH_ORDER_OVERFLOW, SEARCH_ORDER_UNDERFLOW, UNDEFINED_WORD};
+    use mock_vm::VM;
+
+    #[test]
+    fn test_wordlist() {
+        let vm = &mut VM::new();
+        vm.set_source("forth-wordlist  wordlist  wordlist  2dup <>  -rot swap -");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().len(), 3);
+        assert_eq!(vm.s_stack().pop(), 1);
+        assert_eq!(vm.s_stack().pop(), -1);
+        assert_eq!(vm.s_stack().pop(), 0);
+    }
+
+    #[test]
+    fn test_get_set_order() {
+        let vm = &mut VM::new();
+        vm.set_source("get-order");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1]);
+        vm.s_stack().reset();
+        vm.set_source("wordlist dup forth-wordlist swap 2 set-order get-order");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let wid = vm.s_stack()[0];
+        assert_eq!(vm.s_stack().as_slice(), [wid, 0, wid, 2]);
+        vm.s_stack().reset();
+        vm.set_source("-1 set-order get-order");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1]);
+        vm.s_stack().reset();
+        vm.set_source("1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 17 set-order");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(SEARCH_ORDER_OVERFLOW));
+    }
+
+    #[test]
+    fn test_definitions() {
+        let vm = &mut VM::new();
+        vm.set_source("wordlist constant w  forth-wordlist w 2 set-order definitions  : dup 2 ;");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        vm.set_source("get-current w =  3 dup");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, 3, 2]);
+        vm.s_stack().reset();
+        vm.set_source("forth-wordlist set-current  only  3 dup");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [3, 3]);
+    }
+
+    #[test]
+    fn test_search_wordlist() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "wordlist constant w  w set-current  : foo 1 ;  : bar 2 ; immediate \
+             forth-wordlist set-current",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        vm.set_source(
+            ": t1 s\" foo\" w search-wordlist nip ;  : t2 s\" bar\" w search-wordlist nip ; \
+             : t3 s\" foo\" forth-wordlist search-wordlist ;  : t4 s\" dup\" w search-wordlist ; \
+             : t5 s\" foo\" w search-wordlist drop ;",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        vm.set_source("t1 t2");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [-1, 1]);
+        vm.s_stack().reset();
+        vm.set_source("t3 t4");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 0]);
+        vm.s_stack().reset();
+        vm.set_source("t5 execute");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [1]);
+    }
+
+    #[test]
+    fn test_vocabulary() {
+        let vm = &mut VM::new();
+        vm.set_source("vocabulary v  also v definitions  : dup 5 ;  3 dup");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [3, 5]);
+        vm.s_stack().reset();
+        vm.set_source("previous definitions  3 dup");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [3, 3]);
+        vm.s_stack().reset();
+        vm.set_source("also v  3 dup  forth 4 dup");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [3, 5, 4, 4]);
+        vm.s_stack().reset();
+        vm.set_source("-2 set-order");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(SEARCH_ORDER_UNDERFLOW));
+        vm.reset();
+        vm.set_source("only previous");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert!(vm.wordlist().search_order().is_empty());
+        vm.set_source("dup");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(UNDEFINED_WORD));
+    }
+
+    #[test]
+    fn test_order() {
+        let vm = &mut VM::new();
+        vm.set_source("vocabulary v  wordlist drop  also v definitions order");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.output_buffer().as_ref().unwrap(), "v forth  v ");
+    }
+
+    #[test]
+    fn test_marker_restores_search_order() {
+        let vm = &mut VM::new();
+        vm.set_source("marker -v  vocabulary v  also v definitions  : foo 1 ;  -v");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        vm.set_source("get-order get-current");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1, 0]);
+        vm.s_stack().reset();
+        // Word list 1 holds the substitutions of the String word set.
+        vm.set_source("wordlist");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [2]);
+        vm.s_stack().reset();
+        vm.set_source("foo");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(UNDEFINED_WORD));
+    }
+}
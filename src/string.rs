@@ -0,0 +1,482 @@
+//! String word set
+//!
+//! Substitutions defined by `replaces` are kept as words in a word list of
+//! their own, which is not in the search order. The data field of such a
+//! word holds the replacement text as a counted string.
+
+use core::{Core, Word};
+use exception::{
+    Exception, DICTIONARY_OVERFLOW, INVALID_MEMORY_ADDRESS, RESULT_OUT_OF_RANGE,
+    WRITE_TO_A_READ_ONLY_LOCATION,
+};
+use memory::Memory;
+use std::cmp::Ordering;
+use std::mem;
+use std::result;
+use {FALSE, TRUE};
+
+pub trait Strings: Core {
+    /// Add string primitives.
+    fn add_string(&mut self) {
+        self.add_primitive("-trailing", Strings::dash_trailing);
+        self.add_primitive("/string", Strings::slash_string);
+        self.add_primitive("blank", Strings::blank);
+        self.add_primitive("cmove", Strings::cmove);
+        self.add_primitive("cmove>", Strings::cmove_up);
+        self.add_primitive("compare", Strings::compare);
+        self.add_primitive("search", Strings::search);
+        self.add_immediate_and_compile_only("sliteral", Strings::sliteral);
+        self.add_primitive("replaces", Strings::replaces);
+        self.add_primitive("substitute", Strings::substitute);
+        self.add_primitive("unescape", Strings::unescape);
+        self.references().wid_substitutions = self.wordlist_mut().add_wordlist();
+    }
+
+    /// Check that the `u` characters at `addr` are in data space.
+    fn check_read(&mut self, addr: usize, u: usize) -> result::Result<(), Exception> {
+        if u == 0
+            || (self.data_space().start() < addr
+                && addr
+                    .checked_add(u)
+                    .is_some_and(|end| end <= self.data_space().limit()))
+        {
+            Ok(())
+        } else {
+            Err(INVALID_MEMORY_ADDRESS)
+        }
+    }
+
+    /// Check that the `u` characters at `addr` are in data space and can be
+    /// written.
+    fn check_write(&mut self, addr: usize, u: usize) -> result::Result<(), Exception> {
+        self.check_read(addr, u)?;
+        if u != 0 && self.data_space().is_code(addr, addr + u) {
+            Err(WRITE_TO_A_READ_ONLY_LOCATION)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Copy of the `u` characters at `addr`.
+    fn string_at(&mut self, addr: usize, u: usize) -> result::Result<Vec<u8>, Exception> {
+        self.check_read(addr, u)?;
+        if u == 0 {
+            Ok(Vec::new())
+        } else {
+            Ok(unsafe { self.data_space().buffer_from_raw_parts(addr, u) }.to_vec())
+        }
+    }
+
+    /// Store `bytes` at `addr`.
+    fn put_string(&mut self, bytes: &[u8], addr: usize) -> result::Result<(), Exception> {
+        self.check_write(addr, bytes.len())?;
+        if !bytes.is_empty() {
+            unsafe {
+                self.data_space()
+                    .buffer_from_raw_parts_mut(addr, bytes.len())
+                    .copy_from_slice(bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run-time: ( c-addr u1 -- c-addr u2 )
+    ///
+    /// If `u1` is greater than zero, `u2` is equal to `u1` less the number of
+    /// spaces at the end of the character string specified by `c-addr u1`.
+    fn dash_trailing(&mut self) {
+        let (caddr, u1) = self.s_stack().pop2();
+        let u1 = if u1 > 0 { u1 as usize } else { 0 };
+        match self.string_at(caddr as usize, u1) {
+            Ok(s) => {
+                let u2 = s.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+                self.s_stack().push2(caddr, u2 as isize);
+            }
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( c-addr1 u1 n -- c-addr2 u2 )
+    ///
+    /// Adjust the character string at `c-addr1` by `n` characters. The
+    /// resulting character string, specified by `c-addr2 u2`, begins at
+    /// `c-addr1` plus `n` characters and is `u1` minus `n` characters long.
+    fn slash_string(&mut self) {
+        let (caddr, u1, n) = self.s_stack().pop3();
+        self.s_stack()
+            .push2(caddr.wrapping_add(n), u1.wrapping_sub(n));
+    }
+
+    /// Run-time: ( c-addr u -- )
+    ///
+    /// If `u` is greater than zero, store the character value for space in
+    /// `u` consecutive character positions beginning at `c-addr`.
+    fn blank(&mut self) {
+        let (caddr, u) = self.s_stack().pop2();
+        if u > 0 {
+            let (caddr, u) = (caddr as usize, u as usize);
+            match self.check_write(caddr, u) {
+                Ok(()) => unsafe {
+                    self.data_space()
+                        .buffer_from_raw_parts_mut(caddr, u)
+                        .fill(b' ');
+                },
+                Err(e) => self.abort_with(e),
+            }
+        }
+    }
+
+    /// Run-time: ( c-addr1 c-addr2 u -- )
+    ///
+    /// If `u` is greater than zero, copy `u` consecutive characters from the
+    /// data space starting at `c-addr1` to that starting at `c-addr2`,
+    /// proceeding character-by-character from lower addresses to higher
+    /// addresses.
+    fn cmove(&mut self) {
+        let (caddr1, caddr2, u) = self.s_stack().pop3();
+        if u > 0 {
+            let (caddr1, caddr2, u) = (caddr1 as usize, caddr2 as usize, u as usize);
+            match self
+                .check_read(caddr1, u)
+                .and_then(|_| self.check_write(caddr2, u))
+            {
+                Ok(()) => {
+                    for i in 0..u {
+                        unsafe {
+                            let c = self.data_space().get_u8(caddr1 + i);
+                            self.data_space().put_u8(c, caddr2 + i);
+                        }
+                    }
+                }
+                Err(e) => self.abort_with(e),
+            }
+        }
+    }
+
+    /// Run-time: ( c-addr1 c-addr2 u -- )
+    ///
+    /// If `u` is greater than zero, copy `u` consecutive characters from the
+    /// data space starting at `c-addr1` to that starting at `c-addr2`,
+    /// proceeding character-by-character from higher addresses to lower
+    /// addresses.
+    fn cmove_up(&mut self) {
+        let (caddr1, caddr2, u) = self.s_stack().pop3();
+        if u > 0 {
+            let (caddr1, caddr2, u) = (caddr1 as usize, caddr2 as usize, u as usize);
+            match self
+                .check_read(caddr1, u)
+                .and_then(|_| self.check_write(caddr2, u))
+            {
+                Ok(()) => {
+                    for i in (0..u).rev() {
+                        unsafe {
+                            let c = self.data_space().get_u8(caddr1 + i);
+                            self.data_space().put_u8(c, caddr2 + i);
+                        }
+                    }
+                }
+                Err(e) => self.abort_with(e),
+            }
+        }
+    }
+
+    /// Run-time: ( c-addr1 u1 c-addr2 u2 -- n )
+    ///
+    /// Compare the string specified by `c-addr1 u1` to the string specified
+    /// by `c-addr2 u2`. `n` is 0 if the strings are identical, -1 if the
+    /// first string is less than the second one, otherwise 1. Strings are
+    /// compared character by character, and a string which is a prefix of
+    /// the other is the lesser one.
+    fn compare(&mut self) {
+        let (caddr2, u2) = self.s_stack().pop2();
+        let (caddr1, u1) = self.s_stack().pop2();
+        let strings = self.string_at(caddr1 as usize, u1 as usize).and_then(|s1| {
+            self.string_at(caddr2 as usize, u2 as usize)
+                .map(|s2| (s1, s2))
+        });
+        match strings {
+            Ok((s1, s2)) => {
+                let n = match s1.cmp(&s2) {
+                    Ordering::Less => -1,
+                    Ordering::Equal => 0,
+                    Ordering::Greater => 1,
+                };
+                self.s_stack().push(n);
+            }
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( c-addr1 u1 c-addr2 u2 -- c-addr3 u3 flag )
+    ///
+    /// Search the string specified by `c-addr1 u1` for the string specified
+    /// by `c-addr2 u2`. If found, `flag` is true and `c-addr3 u3` is the
+    /// rest of the first string beginning with the match. Otherwise `flag`
+    /// is false and `c-addr3 u3` is `c-addr1 u1`.
+    fn search(&mut self) {
+        let (caddr2, u2) = self.s_stack().pop2();
+        let (caddr1, u1) = self.s_stack().pop2();
+        let strings = self.string_at(caddr1 as usize, u1 as usize).and_then(|s1| {
+            self.string_at(caddr2 as usize, u2 as usize)
+                .map(|s2| (s1, s2))
+        });
+        match strings {
+            Ok((s1, s2)) => {
+                let found = if s2.is_empty() {
+                    Some(0)
+                } else {
+                    s1.windows(s2.len()).position(|w| w == &s2[..])
+                };
+                match found {
+                    Some(i) => {
+                        let i = i as isize;
+                        self.s_stack().push3(caddr1 + i, u1 - i, TRUE);
+                    }
+                    None => self.s_stack().push3(caddr1, u1, FALSE),
+                }
+            }
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Compilation: ( c-addr1 u -- )
+    ///
+    /// Append the run-time semantics given below to the current definition.
+    ///
+    /// Run-time: ( -- c-addr2 u )
+    ///
+    /// Return `c-addr2 u` describing a string consisting of the characters
+    /// specified by `c-addr1 u` during compilation.
+    fn sliteral(&mut self) {
+        let (caddr, u) = self.s_stack().pop2();
+        let s = match self.string_at(caddr as usize, u as usize) {
+            Ok(s) => s,
+            Err(e) => return self.abort_with(e),
+        };
+        let here = self.data_space().here();
+        if here + s.len() + 3 * mem::size_of::<usize>() > self.data_space().limit() {
+            return self.abort_with(DICTIONARY_OVERFLOW);
+        }
+        let idx = self.references().idx_s_quote;
+        let compilation_semantics = self.wordlist()[idx].compilation_semantics;
+        compilation_semantics(self, idx);
+        self.data_space().compile_usize(s.len());
+        for &c in &s {
+            self.data_space().compile_u8(c);
+        }
+        self.data_space().align();
+    }
+
+    /// Replacement text of substitution `name`.
+    fn substitution(&mut self, name: &str) -> Option<Vec<u8>> {
+        let wid = self.references().wid_substitutions;
+        self.find_in(wid, name).map(|w| {
+            let dfa = self.wordlist()[w].dfa();
+            let text = unsafe { self.data_space().get_str(dfa) };
+            text.as_bytes().to_vec()
+        })
+    }
+
+    /// Run-time: ( c-addr1 u1 c-addr2 u2 -- )
+    ///
+    /// Set the string `c-addr1 u1` as the text to substitute for the
+    /// substitution named by `c-addr2 u2`. The strings are copied.
+    ///
+    /// Example: `: axis s" X" ;  : name s" axis" ;  axis name replaces`
+    fn replaces(&mut self) {
+        let (caddr2, u2) = self.s_stack().pop2();
+        let (caddr1, u1) = self.s_stack().pop2();
+        let strings = self
+            .string_at(caddr1 as usize, u1 as usize)
+            .and_then(|text| {
+                self.string_at(caddr2 as usize, u2 as usize)
+                    .map(|name| (text, name))
+            });
+        let (text, name) = match strings {
+            Ok((text, name)) => (text, String::from_utf8_lossy(&name).into_owned()),
+            Err(e) => return self.abort_with(e),
+        };
+        let here = self.data_space().here();
+        if here + name.len() + text.len() + 4 * mem::size_of::<usize>() > self.data_space().limit()
+        {
+            return self.abort_with(DICTIONARY_OVERFLOW);
+        }
+        let nfa = self.data_space().compile_str(&name);
+        self.data_space().align();
+        let dfa = self.data_space().here();
+        self.data_space().mark_code(nfa, dfa);
+        let word = Word::new(Core::p_var, Core::compile_var, nfa, dfa);
+        let current = self.wordlist().current();
+        let wid = self.references().wid_substitutions;
+        self.wordlist_mut().set_current(wid);
+        self.wordlist_mut().push(&name, word);
+        self.wordlist_mut().set_current(current);
+        self.data_space().compile_usize(text.len());
+        for &c in &text {
+            self.data_space().compile_u8(c);
+        }
+        self.data_space().align();
+    }
+
+    /// Run-time: ( c-addr1 u1 c-addr2 u2 -- c-addr2 u3 n )
+    ///
+    /// Copy the string `c-addr1 u1` to the buffer `c-addr2 u2`, replacing
+    /// each `%name%` whose name was defined by `replaces` with its text, and
+    /// each `%%` with a single `%`. Other text, including `%name%` with an
+    /// unknown name, is copied unchanged. `c-addr2 u3` is the resulting
+    /// string and `n` the number of substitutions. If the result does not
+    /// fit in the buffer, `n` is negative.
+    fn substitute(&mut self) {
+        let (caddr2, u2) = self.s_stack().pop2();
+        let (caddr1, u1) = self.s_stack().pop2();
+        let s = match self.string_at(caddr1 as usize, u1 as usize) {
+            Ok(s) => s,
+            Err(e) => return self.abort_with(e),
+        };
+        let mut result = Vec::with_capacity(s.len());
+        let mut n = 0;
+        let mut i = 0;
+        while i < s.len() {
+            if s[i] != b'%' {
+                result.push(s[i]);
+                i += 1;
+                continue;
+            }
+            match s[i + 1..].iter().position(|&c| c == b'%') {
+                Some(0) => {
+                    result.push(b'%');
+                    i += 2;
+                }
+                Some(len) => {
+                    let end = i + 1 + len;
+                    let name = String::from_utf8_lossy(&s[i + 1..end]).into_owned();
+                    match self.substitution(&name) {
+                        Some(text) => {
+                            result.extend_from_slice(&text);
+                            n += 1;
+                        }
+                        None => result.extend_from_slice(&s[i..end + 1]),
+                    }
+                    i = end + 1;
+                }
+                None => {
+                    result.extend_from_slice(&s[i..]);
+                    i = s.len();
+                }
+            }
+        }
+        let u2 = if u2 > 0 { u2 as usize } else { 0 };
+        if result.len() > u2 {
+            result.truncate(u2);
+            n = RESULT_OUT_OF_RANGE.into();
+        }
+        match self.put_string(&result, caddr2 as usize) {
+            Ok(()) => self.s_stack().push3(caddr2, result.len() as isize, n),
+            Err(e) => self.abort_with(e),
+        }
+    }
+
+    /// Run-time: ( c-addr1 u1 c-addr2 -- c-addr2 u2 )
+    ///
+    /// Copy the string `c-addr1 u1` to `c-addr2`, doubling each `%`, so that
+    /// the result `c-addr2 u2` is copied unchanged by `substitute`.
+    fn unescape(&mut self) {
+        let (caddr1, u1, caddr2) = self.s_stack().pop3();
+        let s = match self.string_at(caddr1 as usize, u1 as usize) {
+            Ok(s) => s,
+            Err(e) => return self.abort_with(e),
+        };
+        let mut result = Vec::with_capacity(s.len());
+        for &c in &s {
+            if c == b'%' {
+                result.push(b'%');
+            }
+            result.push(c);
+        }
+        match self.put_string(&result, caddr2 as usize) {
+            Ok(()) => self.s_stack().push2(caddr2, result.len() as isize),
+            Err(e) => self.abort_with(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::Core;
+    use exception::{INVALID_MEMORY_ADDRESS, WRITE_TO_A_READ_ONLY_LOCATION};
+    use mock_vm::VM;
+
+    #[test]
+    fn test_compare_search() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : abc   s\" abc\" ;  : abd   s\" abd\" ;  : ab   s\" ab\" ;
+            : line   s\" G01 X10 Y20  \" ;  : x1   s\" X1\" ;
+            abc abc compare  abc abd compare  abd abc compare  ab abc compare
+            line -trailing nip
+            line x1 search  rot drop
+            line abd search  nip nip
+            abc 1 /string  swap abc drop 1+ =
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, -1, 1, -1, 11, 9, -1, 0, 2, -1]);
+    }
+
+    #[test]
+    fn test_cmove_blank_sliteral() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            create buf 8 allot  align
+            : abc   s\" abc\" ;  : a5   s\" aaaaa\" ;  : aabc   s\" aabc\" ;
+            : bc   s\"   bc\" ;
+            abc buf swap cmove  buf buf 1+ 4 cmove  buf 5 a5 compare
+            abc buf swap cmove  buf buf 1+ 3 cmove>  buf 4 aabc compare
+            buf 2 blank  buf 4 bc compare
+            : greet   [ buf 4 ] sliteral ;  buf 4 0 fill  greet bc compare
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 0, 0, 0]);
+        vm.set_source("buf 0 8 cmove");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_MEMORY_ADDRESS));
+        vm.reset();
+        vm.set_source("buf ' greet >body 4 cmove");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(WRITE_TO_A_READ_ONLY_LOCATION));
+    }
+
+    #[test]
+    fn test_blank_overflow() {
+        let vm = &mut VM::new();
+        vm.set_source("here 100000000000000 blank");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_MEMORY_ADDRESS));
+    }
+
+    #[test]
+    fn test_substitute() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            create buf 64 allot  align
+            : x-axis   s\" X\" ;  : axis   s\" axis\" ;
+            x-axis axis replaces
+            : cmd   s\" G01 %axis%10 %feed%F %%\" ;
+            : expected   s\" G01 X10 %feed%F %\" ;
+            cmd buf 64 substitute  rot rot expected compare  swap
+            cmd buf 10 substitute  nip nip
+            : pct   s\" 5%\" ;  : pct2   s\" 5%%\" ;
+            pct buf unescape  pct2 compare
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(vm.s_stack().as_slice(), [0, 1, -11, 0]);
+    }
+}
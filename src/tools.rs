@@ -1,8 +1,62 @@
 //! Tools to inspect the rtforth system
 
-use memory::Memory;
+use core::Core;
+use exception::UNDEFINED_WORD;
+use memory::{DataSpace, Memory};
 use output::Output;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Write;
+use std::mem;
+
+/// Maximum number of instructions decoded by `see`.
+const MAX_INSTRUCTIONS: usize = 0x10000;
+
+/// Inline operand of an instruction in a compiled thread.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    None,
+    Cell(isize),
+    Float(f64),
+    Str(String),
+    Destination(usize),
+}
+
+/// Instruction of a compiled thread.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    /// Address of the token.
+    pub addr: usize,
+    /// Execution token.
+    pub xt: usize,
+    /// Inline operand following the token.
+    pub operand: Operand,
+    /// Address of the next instruction.
+    pub next: usize,
+}
+
+/// Control structure a branch instruction is part of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Plain,
+    If(usize),
+    IfElse,
+    Else(usize),
+    While,
+    Repeat,
+    Until,
+    Again,
+    Goto(usize),
+    Call(usize),
+}
+
+/// Open control structure while rendering a thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Open {
+    Begin(usize),
+    If(usize),
+    IfElse(usize),
+    Do,
+}
 
 pub trait Tools: Output {
     /// Add programming-tools primitives.
@@ -17,6 +71,7 @@ pub trait Tools: Output {
         self.add_primitive("0xtime", Tools::clear_xtime);
         self.add_primitive(".input", Tools::dot_input);
         self.add_primitive("flush-to-err", Tools::flush_to_err);
+        self.add_primitive("see", Tools::see);
     }
 
     /// Run-time: ( -- )
@@ -42,11 +97,12 @@ pub trait Tools: Output {
 
     /// Run-time: ( -- )
     ///
-    /// List definition names in word list.
+    /// List definition names in the first word list of the search order.
     fn words(&mut self) {
         if let Some(mut buf) = self.output_buffer().take() {
+            let wid = self.wordlist().search_order().last().cloned();
             for w in (1..self.wordlist().len()).rev() {
-                if !self.wordlist()[w].is_hidden() {
+                if !self.wordlist()[w].is_hidden() && Some(self.wordlist()[w].wid()) == wid {
                     let nfa = self.wordlist()[w].nfa();
                     let name = unsafe { self.data_space().get_str(nfa) };
                     write!(buf, "{} ", name).unwrap();
@@ -95,8 +151,7 @@ pub trait Tools: Output {
 
             let xt = self.state().aborted_word_pointer;
             if xt != 0 {
-                let nfa = self.wordlist()[xt].nfa();
-                let name = unsafe { self.data_space().get_str(nfa) };
+                let name = self.token_name(xt);
                 write!(buf, "{} ", name).unwrap();
             }
 
@@ -218,4 +273,565 @@ pub trait Tools: Output {
             None => {}
         }
     }
+
+    /// Name of word `xt`.
+    fn word_name(&mut self, xt: usize) -> String {
+        let nfa = self.wordlist()[xt].nfa();
+        unsafe { self.data_space().get_str(nfa) }.to_string()
+    }
+
+    /// Name of `xt`, or the names of the words fused into it if it is a
+    /// superinstruction.
+    fn token_name(&mut self, xt: usize) -> String {
+        match self.fused_words(xt) {
+            Some((first, second)) => {
+                format!("{} {}", self.word_name(first), self.word_name(second))
+            }
+            None => self.word_name(xt),
+        }
+    }
+
+    /// Decode the instruction at `ip`.
+    ///
+    /// Return `None` if `ip` is outside the compiled code or does not hold
+    /// a valid token with its operand.
+    fn decode_instruction(&mut self, ip: usize) -> Option<Instruction> {
+        let cell = mem::size_of::<usize>();
+        let here = self.data_space().here();
+        if ip < self.data_space().start() || ip + cell > here {
+            return None;
+        }
+        let xt = unsafe { self.data_space().get_usize(ip) };
+        if xt == 0 || xt >= self.wordlist().len() {
+            return None;
+        }
+        let r = self.references();
+        let (idx_lit, idx_flit, idx_s_quote) = (r.idx_lit, r.idx_flit, r.idx_s_quote);
+        let jumps = [
+            r.idx_branch,
+            r.idx_zero_branch,
+            r.idx__call,
+            r.idx_do,
+            r.idx_qdo,
+            r.idx_loop,
+            r.idx_plus_loop,
+        ];
+        let mut slots = vec![r.idx__local_fetch, r.idx__flocal_fetch];
+        for name in &["_to-local", "_fto-local"] {
+            if let Some(idx) = self.find(name) {
+                slots.push(idx);
+            }
+        }
+        // A superinstruction takes the operands of the words fused into it.
+        let (first, second) = self.fused_words(xt).unwrap_or((xt, xt));
+        let (operand, next) = if first == idx_lit || slots.contains(&xt) {
+            if ip + 2 * cell > here {
+                return None;
+            }
+            let v = unsafe { self.data_space().get_isize(ip + cell) };
+            (Operand::Cell(v), ip + 2 * cell)
+        } else if xt == idx_flit {
+            let a = DataSpace::aligned_f64(ip + cell);
+            if a + mem::size_of::<f64>() > here {
+                return None;
+            }
+            let v = unsafe { self.data_space().get_f64(a) };
+            (Operand::Float(v), a + mem::size_of::<f64>())
+        } else if xt == idx_s_quote {
+            if ip + 2 * cell > here {
+                return None;
+            }
+            let len = unsafe { self.data_space().get_usize(ip + cell) };
+            if len > here - (ip + 2 * cell) {
+                return None;
+            }
+            let text = {
+                let bytes = unsafe { self.data_space().buffer_from_raw_parts(ip + 2 * cell, len) };
+                String::from_utf8_lossy(bytes).into_owned()
+            };
+            (Operand::Str(text), DataSpace::aligned(ip + 2 * cell + len))
+        } else if jumps.contains(&second) {
+            if ip + 2 * cell > here {
+                return None;
+            }
+            let offset = unsafe { self.data_space().get_usize(ip + cell) };
+            let destination = self.data_space().address(offset);
+            (Operand::Destination(destination), ip + 2 * cell)
+        } else {
+            (Operand::None, ip + cell)
+        };
+        Some(Instruction {
+            addr: ip,
+            xt,
+            operand,
+            next,
+        })
+    }
+
+    /// Decode the thread starting at `start` up to the `exit` ending it.
+    ///
+    /// Return `Err` with the instructions decoded so far if the thread runs
+    /// out of the data space or holds an invalid token.
+    fn decode_thread(&mut self, start: usize) -> Result<Vec<Instruction>, Vec<Instruction>> {
+        let idx_exit = self.references().idx_exit;
+        let idx_does = self.references().idx__does;
+        let mut instructions = Vec::new();
+        let mut ip = start;
+        // End of the farthest forward jump, the thread cannot end before.
+        let mut limit = start;
+        let mut in_does = false;
+        loop {
+            if instructions.len() >= MAX_INSTRUCTIONS {
+                return Err(instructions);
+            }
+            let ins = match self.decode_instruction(ip) {
+                Some(ins) => ins,
+                None => return Err(instructions),
+            };
+            if let Operand::Destination(d) = ins.operand {
+                limit = limit.max(d);
+            }
+            let (xt, next) = (ins.xt, ins.next);
+            instructions.push(ins);
+            // `does>` compiles `_does exit`, the code of the defined words
+            // follows.
+            if xt == idx_exit && !in_does && ip >= limit {
+                return Ok(instructions);
+            }
+            in_does = xt == idx_does;
+            ip = next;
+        }
+    }
+
+    /// Render `instructions` as Forth source, recovering control
+    /// structures from the branches. Return `None` if the branches do not
+    /// form properly nested control structures.
+    fn render_thread(&mut self, instructions: &[Instruction]) -> Option<String> {
+        let idx_branch = self.references().idx_branch;
+        let idx_zero_branch = self.references().idx_zero_branch;
+        let idx_call = self.references().idx__call;
+        let idx_do = self.references().idx_do;
+        let idx_qdo = self.references().idx_qdo;
+        let idx_loop = self.references().idx_loop;
+        let idx_plus_loop = self.references().idx_plus_loop;
+        let idx_lit = self.references().idx_lit;
+        let idx_s_quote = self.references().idx_s_quote;
+        let idx_type = self.references().idx_type;
+        let idx_exit = self.references().idx_exit;
+        let idx_does = self.references().idx__does;
+        let idx_postpone = self.references().idx__postpone;
+        let idx_abort_quote = self.references().idx__abort_quote;
+
+        let end = instructions.last()?.next;
+        // Superinstructions ending with a jump take part in control
+        // structures as that jump.
+        let jumps: Vec<usize> = instructions
+            .iter()
+            .map(|ins| {
+                self.fused_words(ins.xt)
+                    .map_or(ins.xt, |(_, second)| second)
+            })
+            .collect();
+        let index: HashMap<usize, usize> = instructions
+            .iter()
+            .enumerate()
+            .map(|(i, ins)| (ins.addr, i))
+            .collect();
+        let destination = |i: usize| match instructions[i].operand {
+            Operand::Destination(d) => d,
+            _ => 0,
+        };
+        // Instruction ending at `addr`.
+        let before = |addr: usize| {
+            if addr == end {
+                Some(instructions.len() - 1)
+            } else {
+                index.get(&addr).and_then(|&i| i.checked_sub(1))
+            }
+        };
+
+        let mut roles = vec![Role::Plain; instructions.len()];
+        // Addresses starting `begin`s, with the instructions closing them.
+        let mut begins: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut thens: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut labels: BTreeMap<usize, usize> = BTreeMap::new();
+        for i in 0..instructions.len() {
+            let xt = jumps[i];
+            if xt != idx_branch && xt != idx_zero_branch && xt != idx_call {
+                continue;
+            }
+            let d = destination(i);
+            let addr = instructions[i].addr;
+            if d != end && !index.contains_key(&d) {
+                return None;
+            }
+            if xt == idx_zero_branch {
+                if d > addr {
+                    let j = before(d)?;
+                    let is_branch = j > i && jumps[j] == idx_branch;
+                    if is_branch && roles[j] != Role::Plain {
+                        return None;
+                    }
+                    if is_branch && destination(j) <= addr {
+                        roles[i] = Role::While;
+                        roles[j] = Role::Repeat;
+                        begins.entry(destination(j)).or_default().push(j);
+                    } else if is_branch && destination(j) > instructions[j].addr {
+                        let dj = destination(j);
+                        if dj != end && !index.contains_key(&dj) {
+                            return None;
+                        }
+                        roles[i] = Role::IfElse;
+                        roles[j] = Role::Else(dj);
+                        *thens.entry(dj).or_default() += 1;
+                    } else {
+                        roles[i] = Role::If(d);
+                        *thens.entry(d).or_default() += 1;
+                    }
+                } else {
+                    roles[i] = Role::Until;
+                    begins.entry(d).or_default().push(i);
+                }
+            } else if roles[i] == Role::Plain {
+                if xt == idx_branch && d <= addr {
+                    roles[i] = Role::Again;
+                    begins.entry(d).or_default().push(i);
+                } else {
+                    let n = labels.len() + 1;
+                    let n = *labels.entry(d).or_insert(n);
+                    roles[i] = if xt == idx_call {
+                        Role::Call(n)
+                    } else {
+                        Role::Goto(n)
+                    };
+                }
+            }
+        }
+
+        let mut tokens: Vec<String> = Vec::new();
+        let mut open: Vec<Open> = Vec::new();
+        let mut skip = false;
+        for i in 0..=instructions.len() {
+            let addr = if i < instructions.len() {
+                instructions[i].addr
+            } else {
+                end
+            };
+            let marked = thens.contains_key(&addr)
+                || labels.contains_key(&addr)
+                || begins.contains_key(&addr);
+            if skip && !marked {
+                skip = false;
+                continue;
+            }
+            skip = false;
+            for _ in 0..thens.get(&addr).cloned().unwrap_or(0) {
+                match open.pop() {
+                    Some(Open::If(a)) if a == addr => tokens.push("then".to_string()),
+                    _ => return None,
+                }
+            }
+            if let Some(n) = labels.get(&addr) {
+                tokens.push(format!("[ {} ] label", n));
+            }
+            if let Some(closers) = begins.get(&addr) {
+                let mut closers = closers.clone();
+                closers.sort_unstable_by(|a, b| b.cmp(a));
+                for j in closers {
+                    open.push(Open::Begin(j));
+                    tokens.push("begin".to_string());
+                }
+            }
+            if i == instructions.len() {
+                break;
+            }
+            let ins = &instructions[i];
+            let next = instructions.get(i + 1).map(|n| n.xt);
+            let fused = self.fused_words(ins.xt);
+            if let (Some((first, _)), true) = (fused, roles[i] != Role::Plain) {
+                tokens.push(self.word_name(first));
+            }
+            let token = match roles[i] {
+                Role::If(then) => {
+                    open.push(Open::If(then));
+                    "if".to_string()
+                }
+                Role::IfElse => {
+                    open.push(Open::IfElse(i));
+                    "if".to_string()
+                }
+                Role::Else(then) => {
+                    match open.pop() {
+                        Some(Open::IfElse(_)) => {}
+                        _ => return None,
+                    }
+                    open.push(Open::If(then));
+                    "else".to_string()
+                }
+                Role::While => match open.last() {
+                    Some(&Open::Begin(j)) if roles[j] == Role::Repeat => "while".to_string(),
+                    _ => return None,
+                },
+                Role::Repeat | Role::Until | Role::Again => {
+                    if open.pop() != Some(Open::Begin(i)) {
+                        return None;
+                    }
+                    match roles[i] {
+                        Role::Repeat => "repeat",
+                        Role::Until => "until",
+                        _ => "again",
+                    }
+                    .to_string()
+                }
+                Role::Goto(n) => format!("[ {} ] goto", n),
+                Role::Call(n) => format!("[ {} ] call", n),
+                Role::Plain if fused.is_some() => {
+                    let (first, second) = fused.unwrap_or_default();
+                    match ins.operand {
+                        Operand::Cell(v) if first == idx_lit => {
+                            format!("{} {}", v, self.word_name(second))
+                        }
+                        _ => format!("{} {}", self.word_name(first), self.word_name(second)),
+                    }
+                }
+                Role::Plain => {
+                    if ins.xt == idx_do || ins.xt == idx_qdo {
+                        open.push(Open::Do);
+                        if ins.xt == idx_do { "do" } else { "?do" }.to_string()
+                    } else if ins.xt == idx_loop || ins.xt == idx_plus_loop {
+                        if open.pop() != Some(Open::Do) {
+                            return None;
+                        }
+                        if ins.xt == idx_loop { "loop" } else { "+loop" }.to_string()
+                    } else if ins.xt == idx_exit {
+                        if i + 1 == instructions.len() {
+                            ";".to_string()
+                        } else {
+                            "exit".to_string()
+                        }
+                    } else if ins.xt == idx_does {
+                        skip = next == Some(idx_exit);
+                        "does>".to_string()
+                    } else {
+                        match ins.operand {
+                            Operand::Cell(v) if ins.xt == idx_lit => {
+                                if next == Some(idx_postpone)
+                                    && v > 0
+                                    && (v as usize) < self.wordlist().len()
+                                {
+                                    skip = true;
+                                    format!("postpone {}", self.word_name(v as usize))
+                                } else {
+                                    v.to_string()
+                                }
+                            }
+                            Operand::Float(v) => format!("{:e}", v),
+                            Operand::Str(ref text) if ins.xt == idx_s_quote => {
+                                if next == Some(idx_type) {
+                                    skip = true;
+                                    format!(".\" {}\"", text)
+                                } else if next == Some(idx_abort_quote) {
+                                    skip = true;
+                                    format!("abort\" {}\"", text)
+                                } else {
+                                    format!("s\" {}\"", text)
+                                }
+                            }
+                            Operand::Cell(v) => format!("{} {}", self.word_name(ins.xt), v),
+                            _ => self.word_name(ins.xt),
+                        }
+                    }
+                }
+            };
+            tokens.push(token);
+        }
+        if open.is_empty() {
+            Some(tokens.join(" "))
+        } else {
+            None
+        }
+    }
+
+    /// Name of the token of `ins` followed by its operand, with
+    /// destinations in hex. A superinstruction is shown as the words fused
+    /// into it, with the operand after the word taking it.
+    fn instruction_text(&mut self, ins: &Instruction) -> String {
+        let mut operand = String::new();
+        match ins.operand {
+            Operand::None => {}
+            Operand::Cell(v) => write!(operand, " {}", v).unwrap(),
+            Operand::Float(v) => write!(operand, " {:e}", v).unwrap(),
+            Operand::Str(ref s) => write!(operand, " {:?}", s).unwrap(),
+            Operand::Destination(d) => write!(operand, " {:X}", d).unwrap(),
+        }
+        let idx_lit = self.references().idx_lit;
+        match self.fused_words(ins.xt) {
+            Some((first, second)) if first == idx_lit => {
+                let (first, second) = (self.word_name(first), self.word_name(second));
+                format!("{}{} {}", first, operand, second)
+            }
+            _ => self.token_name(ins.xt) + &operand,
+        }
+    }
+
+    /// List `instructions` with their addresses, followed by `?` if the
+    /// thread could not be decoded to its end.
+    fn list_thread(&mut self, instructions: &[Instruction], complete: bool) -> String {
+        let mut listing = String::new();
+        for ins in instructions {
+            let text = self.instruction_text(ins);
+            write!(listing, "\n{:X} {}", ins.addr, text).unwrap();
+        }
+        if !complete {
+            let addr = instructions.last().map_or(0, |ins| ins.next);
+            write!(listing, "\n{:X} ?", addr).unwrap();
+        }
+        listing
+    }
+
+    /// Forth source of the thread at `start`, or a listing of it if its
+    /// control structures cannot be recovered.
+    fn decompile(&mut self, start: usize) -> String {
+        match self.decode_thread(start) {
+            Ok(instructions) => match self.render_thread(&instructions) {
+                Some(source) => source,
+                None => self.list_thread(&instructions, true),
+            },
+            Err(instructions) => self.list_thread(&instructions, false),
+        }
+    }
+
+    /// Run-time: ( "<spaces>name" -- )
+    ///
+    /// Display the definition of `name`. Colon definitions are decompiled
+    /// to Forth source, with control structures recovered from branches
+    /// and labels numbered in the order of their addresses. If that is not
+    /// possible, the thread is listed with the address of each instruction.
+    ///
+    /// Example: `see sq` displays `: sq   dup * ;`
+    fn see(&mut self) {
+        self.parse_word();
+        let name = self
+            .last_token()
+            .as_ref()
+            .expect("last token")
+            .to_ascii_lowercase();
+        let xt = match self.find(&name) {
+            Some(xt) => xt,
+            None => return self.abort_with(UNDEFINED_WORD),
+        };
+        let nest: fn(&mut Self) = Core::nest;
+        let var: fn(&mut Self) = Core::p_var;
+        let constant: fn(&mut Self) = Core::p_const;
+        let xdoes: fn(&mut Self) = Core::xdoes;
+        let action = self.wordlist()[xt].action() as usize;
+        let dfa = self.wordlist()[xt].dfa();
+        let text = if action == nest as usize {
+            let source = self.decompile(dfa);
+            if source.starts_with('\n') {
+                format!(": {}{}", name, source)
+            } else {
+                format!(": {}   {}", name, source)
+            }
+        } else if action == var as usize {
+            format!("create {}", name)
+        } else if action == constant as usize {
+            let v = unsafe { self.data_space().get_isize(dfa) };
+            format!("{} constant {}", v, name)
+        } else if action == xdoes as usize {
+            let doer = self.wordlist()[xt].doer;
+            let source = self.decompile(doer);
+            format!("create {}  does> {}", name, source)
+        } else {
+            format!("code {}", name)
+        };
+        let immediate = if self.wordlist()[xt].is_immediate() {
+            " immediate"
+        } else {
+            ""
+        };
+        if let Some(buf) = self.output_buffer().as_mut() {
+            write!(buf, "{}{}", text, immediate).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::Core;
+    use exception::UNDEFINED_WORD;
+    use mock_vm::VM;
+
+    fn see(vm: &mut VM, name: &str) -> String {
+        vm.set_output_buffer(String::new());
+        vm.set_source(&format!("see {}", name));
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        vm.output_buffer().clone().unwrap()
+    }
+
+    #[test]
+    fn test_see_control_structures() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : t1   dup 0< if negate else 1+ then ;
+            : t2   begin dup while 1- repeat drop ;
+            : t3   begin 1- dup 0= until ;
+            : t4   10 0 do i . 2 +loop ;
+            : t5   [ 0labels 1 ] goto 5 [ 1 ] label 6 ;
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(see(vm, "t1"), ": t1   dup 0< if negate else 1+ then ;");
+        assert_eq!(see(vm, "t2"), ": t2   begin dup while 1- repeat drop ;");
+        assert_eq!(see(vm, "t3"), ": t3   begin 1- dup 0= until ;");
+        assert_eq!(see(vm, "t4"), ": t4   10 0 do i . 2 +loop ;");
+        assert_eq!(see(vm, "t5"), ": t5   [ 1 ] goto 5 [ 1 ] label 6 ;");
+    }
+
+    #[test]
+    fn test_see_literals_and_defining_words() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : t1   s\" abc\" type .\" hi\" 1.5e0 ;
+            : t2   create , does> @ + ;  3 t2 t3
+            42 constant t4  variable t5
+            : t6   postpone dup ; immediate
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        assert_eq!(see(vm, "t1"), ": t1   .\" abc\" .\" hi\" 1.5e0 ;");
+        assert_eq!(see(vm, "t2"), ": t2   create , does> @ + ;");
+        assert_eq!(see(vm, "t3"), "create t3  does> @ + ;");
+        assert_eq!(see(vm, "t4"), "42 constant t4");
+        assert_eq!(see(vm, "t5"), "create t5");
+        assert_eq!(see(vm, "t6"), ": t6   postpone dup ; immediate");
+        assert_eq!(see(vm, "dup"), "code dup");
+        vm.set_source("see no-such-word");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(UNDEFINED_WORD));
+    }
+
+    #[test]
+    fn test_see_listing() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : bad   [ ' branch ] literal compile, 0 , ; immediate
+            : t1   1 bad ;
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let listing = see(vm, "t1");
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], ": t1");
+        assert!(lines[1].ends_with(" lit 1"));
+        assert!(lines[2].contains(" branch "));
+        assert!(lines[3].ends_with(" exit"));
+    }
 }
@@ -0,0 +1,147 @@
+//! Execution trace
+//!
+//! While tracing, `run` records the last tokens executed by all tasks in a
+//! fixed-size ring buffer, with the task, the address of the token, the top
+//! of data stack and the time. The trace is dumped when an exception aborts
+//! the VM, to see the steps leading to the failure.
+//!
+//! ```text
+//! 4096 trace-on  main  .trace
+//! ```
+
+use core::{Core, TraceBuffer, MAX_TRACE};
+use exception::{Exception, INVALID_NUMERIC_ARGUMENT};
+
+pub trait Tracer: Core {
+    /// Add trace primitives.
+    fn add_tracer(&mut self) {
+        self.add_primitive("trace-on", Tracer::trace_on);
+        self.add_primitive("trace-off", Tracer::trace_off);
+        self.add_primitive("0trace", Tracer::clear_trace);
+        self.add_primitive(".trace", Tracer::dot_trace);
+    }
+
+    /// Trace the last `capacity` tokens executed, or stop tracing if
+    /// `capacity` is 0. The trace recorded so far is dropped.
+    ///
+    /// Fail with `INVALID_NUMERIC_ARGUMENT` if `capacity` is greater than
+    /// `MAX_TRACE`.
+    fn set_tracing(&mut self, capacity: usize) -> Result<(), Exception> {
+        if capacity > MAX_TRACE {
+            return Err(INVALID_NUMERIC_ARGUMENT);
+        }
+        self.instrumentation_mut().trace = TraceBuffer::with_capacity(capacity);
+        self.update_hooks();
+        Ok(())
+    }
+
+    /// Trace recorded.
+    fn trace(&self) -> &TraceBuffer {
+        &self.instrumentation().trace
+    }
+
+    /// Run-time: ( u -- )
+    ///
+    /// Start tracing the last `u` tokens executed, at most `MAX_TRACE`.
+    fn trace_on(&mut self) {
+        let u = self.s_stack().pop();
+        let result = if u > 0 {
+            self.set_tracing(u as usize)
+        } else {
+            Err(INVALID_NUMERIC_ARGUMENT)
+        };
+        if let Err(e) = result {
+            self.abort_with(e);
+        }
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Stop tracing.
+    fn trace_off(&mut self) {
+        self.instrumentation_mut().trace = TraceBuffer::default();
+        self.update_hooks();
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Clear the trace recorded.
+    fn clear_trace(&mut self) {
+        self.instrumentation_mut().trace.clear();
+    }
+
+    /// Run-time: ( -- )
+    ///
+    /// Display the trace recorded, the oldest token first, one per line
+    /// with the time in nano-seconds, the task, the address and the name of
+    /// the token, and the top of data stack before it is executed.
+    fn dot_trace(&mut self) {
+        let dump = self.trace_dump();
+        if let Some(buf) = self.output_buffer().as_mut() {
+            buf.push_str(&dump);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tracer;
+    use core::{Core, MAX_TRACE};
+    use exception::{DIVISION_BY_ZERO, INVALID_NUMERIC_ARGUMENT};
+    use mock_vm::VM;
+
+    #[test]
+    fn test_trace() {
+        let vm = &mut VM::new();
+        vm.set_source(
+            "
+            : inner   3 + ;
+            : outer   1 inner drop ;
+            4 trace-on  outer
+        ",
+        );
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), None);
+        let outer = vm.find("outer").expect("outer");
+        let dfa = vm.wordlist()[outer].dfa();
+        // The last 4 of the 7 tokens executed.
+        let trace: Vec<_> = vm.trace().iter().map(|e| (e.task, e.xt, e.tos)).collect();
+        let names = ["+", "exit", "drop", "exit"];
+        let xts: Vec<_> = names.iter().map(|n| vm.find(n).expect("word")).collect();
+        assert_eq!(
+            trace,
+            [
+                (0, xts[0], 3),
+                (0, xts[1], 4),
+                (0, xts[2], 4),
+                (0, xts[3], 0)
+            ]
+        );
+        assert_eq!(vm.trace().iter().last().map(|e| e.ip), Some(dfa + 4 * 8));
+        // Dumped when aborting.
+        vm.set_source("0trace  : oops   1 0 / ;  oops");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(DIVISION_BY_ZERO));
+        let dump = vm.output_buffer().clone().unwrap();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" lit 0"));
+        assert!(lines[1].ends_with(" lit 1"));
+        assert!(lines[2].ends_with(" / 0"));
+        assert!(lines[2].contains(" task1 "));
+        vm.reset();
+        vm.set_source("trace-off oops");
+        vm.evaluate_input();
+        assert_eq!(vm.trace().len(), 0);
+    }
+
+    #[test]
+    fn test_trace_capacity() {
+        let vm = &mut VM::new();
+        vm.set_source("100000000000000 trace-on");
+        vm.evaluate_input();
+        assert_eq!(vm.last_error(), Some(INVALID_NUMERIC_ARGUMENT));
+        assert_eq!(vm.set_tracing(MAX_TRACE + 1), Err(INVALID_NUMERIC_ARGUMENT));
+        assert_eq!(vm.trace().capacity(), 0);
+    }
+}